@@ -0,0 +1,288 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Per-app resource accounting, so we can tell which app drained the
+// battery overnight. Every app's cgroup is sampled periodically and
+// the CPU time, memory use and wall time (split by rule and by
+// whether it was frozen) since the last sample are added to the
+// current hour and the current day. Only the last couple of days of
+// hours and a couple of weeks of days are kept, older periods fall
+// off the end of the ring buffers.
+//
+// Usage is accumulated per app id rather than per cgroup, so all
+// instances of an app and all its launches add up. Periods are
+// aligned to UTC hours and days.
+//
+// Each period also records how much energy came out of the battery,
+// next to the per-app CPU energy estimates from the energy model that
+// gives an idea of how much of the drain the apps are responsible for.
+
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::{FreezeMode, Rule};
+use crate::energy::EnergyModel;
+use crate::hal::Hal;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use strum_macros::{Display, EnumString};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+const HOURS_KEPT: usize = 48;
+const DAYS_KEPT: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Rollup {
+    Hour,
+    Day,
+}
+
+impl Rollup {
+    fn seconds(&self) -> u64 {
+        match self {
+            Rollup::Hour => 3600,
+            Rollup::Day => 86400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    pub cpu_usec: u64,
+    /// Estimated energy used on the CPU
+    pub energy_mwh: f64,
+    /// Highest memory use seen in the period (bytes)
+    pub memory_peak: u64,
+    /// memory.current in bytes times the seconds it was sampled over
+    memory_integral: f64,
+    /// Wall time spent in each rule
+    pub rules: HashMap<Rule, Duration>,
+    pub frozen: Duration,
+    pub thawed: Duration,
+}
+
+impl Usage {
+    /// Average memory use over the time the app was tracked (bytes)
+    pub fn memory_avg(&self) -> u64 {
+        let secs = (self.frozen + self.thawed).as_secs_f64();
+        match secs > 0.0 {
+            true => (self.memory_integral / secs) as u64,
+            false => 0,
+        }
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.cpu_usec += other.cpu_usec;
+        self.energy_mwh += other.energy_mwh;
+        self.memory_peak = self.memory_peak.max(other.memory_peak);
+        self.memory_integral += other.memory_integral;
+        for (rule, time) in &other.rules {
+            *self.rules.entry(*rule).or_default() += *time;
+        }
+        self.frozen += other.frozen;
+        self.thawed += other.thawed;
+    }
+}
+
+pub struct Period {
+    /// Unix time the period starts at
+    pub start: u64,
+    /// Keyed by app id
+    pub apps: HashMap<String, Usage>,
+    /// Energy that came out of the battery while discharging
+    pub battery_mwh: f64,
+    /// Estimated CPU energy of all apps while discharging, to
+    /// compare with battery_mwh
+    pub apps_mwh: f64,
+}
+
+/// What we read from an app's cgroup last time
+struct Last {
+    at: Instant,
+    cpu_usec: u64,
+    memory_peak: u64,
+}
+
+pub struct Accounting {
+    next_sample: Instant,
+    /// Keyed by cgroup
+    last: HashMap<String, Last>,
+    hours: VecDeque<Period>,
+    days: VecDeque<Period>,
+    energy: EnergyModel,
+    /// When we last looked at the battery and its energy_now (µWh)
+    last_battery: Option<(Instant, Option<u64>)>,
+}
+
+impl Accounting {
+    pub fn new() -> Self {
+        Self {
+            next_sample: Instant::now(),
+            last: HashMap::new(),
+            hours: VecDeque::new(),
+            days: VecDeque::new(),
+            energy: EnergyModel::new(),
+            last_battery: None,
+        }
+    }
+
+    /// The last count periods, oldest first
+    pub fn history(&self, rollup: Rollup, count: usize) -> impl Iterator<Item = &Period> {
+        let periods = match rollup {
+            Rollup::Hour => &self.hours,
+            Rollup::Day => &self.days,
+        };
+        periods.iter().skip(periods.len().saturating_sub(count))
+    }
+
+    /// Usage of each app summed over the last count periods
+    pub fn total(&self, rollup: Rollup, count: usize) -> HashMap<String, Usage> {
+        let mut total: HashMap<String, Usage> = HashMap::new();
+        for period in self.history(rollup, count) {
+            for (app_id, usage) in &period.apps {
+                total.entry(app_id.clone()).or_default().add(usage);
+            }
+        }
+        total
+    }
+
+    /// The period that the current time falls in, starting a new one
+    /// and dropping the oldest if needed
+    fn current(periods: &mut VecDeque<Period>, rollup: Rollup, now: u64, kept: usize) -> &mut Period {
+        let start = now - now % rollup.seconds();
+        if periods.back().map_or(true, |p| p.start != start) {
+            if periods.len() >= kept {
+                periods.pop_front();
+            }
+            periods.push_back(Period {
+                start,
+                apps: HashMap::new(),
+                battery_mwh: 0.0,
+                apps_mwh: 0.0,
+            });
+        }
+        periods.back_mut().unwrap()
+    }
+
+    /// Energy that came out of the battery since last time (mWh),
+    /// from energy_now if the fuel gauge has it or the power otherwise.
+    /// None if we aren't running on battery.
+    fn battery_drained(&mut self, hal: &Hal, now: Instant) -> Option<f64> {
+        let battery = hal.power_supply().battery().ok()??;
+        let last = self.last_battery.replace((now, battery.energy_now));
+        let (at, energy) = match last {
+            Some(last) if battery.discharging() => last,
+            _ => return None,
+        };
+
+        Some(match (energy, battery.energy_now) {
+            (Some(before), Some(after)) => before.saturating_sub(after) as f64 / 1000.0,
+            _ => battery.power().unwrap_or(0.0) * now.duration_since(at).as_secs_f64() / 3600.0,
+        })
+    }
+
+    pub fn process(&mut self, handler: &CGHandler, hal: &Hal, apps: &[App]) {
+        let now = Instant::now();
+        if now < self.next_sample {
+            return;
+        }
+        self.next_sample = now + SAMPLE_INTERVAL;
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        self.energy.sample();
+        let drained = self.battery_drained(hal, now);
+
+        let mut seen = Vec::new();
+        for app in apps {
+            let cgroup = app.cgroup_name();
+            let (app_id, rule, frozen) = {
+                let info = app.info.read();
+                (info.app_id.to_string(), info.match_rule, info.freeze_mode != FreezeMode::Off)
+            };
+            seen.push(cgroup.clone());
+
+            let cpu_usec = match handler.cpu_stat(&cgroup, "usage_usec") {
+                Ok(usage) => usage,
+                Err(e) => {
+                    trace!("Accounting: {}", e);
+                    continue;
+                }
+            };
+            let current = handler.memory_current(&cgroup).unwrap_or(0);
+            let peak = handler.memory_peak(&cgroup).unwrap_or(current);
+
+            let last = self.last.insert(
+                cgroup,
+                Last {
+                    at: now,
+                    cpu_usec,
+                    memory_peak: peak,
+                },
+            );
+            // Need two samples to know what happened in between
+            let last = match last {
+                Some(last) => last,
+                None => continue,
+            };
+
+            let elapsed = now.duration_since(last.at);
+            let used = cpu_usec.saturating_sub(last.cpu_usec);
+            let cpus = handler.effective_cpus(&cgroup).unwrap_or_default();
+            let mut usage = Usage {
+                cpu_usec: used,
+                energy_mwh: self.energy.estimate(&cpus, used),
+                // memory.peak only tells us about this period if it went up
+                memory_peak: if peak > last.memory_peak { peak } else { current },
+                memory_integral: current as f64 * elapsed.as_secs_f64(),
+                ..Default::default()
+            };
+            usage.rules.insert(rule, elapsed);
+            match frozen {
+                true => usage.frozen = elapsed,
+                false => usage.thawed = elapsed,
+            }
+
+            for (periods, rollup, kept) in [
+                (&mut self.hours, Rollup::Hour, HOURS_KEPT),
+                (&mut self.days, Rollup::Day, DAYS_KEPT),
+            ] {
+                let period = Self::current(periods, rollup, unix, kept);
+                if drained.is_some() {
+                    period.apps_mwh += usage.energy_mwh;
+                }
+                period.apps.entry(app_id.clone()).or_default().add(&usage);
+            }
+        }
+
+        for (periods, rollup, kept) in [
+            (&mut self.hours, Rollup::Hour, HOURS_KEPT),
+            (&mut self.days, Rollup::Day, DAYS_KEPT),
+        ] {
+            Self::current(periods, rollup, unix, kept).battery_mwh += drained.unwrap_or(0.0);
+        }
+
+        self.last.retain(|cgroup, _| seen.contains(cgroup));
+    }
+}
+
+impl Default for Accounting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
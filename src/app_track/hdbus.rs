@@ -22,6 +22,7 @@ use crate::events::{HammockEvent, HammockEventSource};
 use anyhow::anyhow;
 use anyhow::{bail, Result};
 use dbus::blocking::{Proxy, Connection};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
 use dbus::channel::MatchingReceiver;
 use dbus::message::{MatchRule, Message};
 use dbus::arg::OwnedFd;
@@ -35,8 +36,14 @@ use parking_lot::Mutex;
 use std::sync::Arc;
 use std::thread;
 
+/// logind's default InhibitDelayMaxSec
+const DEFAULT_INHIBIT_DELAY: Duration = Duration::from_secs(5);
+
 struct InhibitHandler {
     fd: Option<OwnedFd>,
+    /// How long logind will wait for us to release the delay
+    /// inhibitor before suspending anyway
+    max_delay: Duration,
 }
 
 pub(super) struct HammockDbus {
@@ -181,10 +188,14 @@ impl HammockDbus {
             inhib.onSuspend();
             Ok(())
         } else {
-            inhib.onResume(&self.connection)
+            inhib.onResume(&self.sys_conn)
         }
     }
 
+    pub(super) fn inhibit_delay_max(&self) -> Duration {
+        self.inhib.lock().max_delay
+    }
+
     // pub(super) fn start(&self) {
     //     std::thread::spawn(|| {
     //         loop {
@@ -209,8 +220,20 @@ impl InhibitHandler {
 
         let (fd,) = proxy.method_call("org.freedesktop.login1.Manager", "Inhibit", ("sleep", "Hammock", "Freeze gnome-session", "delay"))?;
 
+        let max_delay = match proxy.get::<u64>("org.freedesktop.login1.Manager", "InhibitDelayMaxUSec") {
+            Ok(max_delay) => {
+                debug!("logind InhibitDelayMaxUSec={}", max_delay);
+                Duration::from_micros(max_delay)
+            }
+            Err(e) => {
+                warn!("Couldn't read InhibitDelayMaxUSec, assuming {}s: {}", DEFAULT_INHIBIT_DELAY.as_secs(), e);
+                DEFAULT_INHIBIT_DELAY
+            }
+        };
+
         Ok(Self {
             fd: Some(fd),
+            max_delay,
         })
     }
     
@@ -25,6 +25,7 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 use crate::events::{HammockEvent, HammockEventSource};
 
 use hdbus::HammockDbus;
@@ -65,6 +66,22 @@ impl AppTrack {
     pub fn handle_suspend(&self, active: bool) -> Result<()> {
         self.hdbus.handle_suspend(active) // Need to freeze GSD or something :Sob:
     }
+
+    /// The longest we can delay suspend before logind gives up on us
+    pub fn inhibit_delay_max(&self) -> Duration {
+        self.hdbus.inhibit_delay_max()
+    }
+
+    /// Whether any output is powered on, None if we can't tell
+    pub fn outputs_powered(&self) -> Option<bool> {
+        self.hwl.outputs_powered()
+    }
+
+    /// Block until an output's power state changes or the timeout
+    /// passes, returns whether anything changed
+    pub fn wait_outputs_changed(&self, timeout: Duration) -> bool {
+        self.hwl.wait_outputs_changed(timeout)
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -25,16 +25,19 @@ use serde::{Serialize, Deserialize};
 use wayland_client::backend::ObjectId;
 use parking_lot::Mutex;
 use strum_macros::Display as StrumDisplay;
+use std::collections::HashMap;
 use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender};
 use std::sync::Arc;
 use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
 use super::AppId;
 use crate::events::{HammockEvent, HammockEventSource};
 use wayland_client::event_created_child;
 use wayland_client::{
     globals::{registry_queue_init, GlobalListContents},
+    protocol::wl_output::WlOutput,
     protocol::wl_registry::{Event, WlRegistry},
-    Connection, Dispatch, Proxy, QueueHandle,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols_wlr::foreign_toplevel::v1::client::{
     zwlr_foreign_toplevel_handle_v1::{
@@ -45,16 +48,32 @@ use wayland_protocols_wlr::foreign_toplevel::v1::client::{
         EVT_TOPLEVEL_OPCODE,
     },
 };
+use wayland_protocols_wlr::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1 as OutputPowerManager,
+    zwlr_output_power_v1::{
+        Event as OutputPowerEvent, Mode as OutputPowerMode, ZwlrOutputPowerV1 as OutputPower,
+    },
+};
+
+/// Power state of each output keyed by the wl_output global name,
+/// true if the output is on.
+type OutputStates = Arc<Mutex<HashMap<u32, bool>>>;
 
 #[derive(Clone)]
 struct HammockWlInner {
     exit: Arc<Mutex<bool>>,
     tx: Sender<HammockEvent>,
+    power_manager: Option<OutputPowerManager>,
+    outputs: OutputStates,
+    /// Poked whenever an output's power state changes
+    power_tx: Sender<()>,
 }
 
 pub(super) struct HammockWl {
     exit: Arc<Mutex<bool>>,
     handle: JoinHandle<Result<()>>,
+    outputs: OutputStates,
+    power_rx: Receiver<()>,
 }
 
 impl HammockWl {
@@ -77,13 +96,35 @@ impl HammockWl {
         // Tell the server to get us the TopLevelManager
         globals.bind::<TopLevelManager, _, _>(&event_queue.handle(), 1..=1, ())?;
 
+        // Used to find out when the panel has actually been turned off
+        let power_manager = match globals.bind::<OutputPowerManager, _, _>(&event_queue.handle(), 1..=1, ()) {
+            Ok(manager) => Some(manager),
+            Err(e) => {
+                warn!("Compositor doesn't support wlr-output-power-management: {}", e);
+                None
+            }
+        };
+
         let exit = Arc::new(Mutex::new(false));
+        let outputs = OutputStates::default();
+        let (power_tx, power_rx) = std::sync::mpsc::channel();
 
         let mut inner = HammockWlInner {
             exit: exit.clone(),
             tx,
+            power_manager,
+            outputs: outputs.clone(),
+            power_tx,
         };
 
+        // Outputs that already exist, any new ones are
+        // bound when the registry tells us about them
+        globals.contents().with_list(|list| {
+            for global in list.iter().filter(|g| g.interface == "wl_output") {
+                inner.bind_output(globals.registry(), global.name, global.version, &event_queue.handle());
+            }
+        });
+
         let wl_handle = std::thread::spawn(move || {
             loop {
                 match event_queue.blocking_dispatch(&mut inner) {
@@ -103,12 +144,42 @@ impl HammockWl {
         Ok(HammockWl {
             exit,
             handle: wl_handle,
+            outputs,
+            power_rx,
         })
     }
 
     pub fn exit(&self) {
         *self.exit.lock() = true;
     }
+
+    /// Whether any output is powered on, or None if
+    /// we don't know about any outputs.
+    pub(super) fn outputs_powered(&self) -> Option<bool> {
+        let outputs = self.outputs.lock();
+        if outputs.is_empty() {
+            return None;
+        }
+        Some(outputs.values().any(|on| *on))
+    }
+
+    /// Block until an output's power state changes or the timeout
+    /// passes, returns whether anything changed. Changes from before
+    /// the call count too.
+    pub(super) fn wait_outputs_changed(&self, timeout: Duration) -> bool {
+        self.power_rx.recv_timeout(timeout).is_ok()
+    }
+}
+
+impl HammockWlInner {
+    fn bind_output(&self, registry: &WlRegistry, name: u32, version: u32, qh: &QueueHandle<Self>) {
+        if let Some(ref manager) = self.power_manager {
+            let output = registry.bind::<WlOutput, _, _>(name, version.min(4), qh, ());
+            manager.get_output_power(&output, qh, name);
+            // Assume it's on until the compositor tells us otherwise
+            self.outputs.lock().insert(name, true);
+        }
+    }
 }
 
 impl HammockEventSource for HammockWl {
@@ -120,22 +191,81 @@ impl HammockEventSource for HammockWl {
 
 impl wayland_client::Dispatch<WlRegistry, GlobalListContents> for HammockWlInner {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlRegistry,
+        state: &mut Self,
+        proxy: &WlRegistry,
         event: Event,
         // This mutex contains an up-to-date list of the currently known globals
         // including the one that was just added or destroyed
         _data: &GlobalListContents,
         _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            Event::Global {
+                name,
+                interface,
+                version,
+            } => {
+                //trace!("NEW global: [{}] {} (v{})", name, interface, version);
+                if interface == "wl_output" {
+                    state.bind_output(proxy, name, version, qhandle);
+                }
+            }
+            Event::GlobalRemove { name } => {
+                state.outputs.lock().remove(&name);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, ()> for HammockWlInner {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlOutput,
+        _event: <WlOutput as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
-        if let Event::Global {
-            name: _,
-            interface: _,
-            version: _,
-        } = event
-        {
-            //trace!("NEW global: [{}] {} (v{})", name, interface, version);
+    }
+}
+
+impl Dispatch<OutputPowerManager, ()> for HammockWlInner {
+    fn event(
+        _state: &mut Self,
+        _proxy: &OutputPowerManager,
+        _event: <OutputPowerManager as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<OutputPower, u32> for HammockWlInner {
+    fn event(
+        state: &mut Self,
+        _proxy: &OutputPower,
+        event: <OutputPower as Proxy>::Event,
+        // The wl_output global name
+        name: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            OutputPowerEvent::Mode { mode } => {
+                let on = !matches!(mode, WEnum::Value(OutputPowerMode::Off));
+                trace!("Output {} powered {}", name, if on { "on" } else { "off" });
+                state.outputs.lock().insert(*name, on);
+                let _ = state.power_tx.send(());
+            }
+            // The output went away or doesn't support power management
+            OutputPowerEvent::Failed => {
+                state.outputs.lock().remove(name);
+                let _ = state.power_tx.send(());
+            }
+            _ => {}
         }
     }
 }
@@ -18,13 +18,14 @@
 */
 
 use std::sync::Arc;
+use std::time::Instant;
 use cgroups_rs::freezer::FreezerController;
 use cgroups_rs::{Cgroup, CgroupPid};
 use anyhow::Result;
 use parking_lot::RwLock;
 use strum_macros::Display;
 use crate::app_track::AppId;
-use crate::config::{Rule, Tag};
+use crate::config::{FreezeMode, Rule, Tag};
 use crate::cgroups::CGHandler;
 
 pub struct AppMatchInfo {
@@ -32,6 +33,14 @@ pub struct AppMatchInfo {
     pub cgroup: Cgroup,
     pub tags: Vec<Tag>,
     pub match_rule: Rule,
+    /// When the app entered its current rule
+    pub rule_since: Instant,
+    /// How the app is currently stopped, if at all
+    pub freeze_mode: FreezeMode,
+    /// Whether one of the app's toplevels is activated
+    pub focused: bool,
+    /// When the app last gained or lost focus
+    pub last_focused: Option<Instant>,
 }
 
 // FIXME: doesn't belong here...
@@ -66,6 +75,10 @@ impl App {
                 app_id,
                 tags: Vec::new(),
                 match_rule: Rule::Foreground,
+                rule_since: Instant::now(),
+                freeze_mode: FreezeMode::Off,
+                focused: true,
+                last_focused: None,
                 cgroup,
             })),
             pid
@@ -84,6 +97,11 @@ impl App {
         self.info.read().cgroup.tasks().into()
     }
 
+    /// Name of the app's cgroup relative to the hammock root
+    pub fn cgroup_name(&self) -> String {
+        format!("{}-{}", self.info.read().app_id, self.pid)
+    }
+
     pub fn get_info(&self) -> Arc<RwLock<AppMatchInfo>> {
         self.info.clone()
     }
@@ -17,16 +17,122 @@
 * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 */
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Base config, by default the one for this device from
+    /// /usr/share/hammock/devices. Overlays are merged on top of it.
     #[arg(short, long)]
     pub config_path: Option<PathBuf>,
     #[arg(short, long, default_value = "/run/user/10000")]
     pub xdg_runtime_dir: String,
     #[arg(short, long, default_value = "wayland-0")]
     pub wayland_display: String,
+    /// Check the config and exit, doesn't check it against this device
+    /// unless --device is also given
+    #[arg(long)]
+    pub check_config: bool,
+    /// With --check-config, also check the config suits this device
+    #[arg(long)]
+    pub device: bool,
+    /// Print the JSON schema of the config format (as YAML) and exit
+    #[arg(long)]
+    pub dump_schema: bool,
+    /// Print the config with all its layers merged and tunables
+    /// substituted, as parsed, and exit
+    #[arg(long)]
+    pub dump_config: bool,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct CtlArgs {
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Inspect and take keep-awake holds
+    #[command(subcommand)]
+    KeepAwake(KeepAwakeCommand),
+    /// Inspect the freezer
+    #[command(subcommand)]
+    Freezer(FreezerCommand),
+    /// Inspect memory management
+    #[command(subcommand)]
+    Memory(MemoryCommand),
+    /// Show how much CPU budget apps have left
+    Budget,
+    /// Show per-app CPU, memory and freeze time accounting
+    Usage {
+        /// Add up days rather than hours
+        #[arg(short, long)]
+        daily: bool,
+        /// How many hours (or days) to go back
+        #[arg(short, long, default_value_t = 24)]
+        count: u32,
+        /// Break down a single app's usage per hour (or day)
+        app: Option<String>,
+    },
+    /// Inspect and pick power profiles
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+    /// Reload hammockd's config and show what changed
+    Reload,
+    /// Inspect the config in effect
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeepAwakeCommand {
+    /// List the active holds
+    List,
+    /// Show per-app keep-awake accounting
+    Stats,
+    /// Take a hold until the timeout expires or we're interrupted
+    Hold {
+        /// "device" or "app"
+        #[arg(short, long, default_value = "device")]
+        scope: String,
+        #[arg(short, long)]
+        reason: String,
+        /// Seconds
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FreezerCommand {
+    /// List apps that couldn't be frozen and why
+    Unfreezable,
+    /// Show per-app freeze latency
+    Stats,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MemoryCommand {
+    /// Show how much memory was reclaimed from snoozed apps
+    Reclaimed,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// List the configured profiles
+    List,
+    /// Show the active profile and why it's active
+    Get,
+    /// Pick a profile, "auto" goes back to choosing one automatically
+    Set { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Show every value and which file (or tunable) it came from
+    Show,
 }
@@ -0,0 +1,462 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Opportunistic suspend, roughly what Android does with
+// /sys/power/autosleep except we do it from userspace so that
+// logind stays in charge of actually suspending.
+//
+// Once the device is idle, the screen is off and nothing is
+// blocking sleep we do the wakeup_count handshake from a worker
+// thread (the read blocks while wakeup events are in progress) and
+// ask logind to suspend. If the kernel aborts the suspend (a wakeup
+// event raced us) we back off exponentially before trying again.
+
+use crate::config::AutosleepConfig;
+use crate::hal::{Backlight, Power, SuspendStats};
+use crate::keepawake::KeepAwake;
+use anyhow::Result;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// If logind hasn't sent PrepareForSleep this long after we
+/// asked it to suspend then something is holding it up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum State {
+    /// Waiting for the device to become idle
+    Awake,
+    /// The wakeup_count handshake is running on a worker thread
+    Attempting(JoinHandle<Result<()>>),
+    /// logind accepted our request, waiting for PrepareForSleep
+    Requested(Instant),
+    /// Between PrepareForSleep(true) and PrepareForSleep(false)
+    Suspended(SuspendStats),
+}
+
+/// Everything autosleep needs from the rest of the system, so that
+/// the state machine can be driven by the tests
+trait Platform: Send {
+    fn now(&self) -> Instant;
+    /// Why the device has to stay awake, None if it could sleep
+    fn inhibitor(&self) -> Option<String>;
+    fn suspend_stats(&self) -> Result<SuspendStats>;
+    /// Start an attempt to suspend
+    fn attempt(&self) -> JoinHandle<Result<()>>;
+}
+
+/// The screen, logind's idle hint and inhibitors
+struct Logind {
+    conn: Connection,
+    backlight: Backlight,
+    power: Power,
+}
+
+impl Logind {
+    fn new() -> Result<Self> {
+        let conn = match Connection::new_system() {
+            Ok(c) => c,
+            Err(e) => bail!("Failed to connect to DBUS system bus: {}", e),
+        };
+
+        Ok(Self {
+            conn,
+            backlight: Backlight::default(),
+            power: Power::new(),
+        })
+    }
+
+    /// Runs on a worker thread, the wakeup_count handshake ensures
+    /// that the kernel will abort the suspend if any wakeup events
+    /// happen between now and when logind writes /sys/power/state.
+    fn suspend() -> Result<()> {
+        let power = Power::new();
+        let count = power.wakeup_count()?;
+        if !power.set_wakeup_count(count)? {
+            bail!("Wakeup events occurred during handshake");
+        }
+
+        let conn = Connection::new_system()?;
+        let proxy = conn.with_proxy(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            Duration::from_millis(1000),
+        );
+
+        let _: () = proxy.method_call("org.freedesktop.login1.Manager", "Suspend", (false,))?;
+        Ok(())
+    }
+}
+
+impl Platform for Logind {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn inhibitor(&self) -> Option<String> {
+        match self.backlight.is_off() {
+            Ok(true) => {}
+            Ok(false) => return Some("the screen is on".into()),
+            Err(e) => {
+                warn!("Couldn't read backlight state: {}", e);
+                return Some("unknown backlight state".into());
+            }
+        }
+
+        let proxy = self.conn.with_proxy(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            Duration::from_millis(1000),
+        );
+
+        match proxy.get::<bool>("org.freedesktop.login1.Manager", "IdleHint") {
+            Ok(true) => {}
+            Ok(false) => return Some("the session isn't idle".into()),
+            Err(e) => {
+                warn!("Couldn't get IdleHint from logind: {}", e);
+                return Some("unknown idle hint".into());
+            }
+        }
+
+        // what, who, why, mode, uid, pid
+        let res: Result<(Vec<(String, String, String, String, u32, u32)>,), dbus::Error> =
+            proxy.method_call("org.freedesktop.login1.Manager", "ListInhibitors", ());
+        let inhibitors = match res {
+            Ok((inhibitors,)) => inhibitors,
+            Err(e) => {
+                warn!("Couldn't list logind inhibitors: {}", e);
+                return Some("unknown logind inhibitors".into());
+            }
+        };
+
+        // Delay inhibitors (like our own) don't stop us suspending
+        inhibitors
+            .iter()
+            .find(|(what, _, _, mode, _, _)| mode == "block" && what.split(':').any(|w| w == "sleep"))
+            .map(|(_, who, why, _, _, pid)| format!("{} ({}): {}", who, pid, why))
+    }
+
+    fn suspend_stats(&self) -> Result<SuspendStats> {
+        self.power.suspend_stats()
+    }
+
+    fn attempt(&self) -> JoinHandle<Result<()>> {
+        thread::spawn(Self::suspend)
+    }
+}
+
+pub struct Autosleep {
+    config: AutosleepConfig,
+    platform: Box<dyn Platform>,
+    state: State,
+    /// When all the conditions for sleeping were first met
+    idle_since: Option<Instant>,
+    next_check: Instant,
+    /// Don't attempt to suspend before this point
+    not_before: Instant,
+    backoff: Duration,
+}
+
+impl Autosleep {
+    /// None if autosleep is disabled, nothing needs watching then
+    pub fn new(config: AutosleepConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::with_platform(config, Box::new(Logind::new()?))))
+    }
+
+    fn with_platform(config: AutosleepConfig, platform: Box<dyn Platform>) -> Self {
+        let now = platform.now();
+        Self {
+            backoff: Duration::from_secs(config.backoff_initial as u64),
+            config,
+            platform,
+            state: State::Awake,
+            idle_since: None,
+            next_check: now,
+            not_before: now,
+        }
+    }
+
+    /// Called from the event loop, decide whether we should try
+    /// to suspend and check up on any attempt in progress.
+    pub fn process(&mut self, keepawake: &KeepAwake) -> Result<()> {
+        let now = self.platform.now();
+        self.state = match std::mem::replace(&mut self.state, State::Awake) {
+            State::Attempting(handle) if handle.is_finished() => match handle.join() {
+                Ok(Ok(())) => {
+                    debug!("Suspend requested");
+                    State::Requested(now)
+                }
+                Ok(Err(e)) => {
+                    info!("Suspend attempt aborted: {}", e);
+                    self.failed(now);
+                    State::Awake
+                }
+                Err(_) => {
+                    error!("Suspend attempt thread panicked!");
+                    self.failed(now);
+                    State::Awake
+                }
+            },
+            State::Requested(at) if now.duration_since(at) > REQUEST_TIMEOUT => {
+                warn!("logind didn't suspend after {}s", REQUEST_TIMEOUT.as_secs());
+                self.failed(now);
+                State::Awake
+            }
+            State::Awake if now >= self.next_check && now >= self.not_before => {
+                self.next_check = now + Duration::from_secs(self.config.poll_interval as u64);
+                if !self.can_sleep(keepawake) {
+                    self.idle_since = None;
+                    State::Awake
+                } else {
+                    let since = *self.idle_since.get_or_insert(now);
+                    if now.duration_since(since) >= Duration::from_secs(self.config.idle_time as u64) {
+                        info!("Device idle for {}s, attempting to suspend", now.duration_since(since).as_secs());
+                        State::Attempting(self.platform.attempt())
+                    } else {
+                        State::Awake
+                    }
+                }
+            }
+            state => state,
+        };
+
+        Ok(())
+    }
+
+    /// Called when logind sends PrepareForSleep, regardless of
+    /// whether we were the ones that asked for it.
+    pub fn on_suspend(&mut self, active: bool) {
+        let stats = self.platform.suspend_stats().unwrap_or_else(|e| {
+            warn!("Couldn't read suspend stats: {}", e);
+            SuspendStats::default()
+        });
+
+        if active {
+            self.state = State::Suspended(stats);
+            return;
+        }
+
+        let now = self.platform.now();
+        let aborted = match self.state {
+            State::Suspended(before) => stats.fail > before.fail,
+            _ => false,
+        };
+
+        self.state = State::Awake;
+        self.idle_since = None;
+        if aborted {
+            info!("Suspend was aborted by the kernel");
+            self.failed(now);
+        } else {
+            self.backoff = Duration::from_secs(self.config.backoff_initial as u64);
+            self.not_before = now + Duration::from_secs(self.config.min_awake as u64);
+        }
+    }
+
+    fn failed(&mut self, now: Instant) {
+        debug!("Backing off autosleep for {}s", self.backoff.as_secs());
+        self.not_before = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(self.config.backoff_max as u64));
+    }
+
+    fn can_sleep(&self, keepawake: &KeepAwake) -> bool {
+        if let Some(hold) = keepawake.blocks_sleep() {
+            trace!("Sleep blocked by keep-awake #{} from {}: {}", hold.id, hold.app_id, hold.reason);
+            return false;
+        }
+
+        match self.platform.inhibitor() {
+            Some(why) => {
+                trace!("Sleep blocked: {}", why);
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeepAwakeConfig;
+    use crate::keepawake::HoldScope;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Fake {
+        elapsed: Duration,
+        inhibitor: Option<String>,
+        stats: SuspendStats,
+        /// Whether the next attempt gets through the handshake
+        fail_attempt: bool,
+        attempts: u32,
+    }
+
+    struct FakePlatform {
+        start: Instant,
+        fake: Arc<Mutex<Fake>>,
+    }
+
+    impl Platform for FakePlatform {
+        fn now(&self) -> Instant {
+            self.start + self.fake.lock().elapsed
+        }
+
+        fn inhibitor(&self) -> Option<String> {
+            self.fake.lock().inhibitor.clone()
+        }
+
+        fn suspend_stats(&self) -> Result<SuspendStats> {
+            Ok(self.fake.lock().stats)
+        }
+
+        fn attempt(&self) -> JoinHandle<Result<()>> {
+            let mut fake = self.fake.lock();
+            fake.attempts += 1;
+            let fail = fake.fail_attempt;
+            thread::spawn(move || if fail { Err(anyhow!("wakeup event")) } else { Ok(()) })
+        }
+    }
+
+    fn autosleep() -> (Autosleep, Arc<Mutex<Fake>>) {
+        let config = AutosleepConfig {
+            enabled: true,
+            idle_time: 30,
+            min_awake: 5,
+            backoff_initial: 2,
+            backoff_max: 8,
+            poll_interval: 1,
+        };
+        let fake = Arc::new(Mutex::new(Fake::default()));
+        let platform = FakePlatform { start: Instant::now(), fake: fake.clone() };
+        (Autosleep::with_platform(config, Box::new(platform)), fake)
+    }
+
+    /// Move the clock on and run the event loop, waiting for any
+    /// attempt to finish so it's picked up straight away
+    fn advance(autosleep: &mut Autosleep, fake: &Mutex<Fake>, secs: u64, keepawake: &KeepAwake) {
+        fake.lock().elapsed += Duration::from_secs(secs);
+        if let State::Attempting(handle) = &autosleep.state {
+            while !handle.is_finished() {
+                thread::yield_now();
+            }
+        }
+        autosleep.process(keepawake).unwrap();
+    }
+
+    fn backoff_for(autosleep: &Autosleep) -> u64 {
+        autosleep.not_before.duration_since(autosleep.platform.now()).as_secs()
+    }
+
+    #[test]
+    fn disabled() {
+        assert!(Autosleep::new(AutosleepConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn transitions() {
+        let (mut autosleep, fake) = autosleep();
+        let mut keepawake = KeepAwake::new(KeepAwakeConfig::default());
+
+        // Not idle for long enough yet
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        advance(&mut autosleep, &fake, 29, &keepawake);
+        assert!(matches!(autosleep.state, State::Awake));
+
+        // Anything keeping the device awake restarts the idle time
+        fake.lock().inhibitor = Some("the screen is on".into());
+        advance(&mut autosleep, &fake, 1, &keepawake);
+        assert!(autosleep.idle_since.is_none());
+        fake.lock().inhibitor = None;
+        let hold = keepawake.acquire(":1.1", None, HoldScope::Device, "downloading", 0).unwrap();
+        advance(&mut autosleep, &fake, 1, &keepawake);
+        assert!(autosleep.idle_since.is_none());
+        keepawake.release(":1.1", hold).unwrap();
+
+        advance(&mut autosleep, &fake, 1, &keepawake);
+        advance(&mut autosleep, &fake, 30, &keepawake);
+        assert!(matches!(autosleep.state, State::Attempting(_)));
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        assert!(matches!(autosleep.state, State::Requested(_)));
+
+        autosleep.on_suspend(true);
+        assert!(matches!(autosleep.state, State::Suspended(_)));
+        fake.lock().stats.success += 1;
+        autosleep.on_suspend(false);
+        assert!(matches!(autosleep.state, State::Awake));
+        assert_eq!(backoff_for(&autosleep), 5);
+        assert_eq!(fake.lock().attempts, 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let (mut autosleep, fake) = autosleep();
+        let keepawake = KeepAwake::new(KeepAwakeConfig::default());
+        fake.lock().fail_attempt = true;
+
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        advance(&mut autosleep, &fake, 30, &keepawake);
+        for expected in [2, 4, 8, 8] {
+            assert!(matches!(autosleep.state, State::Attempting(_)));
+            advance(&mut autosleep, &fake, 0, &keepawake);
+            assert!(matches!(autosleep.state, State::Awake));
+            assert_eq!(backoff_for(&autosleep), expected);
+
+            // Nothing until the backoff is over
+            advance(&mut autosleep, &fake, expected - 1, &keepawake);
+            assert!(matches!(autosleep.state, State::Awake));
+            advance(&mut autosleep, &fake, 1, &keepawake);
+        }
+
+        fake.lock().fail_attempt = false;
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        assert_eq!(backoff_for(&autosleep), 8);
+        advance(&mut autosleep, &fake, 8, &keepawake);
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        assert!(matches!(autosleep.state, State::Requested(_)));
+
+        // The kernel aborting the suspend backs off too
+        autosleep.on_suspend(true);
+        fake.lock().stats.fail += 1;
+        autosleep.on_suspend(false);
+        assert_eq!(backoff_for(&autosleep), 8);
+
+        // logind never suspending
+        advance(&mut autosleep, &fake, 8, &keepawake);
+        advance(&mut autosleep, &fake, 30, &keepawake);
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        assert!(matches!(autosleep.state, State::Requested(_)));
+        advance(&mut autosleep, &fake, REQUEST_TIMEOUT.as_secs() + 1, &keepawake);
+        assert!(matches!(autosleep.state, State::Awake));
+        assert_eq!(backoff_for(&autosleep), 8);
+
+        // A successful suspend resets it
+        advance(&mut autosleep, &fake, 8, &keepawake);
+        advance(&mut autosleep, &fake, 0, &keepawake);
+        autosleep.on_suspend(true);
+        autosleep.on_suspend(false);
+        assert_eq!(autosleep.backoff, Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,220 @@
+/*
+* Hammock control utility
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use clap::Parser;
+use hammock::args::{ConfigCommand, CtlArgs, CtlCommand, FreezerCommand, KeepAwakeCommand, MemoryCommand, ProfileCommand};
+use hammock::dbus::hammock1::{AccountingProxyBlocking, BudgetProxyBlocking, ConfigProxyBlocking, FreezerProxyBlocking, KeepAwakeProxyBlocking, MemoryProxyBlocking, ProfileProxyBlocking};
+use std::time::Duration;
+use zbus::blocking::Connection;
+
+fn main() -> Result<()> {
+    let args = CtlArgs::parse();
+    let conn = Connection::system()?;
+
+    match args.command {
+        CtlCommand::KeepAwake(cmd) => keep_awake(&conn, cmd),
+        CtlCommand::Freezer(cmd) => freezer(&conn, cmd),
+        CtlCommand::Memory(cmd) => memory(&conn, cmd),
+        CtlCommand::Budget => budget(&conn),
+        CtlCommand::Usage { daily, count, app } => usage(&conn, daily, count, app),
+        CtlCommand::Profile(cmd) => profile(&conn, cmd),
+        CtlCommand::Reload => reload(&conn),
+        CtlCommand::Config(cmd) => config(&conn, cmd),
+    }
+}
+
+fn keep_awake(conn: &Connection, cmd: KeepAwakeCommand) -> Result<()> {
+    let proxy = KeepAwakeProxyBlocking::new(conn)?;
+
+    match cmd {
+        KeepAwakeCommand::List => {
+            println!("{:>4} {:<32} {:<8} {:>6} {:>6}  REASON", "ID", "APP", "SCOPE", "HELD", "LEFT");
+            for (id, app_id, owner, scope, reason, held, left) in proxy.list()? {
+                println!("{:>4} {:<32} {:<8} {:>5}s {:>5}s  {} ({})", id, app_id, scope, held, left, reason, owner);
+            }
+        }
+        KeepAwakeCommand::Stats => {
+            let mut stats = proxy.stats()?;
+            // Worst offenders first
+            stats.sort_by(|a, b| b.3.cmp(&a.3));
+            println!("{:<32} {:>8} {:>8} {:>8} {:>8}", "APP", "ACQUIRED", "EXPIRED", "HELD", "LONGEST");
+            for (app_id, acquired, expired, held, longest) in stats {
+                println!("{:<32} {:>8} {:>8} {:>7}s {:>7}s", app_id, acquired, expired, held, longest);
+            }
+        }
+        KeepAwakeCommand::Hold { scope, reason, timeout } => {
+            let id = proxy.acquire(&scope, &reason, timeout)?;
+            println!("Holding keep-awake #{} for {}s", id, timeout);
+            // The hold is dropped when we disconnect from the bus
+            std::thread::sleep(Duration::from_secs(timeout as u64));
+        }
+    }
+
+    Ok(())
+}
+
+fn freezer(conn: &Connection, cmd: FreezerCommand) -> Result<()> {
+    let proxy = FreezerProxyBlocking::new(conn)?;
+
+    match cmd {
+        FreezerCommand::Unfreezable => {
+            for (cgroup, reason) in proxy.unfreezable()? {
+                println!("{}: {}", cgroup, reason);
+            }
+        }
+        FreezerCommand::Stats => {
+            let mut stats = proxy.stats()?;
+            // Slowest first
+            stats.sort_by(|a, b| b.3.cmp(&a.3));
+            println!("{:<32} {:>8} {:>8} {:>8} {:>8}", "APP", "FREEZES", "AVG", "MAX", "TIMEOUTS");
+            for (app_id, freezes, avg, max, timeouts) in stats {
+                println!("{:<32} {:>8} {:>6}ms {:>6}ms {:>8}", app_id, freezes, avg, max, timeouts);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn memory(conn: &Connection, cmd: MemoryCommand) -> Result<()> {
+    let proxy = MemoryProxyBlocking::new(conn)?;
+
+    match cmd {
+        MemoryCommand::Reclaimed => {
+            let mut stats = proxy.reclaimed()?;
+            // Biggest first
+            stats.sort_by(|a, b| b.1.cmp(&a.1));
+            println!("{:<32} {:>10} {:>6}", "APP", "RECLAIMED", "RUNS");
+            for (app_id, reclaimed, runs) in stats {
+                println!("{:<32} {:>8}MB {:>6}", app_id, reclaimed >> 20, runs);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn budget(conn: &Connection) -> Result<()> {
+    let proxy = BudgetProxyBlocking::new(conn)?;
+    let mut budgets = proxy.remaining()?;
+    // Closest to running out first
+    budgets.sort_by(|a, b| (a.3 / a.4).total_cmp(&(b.3 / b.4)));
+
+    println!("{:<40} {:<12} {:>10} {:>10}", "APP", "RULE", "REMAINING", "BUDGET");
+    for (_, cgroup, rule, remaining, capacity, exhausted) in budgets {
+        println!("{:<40} {:<12} {:>9.1}s {:>9.1}s{}", cgroup, rule, remaining, capacity,
+            if exhausted { "  (exhausted)" } else { "" });
+    }
+
+    Ok(())
+}
+
+fn usage(conn: &Connection, daily: bool, count: u32, app: Option<String>) -> Result<()> {
+    let proxy = AccountingProxyBlocking::new(conn)?;
+    let rollup = if daily { "day" } else { "hour" };
+    let format_start = |start: u64| {
+        Local
+            .timestamp_opt(start as i64, 0)
+            .single()
+            .map_or_else(|| start.to_string(), |t| t.format("%Y-%m-%d %H:%M").to_string())
+    };
+
+    if let Some(app_id) = app {
+        println!("{:<16} {:>10} {:>9} {:>8} {:>8} {:>8}", "FROM", "CPU", "ENERGY", "PEAK", "FROZEN", "THAWED");
+        for (start, cpu, energy, peak, frozen, thawed) in proxy.history(rollup, count, &app_id)? {
+            println!("{:<16} {:>9.1}s {:>6.1}mWh {:>6}MB {:>7}s {:>7}s",
+                format_start(start), cpu as f64 / 1e6, energy, peak >> 20, frozen, thawed);
+        }
+        return Ok(());
+    }
+
+    let mut usage = proxy.usage(rollup, count)?;
+    // Hungriest first
+    usage.sort_by(|a, b| b.2.total_cmp(&a.2));
+    println!("{:<32} {:>10} {:>9} {:>8} {:>8} {:>8} {:>8}  RULES",
+        "APP", "CPU", "ENERGY", "PEAK", "AVG", "FROZEN", "THAWED");
+    for (app_id, cpu, energy, peak, avg, frozen, thawed, rules) in usage {
+        let mut rules: Vec<_> = rules.into_iter().collect();
+        rules.sort_by(|a, b| b.1.cmp(&a.1));
+        let rules: Vec<String> = rules.iter().map(|(rule, secs)| format!("{}:{}s", rule, secs)).collect();
+        println!("{:<32} {:>9.1}s {:>6.1}mWh {:>6}MB {:>6}MB {:>7}s {:>7}s  {}",
+            app_id, cpu as f64 / 1e6, energy, peak >> 20, avg >> 20, frozen, thawed, rules.join(" "));
+    }
+
+    let (battery, apps) = proxy
+        .battery(rollup, count)?
+        .iter()
+        .fold((0.0, 0.0), |(battery, apps), (_, b, a)| (battery + b, apps + a));
+    if battery > 0.0 {
+        println!("\nBattery drained {:.0}mWh, apps used an estimated {:.0}mWh of it on the CPU ({:.0}%)",
+            battery, apps, apps / battery * 100.0);
+    }
+
+    Ok(())
+}
+
+fn profile(conn: &Connection, cmd: ProfileCommand) -> Result<()> {
+    let proxy = ProfileProxyBlocking::new(conn)?;
+
+    match cmd {
+        ProfileCommand::List => {
+            let (active, _) = proxy.get()?;
+            for name in proxy.list()? {
+                println!("{} {}", if name == active { "*" } else { " " }, name);
+            }
+        }
+        ProfileCommand::Get => match proxy.get()? {
+            (name, _) if name.is_empty() => println!("No power profile active"),
+            (name, source) => println!("{} ({})", name, source),
+        },
+        ProfileCommand::Set { name } => {
+            proxy.set(if name == "auto" { "" } else { &name })?;
+        }
+    }
+
+    Ok(())
+}
+
+fn reload(conn: &Connection) -> Result<()> {
+    let changes = ConfigProxyBlocking::new(conn)?.reload()?;
+    if changes.is_empty() {
+        println!("Config reloaded, nothing changed");
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+
+    Ok(())
+}
+
+fn config(conn: &Connection, cmd: ConfigCommand) -> Result<()> {
+    let proxy = ConfigProxyBlocking::new(conn)?;
+
+    match cmd {
+        ConfigCommand::Show => {
+            for (path, value, source) in proxy.show()? {
+                println!("{} = {}  ({})", path, value, source);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -17,12 +17,13 @@
 * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 */
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
 use hammock::args::Args;
 use hammock::match_rules::MatchRules;
-use hammock::{cgroups::CGHandler, config::Config};
-use log::info;
+use hammock::reload;
+use hammock::{cgroups::CGHandler, config::Config, config::lint::Severity};
+use log::{info, warn};
 use hammock::hammock::{self as hmk, Hammock};
 use std::io::Write;
 
@@ -33,30 +34,72 @@ use std::io::Write;
 // }
 
 fn main() -> Result<()> {
+    // Before anything starts a thread, so they all inherit the mask
+    // and SIGHUP can only reach the thread waiting for it
+    reload::block_sighup()?;
     setup_logging();
 
     //let are_root = nix::unistd::getuid() == nix::unistd::Uid::from_raw(0);
 
     let args = Args::parse();
 
+    if args.dump_schema {
+        print!("{}", Config::schema()?);
+        return Ok(());
+    }
+
+    if args.dump_config {
+        print!("{}", Config::merged(args.config_path)?);
+        return Ok(());
+    }
+
     let config = match Config::load(args.config_path) {
         Ok(c) => c,
         Err(e) => bail!("Failed to load config: {}", e),
     };
 
-    let handler = CGHandler::new();
+    if args.check_config {
+        return check_config(&config, args.device);
+    }
+
+    if let Err(e) = config.validate_memory() {
+        bail!("Unsuitable config for this device: {}", e);
+    }
+    let layout = match config.validate_cpus() {
+        Ok(layout) => layout,
+        Err(e) => bail!("Unsuitable config for this device: {}", e),
+    };
+    for lint in config.lint(Some(&layout)) {
+        match lint.severity {
+            Severity::Info => info!("Config: {}", lint),
+            _ => warn!("Config: {}", lint),
+        }
+    }
+
+    let handler = match config.freeze_all() {
+        Ok(c) => CGHandler::new(c.allowlist),
+        Err(e) => bail!("Invalid freeze-all config: {}", e),
+    };
     let rules = match config.parse_rules() {
         Ok(r) => MatchRules(r),
         Err(e) => bail!("Failed to parse rules: {}", e),
     };
+    handler.resolve_io_devices(rules.iter().map(|r| r.cgroup()))?;
+
+    // Power profiles can point rules at other block devices
+    let profiles = match config.power_profiles() {
+        Ok(p) => p,
+        Err(e) => bail!("Invalid power-profiles config: {}", e),
+    };
+    for name in profiles.profiles.keys() {
+        let profile_rules = config.profile_rules(Some(name))?;
+        handler.resolve_io_devices(profile_rules.iter().map(|r| r.cgroup()))?;
+    }
 
-    let hammock = Hammock::new(rules, handler);
+    let hammock = Hammock::new(config, rules, handler)?;
 
-    info!(
-        "Hammock daemon started! Loaded {} rules.\n{}",
-        hammock.rules.len(),
-        &hammock.rules
-    );
+    let rules = hammock.rules();
+    info!("Hammock daemon started! Loaded {} rules.\n{}", rules.len(), &rules);
 
     hmk::event_loop(hammock, &args.xdg_runtime_dir, &args.wayland_display)?;
 
@@ -74,6 +117,30 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn check_config(config: &Config, device: bool) -> Result<()> {
+    let path = config.path().display();
+    if let Err(e) = config.check() {
+        bail!("{}: {}", path, e);
+    }
+    let layout = match device {
+        true => {
+            config.validate_memory().map_err(|e| anyhow!("{}: unsuitable for this device: {}", path, e))?;
+            Some(config.validate_cpus().map_err(|e| anyhow!("{}: unsuitable for this device: {}", path, e))?)
+        }
+        false => None,
+    };
+
+    let lints = config.lint(layout.as_ref());
+    for lint in &lints {
+        println!("{}: {}", path, lint);
+    }
+    if lints.iter().any(|lint| lint.severity == Severity::Error) {
+        bail!("{}: the match rules have errors", path);
+    }
+    println!("{}: OK", path);
+    Ok(())
+}
+
 fn setup_logging() {
     #[cfg(debug_assertions)]
     ::std::env::set_var("RUST_LOG", "trace");
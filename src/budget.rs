@@ -0,0 +1,185 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// CPU time budgets. A rule can give apps a token bucket of CPU time,
+// e.g. 30 seconds per hour, so they can sync a message now and then
+// without being allowed to spin forever. CPU time used while in the
+// rule (from cpu.stat usage_usec) is taken out of the bucket, which
+// refills at a constant rate. Once it's empty the app is stopped with
+// the budget's action until enough has been refilled.
+//
+// Each app has a bucket per rule, so time spent in the foreground
+// doesn't count against its background budget and bouncing between
+// rules doesn't reset anything.
+
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::{CpuBudgetConfig, FreezeMode, Rule};
+use crate::match_rules::MatchRules;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    config: CpuBudgetConfig,
+    /// Microseconds of CPU time left
+    tokens: f64,
+    refilled: Instant,
+    exhausted: bool,
+}
+
+impl Bucket {
+    fn new(config: CpuBudgetConfig) -> Self {
+        Self {
+            tokens: config.seconds as f64 * 1e6,
+            config,
+            refilled: Instant::now(),
+            exhausted: false,
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.config.seconds as f64 * 1e6
+    }
+
+    /// How many tokens there would be after refilling up to now
+    fn refilled_at(&self, now: Instant) -> f64 {
+        let rate = self.capacity() / self.config.per as f64;
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        (self.tokens + elapsed * rate).min(self.capacity())
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.refilled_at(now);
+        self.refilled = now;
+    }
+}
+
+/// A snapshot of an app's budget for the API
+pub struct BudgetInfo {
+    pub app_id: String,
+    pub cgroup: String,
+    pub rule: Rule,
+    /// Seconds
+    pub remaining: f64,
+    pub capacity: f64,
+    pub exhausted: bool,
+}
+
+#[derive(Default)]
+pub struct CpuBudgets {
+    next_sample: Option<Instant>,
+    /// Keyed by cgroup
+    buckets: HashMap<String, HashMap<Rule, Bucket>>,
+    /// Last usage_usec of each cgroup
+    usage: HashMap<String, u64>,
+}
+
+impl CpuBudgets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the app ran out of budget in its current rule,
+    /// returns how it should be stopped
+    pub fn exhausted(&self, cgroup: &str, rule: Rule) -> Option<FreezeMode> {
+        self.buckets
+            .get(cgroup)
+            .and_then(|buckets| buckets.get(&rule))
+            .filter(|bucket| bucket.exhausted)
+            .map(|bucket| bucket.config.action)
+    }
+
+    pub fn list(&self) -> Vec<BudgetInfo> {
+        let now = Instant::now();
+        let mut list = Vec::new();
+        for (cgroup, buckets) in &self.buckets {
+            for (rule, bucket) in buckets {
+                list.push(BudgetInfo {
+                    app_id: CGHandler::app_id_of(cgroup),
+                    cgroup: cgroup.clone(),
+                    rule: *rule,
+                    remaining: bucket.refilled_at(now).max(0.0) / 1e6,
+                    capacity: bucket.capacity() / 1e6,
+                    exhausted: bucket.exhausted,
+                });
+            }
+        }
+        list
+    }
+
+    /// Charge apps for the CPU time they used, returns the cgroups
+    /// that ran out of budget or got enough back to run again.
+    pub fn process(&mut self, handler: &CGHandler, apps: &[App], rules: &MatchRules) -> Vec<String> {
+        let now = Instant::now();
+        if self.next_sample.map_or(false, |next| now < next) {
+            return Vec::new();
+        }
+        self.next_sample = Some(now + SAMPLE_INTERVAL);
+
+        let mut changed = Vec::new();
+        let mut seen = Vec::new();
+        for app in apps {
+            let cgroup = app.cgroup_name();
+            let rule = app.info.read().match_rule;
+            seen.push(cgroup.clone());
+
+            let usage = match handler.cpu_stat(&cgroup, "usage_usec") {
+                Ok(usage) => usage,
+                Err(e) => {
+                    trace!("Budget: {}", e);
+                    continue;
+                }
+            };
+            let used = self.usage.insert(cgroup.clone(), usage).map_or(0, |last| usage.saturating_sub(last));
+
+            let config = match rules.get(rule).ok().and_then(|r| r.cgroup().cpu_budget.clone()) {
+                Some(config) => config,
+                None => continue,
+            };
+            let bucket = self
+                .buckets
+                .entry(cgroup.clone())
+                .or_default()
+                .entry(rule)
+                .or_insert_with(|| Bucket::new(config));
+
+            bucket.refill(now);
+            bucket.tokens -= used as f64;
+
+            let resume_at = bucket.capacity() * bucket.config.resume_at as f64;
+            if !bucket.exhausted && bucket.tokens <= 0.0 {
+                info!("{} used up its {}s of CPU per {}s in {}", cgroup, bucket.config.seconds, bucket.config.per, rule);
+                bucket.exhausted = true;
+                changed.push(cgroup);
+            } else if bucket.exhausted && bucket.tokens >= resume_at.max(1.0) {
+                debug!("{} has {:.1}s of CPU budget again", cgroup, bucket.tokens / 1e6);
+                bucket.exhausted = false;
+                changed.push(cgroup);
+            }
+        }
+
+        // Forget apps that went away
+        self.buckets.retain(|cgroup, _| seen.contains(cgroup));
+        self.usage.retain(|cgroup, _| seen.contains(cgroup));
+
+        changed
+    }
+}
@@ -0,0 +1,167 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Guessing when an app is busy. Apps that aren't hammock aware won't
+// tell us they're exporting a video or downloading a file, so we look
+// at how much CPU and I/O they use once they're out of the foreground.
+// An app that stays over either threshold for a while gets the busy
+// tag, which rules can use in never-from to avoid freezing it mid
+// task. The tag only lasts for the configured timeout, after that
+// the app has to go quiet before it can get it again so that
+// something spinning forever can't stay busy.
+//
+// Throttled apps can't get anywhere near the CPU threshold, so being
+// throttled in most periods counts as wanting the CPU too.
+
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::{BusyConfig, Rule, Tag};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Sample {
+    at: Instant,
+    cpu_usec: u64,
+    io_bytes: u64,
+    nr_periods: u64,
+    nr_throttled: u64,
+    /// When the app went over a threshold
+    active_since: Option<Instant>,
+    tagged_at: Option<Instant>,
+    /// The tag timed out, wait for the app to go quiet
+    cooldown: bool,
+}
+
+pub struct BusyDetector {
+    config: Option<BusyConfig>,
+    next_sample: Instant,
+    /// Keyed by cgroup
+    samples: HashMap<String, Sample>,
+}
+
+impl BusyDetector {
+    pub fn new(config: Option<BusyConfig>) -> Self {
+        Self {
+            config,
+            next_sample: Instant::now(),
+            samples: HashMap::new(),
+        }
+    }
+
+    fn read(handler: &CGHandler, cgroup: &str, now: Instant) -> Option<Sample> {
+        Some(Sample {
+            at: now,
+            cpu_usec: handler.cpu_stat(cgroup, "usage_usec").ok()?,
+            // io.stat is missing if the io controller isn't enabled
+            io_bytes: handler.io_bytes(cgroup).unwrap_or(0),
+            // So are these without the cpu controller
+            nr_periods: handler.cpu_stat(cgroup, "nr_periods").unwrap_or(0),
+            nr_throttled: handler.cpu_stat(cgroup, "nr_throttled").unwrap_or(0),
+            active_since: None,
+            tagged_at: None,
+            cooldown: false,
+        })
+    }
+
+    pub fn process(&mut self, handler: &CGHandler, apps: &[App]) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+        let now = Instant::now();
+        if now < self.next_sample {
+            return;
+        }
+        self.next_sample = now + Duration::from_secs(config.interval as u64);
+
+        let sustain = Duration::from_secs(config.sustain as u64);
+        let timeout = Duration::from_secs(config.timeout as u64);
+        let mut seen = Vec::new();
+        for app in apps {
+            let cgroup = app.cgroup_name();
+            if app.info.read().match_rule == Rule::Foreground {
+                // Start from scratch next time it's backgrounded
+                self.samples.remove(&cgroup);
+                app.info.write().tags.retain(|t| *t != Tag::Busy);
+                continue;
+            }
+            seen.push(cgroup.clone());
+
+            let mut sample = match Self::read(handler, &cgroup, now) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let last = match self.samples.remove(&cgroup) {
+                Some(last) => last,
+                None => {
+                    self.samples.insert(cgroup, sample);
+                    continue;
+                }
+            };
+
+            let secs = now.duration_since(last.at).as_secs_f32().max(0.001);
+            let cpu_percent = sample.cpu_usec.saturating_sub(last.cpu_usec) as f32 / 1e4 / secs;
+            let io_kbps = sample.io_bytes.saturating_sub(last.io_bytes) as f32 * 8.0 / 1000.0 / secs;
+            let periods = sample.nr_periods.saturating_sub(last.nr_periods);
+            let throttled = sample.nr_throttled.saturating_sub(last.nr_throttled);
+            let active = cpu_percent >= config.cpu_percent
+                || io_kbps >= config.io_kbps as f32
+                || (periods > 0 && throttled * 2 > periods);
+
+            sample.active_since = match active {
+                true => last.active_since.or(Some(now)),
+                false => None,
+            };
+            sample.tagged_at = last.tagged_at;
+            sample.cooldown = last.cooldown && active;
+
+            let tagged = match sample.tagged_at {
+                Some(at) if !active || now.duration_since(at) >= timeout => {
+                    sample.cooldown = active;
+                    debug!("{} no longer busy{}", cgroup, if active { " (timed out)" } else { "" });
+                    false
+                }
+                Some(_) => true,
+                None => match sample.active_since {
+                    Some(since) if !sample.cooldown && now.duration_since(since) >= sustain => {
+                        debug!("{} is busy: {:.0}% CPU, {:.0}kbps I/O, throttled {}/{}",
+                            cgroup, cpu_percent, io_kbps, throttled, periods);
+                        true
+                    }
+                    _ => false,
+                },
+            };
+            sample.tagged_at = match tagged {
+                true => sample.tagged_at.or(Some(now)),
+                false => None,
+            };
+
+            {
+                let mut info = app.info.write();
+                info.tags.retain(|t| *t != Tag::Busy);
+                if tagged {
+                    info.tags.push(Tag::Busy);
+                }
+            }
+            self.samples.insert(cgroup, sample);
+        }
+
+        self.samples.retain(|cgroup, _| seen.contains(cgroup));
+    }
+}
@@ -17,26 +17,100 @@
 * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 */
 
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
-use crate::config::CgroupConfig;
+use crate::config::{CgroupConfig, CpuSet, FreezeAllowEntry, FreezeMode, IoConfig, MemorySize, ThrottleConfig};
+use crate::hal::{format_cpu_list, parse_cpu_list, CpuLayout, MemInfo};
 use anyhow::Result;
+use glob::Pattern;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use parking_lot::Mutex;
 use cgroups_rs::hierarchies::{V2, custom_v2};
 use cgroups_rs::{Cgroup, Hierarchy};
 use cgroups_rs::cgroup_builder::CgroupBuilder;
 use cgroups_rs::freezer::FreezerController;
 
+const ROOT_PATH: &str = "/sys/fs/cgroup/unified/tinydm";
+
 pub struct CGHandler {
     heirachy: Box<V2>,
     root: Cgroup,
+    path: PathBuf,
+    /// cgroups that freeze_all() must leave running
+    allowlist: Vec<FreezeAllowEntry>,
+    /// What the last call to freeze_all(true) froze
+    frozen: Mutex<FrozenSet>,
+    /// Total RAM in bytes, for resolving relative memory limits
+    mem_total: u64,
+    /// Block devices named in the config -> the "MAJ:MIN" of the disk
+    io_devices: Mutex<HashMap<String, String>>,
+    /// For resolving symbolic cpusets, the online CPUs change with hotplug
+    cpus: Mutex<CpuLayout>,
+    /// Optional knobs the kernel doesn't have, they aren't written again
+    missing_knobs: Mutex<HashSet<String>>,
+    /// Knobs that failed to be written and have been warned about
+    failed_knobs: Mutex<HashSet<String>>,
+}
+
+#[derive(Default)]
+enum FrozenSet {
+    #[default]
+    None,
+    /// Nothing was allowlisted so the whole root was frozen
+    Root,
+    /// Individual cgroups relative to the root
+    Cgroups(Vec<String>),
+}
+
+/// The outcome of freeze_all()
+#[derive(Debug, Default)]
+pub struct FreezeReport {
+    pub frozen: Vec<String>,
+    /// cgroups left running and why
+    pub skipped: Vec<(String, String)>,
+}
+
+impl fmt::Display for FreezeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "froze {} cgroups, left {} running", self.frozen.len(), self.skipped.len())?;
+        for (cgroup, reason) in &self.skipped {
+            writeln!(f, "\t{}: {}", cgroup, reason)?;
+        }
+        Ok(())
+    }
 }
 
 impl CGHandler {
-    pub fn new() -> Self {
+    pub fn new(allowlist: Vec<FreezeAllowEntry>) -> Self {
+        let mem_total = match MemInfo::read() {
+            Ok(info) => info.total,
+            Err(e) => {
+                warn!("Couldn't read memory info, relative memory limits won't be applied: {}", e);
+                0
+            }
+        };
+        let cpus = CpuLayout::detect().unwrap_or_else(|e| {
+            warn!("Couldn't detect the CPU topology, cpusets won't be applied: {}", e);
+            CpuLayout::default()
+        });
+
         Self {
-            heirachy: custom_v2("/sys/fs/cgroup/unified/tinydm"),
+            allowlist,
+            cpus: Mutex::new(cpus),
+            frozen: Mutex::new(FrozenSet::None),
+            mem_total,
+            io_devices: Mutex::new(HashMap::new()),
+            missing_knobs: Mutex::new(HashSet::new()),
+            failed_knobs: Mutex::new(HashSet::new()),
+            path: PathBuf::from(ROOT_PATH),
+            heirachy: custom_v2(ROOT_PATH),
             root: CgroupBuilder::new(&"tinydm")
-                .set_specified_controllers(vec!["cpuset".into(), "pids".into(), "freezer".into()])
+                .set_specified_controllers(vec!["cpuset".into(), "cpu".into(), "pids".into(), "freezer".into(), "memory".into(), "io".into()])
                 .build(custom_v2("/sys/fs/cgroup/unified")).unwrap(),
         }
     }
@@ -81,22 +155,575 @@ impl CGHandler {
         }
     }
 
-    pub fn freeze_all(&self, active: bool) -> Result<()> {
-        info!("Freezing all user processes");
-        let freezer: &FreezerController = match self.root.controller_of() {
-            Some(freezer) => freezer,
-            None => bail!("Failed to get root freezer controller"),
+    /// Find the cgroup a process is in relative to our root,
+    /// returns None if it isn't one of ours.
+    pub fn cgroup_of_pid(pid: u32) -> Result<Option<String>> {
+        let content = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+        // cgroup v2 only has the one "0::" hierarchy
+        Ok(content
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .and_then(|path| path.strip_prefix("/tinydm/"))
+            .map(|path| path.to_string()))
+    }
+
+    /// Read a key from cgroup.events, name is relative to our
+    /// root, use "" for the root itself.
+    fn read_event(&self, name: &str, key: &str) -> Result<u64> {
+        self.read_keyed(name, "cgroup.events", key)
+    }
+
+    /// Read a value from a flat keyed file like cgroup.events or cpu.stat
+    fn read_keyed(&self, name: &str, file: &str, key: &str) -> Result<u64> {
+        let path = self.path.join(name).join(file);
+        let content = std::fs::read_to_string(&path)?;
+        content
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.trim().parse::<u64>())
+            .ok_or_else(|| anyhow!("No '{}' in {}", key, path.display()))?
+            .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Thread IDs of every task in the cgroup
+    pub fn threads(&self, name: &str) -> Result<Vec<u32>> {
+        let content = std::fs::read_to_string(self.path.join(name).join("cgroup.threads"))?;
+        Ok(content.lines().filter_map(|l| l.trim().parse::<u32>().ok()).collect())
+    }
+
+    /// Process IDs of every process in the cgroup
+    pub fn procs(&self, name: &str) -> Result<Vec<u32>> {
+        let content = std::fs::read_to_string(self.path.join(name).join("cgroup.procs"))?;
+        Ok(content.lines().filter_map(|l| l.trim().parse::<u32>().ok()).collect())
+    }
+
+    /// Whether there are any processes left in the cgroup
+    pub fn populated(&self, name: &str) -> Result<bool> {
+        Ok(self.read_event(name, "populated")? == 1)
+    }
+
+    /// Where a cgroup lives in the filesystem, name is relative
+    /// to our root, use "" for the root itself.
+    pub fn cgroup_path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Set oom_score_adj for every process in the cgroup, children
+    /// inherit it when they fork. Returns how many processes had a
+    /// different value.
+    pub fn set_oom_score_adj(&self, name: &str, value: i16) -> Result<usize> {
+        let mut changed = 0;
+        for pid in self.procs(name)? {
+            let path = format!("/proc/{}/oom_score_adj", pid);
+            // The process may have exited since we listed it
+            let current = match std::fs::read_to_string(&path) {
+                Ok(current) => current,
+                Err(_) => continue,
+            };
+            if current.trim().parse::<i16>().ok() == Some(value) {
+                continue;
+            }
+            match std::fs::write(&path, value.to_string()) {
+                Ok(_) => changed += 1,
+                Err(e) => warn!("Failed to write {}: {}", path, e),
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Read a key from cpu.stat, these are in microseconds
+    pub fn cpu_stat(&self, name: &str, key: &str) -> Result<u64> {
+        self.read_keyed(name, "cpu.stat", key)
+    }
+
+    /// The CPUs the cgroup's processes may run on
+    pub fn effective_cpus(&self, name: &str) -> Result<Vec<u32>> {
+        parse_cpu_list(&std::fs::read_to_string(self.path.join(name).join("cpuset.cpus.effective"))?)
+    }
+
+    /// Bytes read and written by the cgroup across all block devices
+    pub fn io_bytes(&self, name: &str) -> Result<u64> {
+        let content = std::fs::read_to_string(self.path.join(name).join("io.stat"))?;
+        // "MAJ:MIN rbytes=1 wbytes=2 rios=3 wios=4 dbytes=5 dios=6"
+        Ok(content
+            .split_whitespace()
+            .filter_map(|field| field.strip_prefix("rbytes=").or_else(|| field.strip_prefix("wbytes=")))
+            .filter_map(|v| v.parse::<u64>().ok())
+            .sum())
+    }
+
+    /// Read a knob that holds a single number
+    fn read_u64(&self, name: &str, file: &str) -> Result<u64> {
+        let path = self.path.join(name).join(file);
+        let content = std::fs::read_to_string(&path)?;
+        content
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Current memory usage of the cgroup in bytes
+    pub fn memory_current(&self, name: &str) -> Result<u64> {
+        self.read_u64(name, "memory.current")
+    }
+
+    /// Highest memory usage since the cgroup was created, needs 5.19+
+    pub fn memory_peak(&self, name: &str) -> Result<u64> {
+        self.read_u64(name, "memory.peak")
+    }
+
+    /// Ask the kernel to reclaim up to bytes from the cgroup. Returns
+    /// false if it couldn't reclaim the full amount.
+    pub fn reclaim(&self, name: &str, bytes: u64) -> Result<bool> {
+        let path = self.path.join(name).join("memory.reclaim");
+        match std::fs::write(&path, bytes.to_string()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(nix::libc::EAGAIN) => Ok(false),
+            Err(e) => Err(anyhow!("Failed to write {}: {}", path.display(), e)),
+        }
+    }
+
+    /// SIGKILL every process in the cgroup, this works even if it's frozen
+    pub fn kill(&self, name: &str) -> Result<()> {
+        // cgroup.kill needs Linux 5.14
+        if self.write_knob(name, "cgroup.kill", "1").is_ok() {
+            return Ok(());
+        }
+
+        self.signal(name, Signal::SIGKILL)
+    }
+
+    /// Whether every task in the cgroup has actually been frozen,
+    /// writing to cgroup.freeze only starts the process.
+    pub fn is_frozen(&self, name: &str) -> Result<bool> {
+        Ok(self.read_event(name, "frozen")? == 1)
+    }
+
+    /// App cgroups are named "<app_id>-<pid>"
+    pub fn app_id_of(cgroup: &str) -> String {
+        let name = cgroup.rsplit('/').next().unwrap_or(cgroup);
+        match name.rsplit_once('-') {
+            Some((app_id, pid)) if pid.parse::<u64>().is_ok() => app_id.to_string(),
+            _ => name.to_string(),
+        }
+    }
+
+    /// If the cgroup is allowlisted returns the reason why
+    fn allowed(&self, name: &str) -> Option<&str> {
+        self.allowlist
+            .iter()
+            .find(|entry| {
+                entry.cgroup.as_ref().map_or(false, |p| Pattern::new(p).map_or(false, |p| p.matches(name)))
+                    || entry.app_id.as_ref().map_or(false, |id| *id == Self::app_id_of(name))
+            })
+            .map(|entry| entry.reason.as_str())
+    }
+
+    fn children(&self, name: &str) -> Result<Vec<String>> {
+        let mut children = Vec::new();
+        for entry in std::fs::read_dir(self.path.join(name))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let child = entry.file_name().to_string_lossy().to_string();
+                children.push(match name {
+                    "" => child,
+                    _ => format!("{}/{}", name, child),
+                });
+            }
+        }
+        Ok(children)
+    }
+
+    fn contains_allowed(&self, name: &str) -> Result<bool> {
+        for child in self.children(name)? {
+            if self.allowed(&child).is_some() || self.contains_allowed(&child)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn write_knob(&self, name: &str, knob: &str, value: &str) -> Result<()> {
+        let path = self.path.join(name).join(knob);
+        std::fs::write(&path, value)
+            .map_err(|e| anyhow!("Failed to write '{}' to {}: {}", value, path.display(), e))
+    }
+
+    /// Write a knob that depends on the kernel config, these get written
+    /// for every app on every rule change so only warn once. A knob the
+    /// kernel doesn't have is skipped from then on.
+    fn write_optional_knob(&self, name: &str, knob: &str, value: &str) {
+        if self.missing_knobs.lock().contains(knob) {
+            return;
+        }
+        let dir = self.path.join(name);
+        let e = match std::fs::write(dir.join(knob), value) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        // The app might have just exited and taken its cgroup with it
+        if e.kind() == ErrorKind::NotFound && dir.is_dir() {
+            warn!("{} isn't supported by the kernel, not setting it", knob);
+            self.missing_knobs.lock().insert(knob.to_string());
+        } else if self.failed_knobs.lock().insert(knob.to_string()) {
+            warn!("Failed to write '{}' to {}/{}: {} (further failures are logged at debug level)",
+                value, name, knob, e);
+        } else {
+            debug!("Failed to write '{}' to {}/{}: {}", value, name, knob, e);
+        }
+    }
+
+    /// Find the disk a block device config refers to. The io
+    /// controller only deals with whole disks, not partitions.
+    fn block_device(spec: &str) -> Result<String> {
+        let devnum = if spec.starts_with('/') {
+            let meta = std::fs::metadata(spec)?;
+            // A device node or something on a mounted filesystem
+            let dev = if meta.file_type().is_block_device() { meta.rdev() } else { meta.dev() };
+            format!("{}:{}", nix::sys::stat::major(dev), nix::sys::stat::minor(dev))
+        } else {
+            spec.to_string()
+        };
+
+        let sysfs = Path::new("/sys/dev/block").join(&devnum);
+        if !sysfs.exists() {
+            bail!("{} ({}) isn't a block device, is it on overlayfs or btrfs?", spec, devnum);
+        }
+        if sysfs.join("partition").exists() {
+            let disk = std::fs::read_to_string(sysfs.join("../dev"))?;
+            return Ok(disk.trim().to_string());
+        }
+        Ok(devnum)
+    }
+
+    /// Resolve the block devices used by the io config of every rule,
+    /// done at startup and on reload since mounts don't tend to move
+    /// around. Devices already resolved are kept.
+    pub fn resolve_io_devices<'a>(&self, configs: impl Iterator<Item = &'a CgroupConfig>) -> Result<()> {
+        let devices = self.lookup_io_devices(configs)?;
+        self.add_io_devices(devices);
+        Ok(())
+    }
+
+    /// Like resolve_io_devices() but only returns the devices that
+    /// aren't resolved yet, for add_io_devices() once everything else
+    /// has been checked
+    pub fn lookup_io_devices<'a>(
+        &self,
+        configs: impl Iterator<Item = &'a CgroupConfig>,
+    ) -> Result<HashMap<String, String>> {
+        let mut devices = HashMap::new();
+        for io in configs.filter_map(|c| c.io.as_ref()) {
+            for dev in &io.devices {
+                if devices.contains_key(&dev.device) || self.io_devices.lock().contains_key(&dev.device) {
+                    continue;
+                }
+                let disk = match Self::block_device(&dev.device) {
+                    Ok(disk) => disk,
+                    Err(e) => bail!("Couldn't find the block device for '{}': {}", dev.device, e),
+                };
+                devices.insert(dev.device.clone(), disk);
+            }
+        }
+
+        Ok(devices)
+    }
+
+    pub fn add_io_devices(&self, devices: HashMap<String, String>) {
+        for (device, disk) in devices {
+            info!("I/O limits for '{}' apply to block device {}", device, disk);
+            self.io_devices.lock().insert(device, disk);
+        }
+    }
+
+    fn apply_io(&self, name: &str, io: Option<&IoConfig>) {
+        let weight = io.and_then(|io| io.weight).unwrap_or(100);
+        self.write_optional_knob(name, "io.weight", &format!("default {}", weight));
+
+        // Every device mentioned by any rule, so that limits
+        // from the last rule are reset
+        let io_devices = self.io_devices.lock();
+        let mut disks: Vec<&String> = io_devices.values().collect();
+        disks.sort();
+        disks.dedup();
+        for disk in disks {
+            let dev = io.and_then(|io| {
+                io.devices.iter().find(|d| io_devices.get(&d.device) == Some(disk))
+            });
+            let size = |s: Option<MemorySize>| s.map_or("max".into(), |s| s.resolve(self.mem_total));
+            let iops = |i: Option<u64>| i.map_or("max".into(), |i| i.to_string());
+            let max = format!(
+                "{} rbps={} wbps={} riops={} wiops={}",
+                disk,
+                size(dev.and_then(|d| d.rbps)),
+                size(dev.and_then(|d| d.wbps)),
+                iops(dev.and_then(|d| d.riops)),
+                iops(dev.and_then(|d| d.wiops)),
+            );
+            let latency = match dev.and_then(|d| d.latency_ms) {
+                Some(ms) => format!("{} target={}", disk, ms as u64 * 1000),
+                None => format!("{} target=max", disk),
+            };
+
+            for (knob, value) in [("io.max", max), ("io.latency", latency)] {
+                self.write_optional_knob(name, knob, &value);
+            }
+        }
+    }
+
+    /// Apply the limits from a rule to an app cgroup. Limits the rule
+    /// doesn't set are reset so nothing leaks over from the last rule.
+    pub fn apply_config(&self, name: &str, config: &CgroupConfig) -> Result<()> {
+        let knobs = [
+            ("memory.low", config.memory_low, "0"),
+            ("memory.high", config.memory_high, "max"),
+            ("memory.max", config.memory_max, "max"),
+            ("memory.swap.max", config.memory_swap_max, "max"),
+        ];
+
+        for (knob, size, reset) in knobs {
+            let value = match size {
+                Some(MemorySize::Fraction(_)) if self.mem_total == 0 => {
+                    warn!("{}: can't set {} without knowing the total RAM", name, knob);
+                    continue;
+                }
+                Some(size) => size.resolve(self.mem_total),
+                None => reset.to_string(),
+            };
+            // memory.swap.max doesn't exist without swap support
+            self.write_optional_knob(name, knob, &value);
+        }
+
+        self.apply_io(name, config.io.as_ref());
+        self.apply_cpuset(name, &config.cpuset);
+        self.set_cpu_weight(name, config.cpu_weight());
+        self.set_uclamp(name, config.uclamp_min, config.uclamp_max);
+
+        Ok(())
+    }
+
+    /// The online CPUs in a cpuset, or all of them if none of its
+    /// CPUs are online. None if we don't know the topology.
+    pub fn resolve_cpus(&self, cpuset: &CpuSet) -> Option<Vec<u32>> {
+        let layout = self.cpus.lock();
+        if layout.possible.is_empty() {
+            return None;
+        }
+        let cpus: Vec<u32> = cpuset
+            .resolve(&layout)
+            .into_iter()
+            .filter(|cpu| layout.online.contains(cpu))
+            .collect();
+        Some(match cpus.is_empty() {
+            true => layout.online.clone(),
+            false => cpus,
+        })
+    }
+
+    pub fn set_cpus(&self, name: &str, cpus: &[u32]) {
+        if let Err(e) = self.write_knob(name, "cpuset.cpus", &format_cpu_list(cpus)) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Restrict a cgroup to the CPUs in a cpuset
+    pub fn apply_cpuset(&self, name: &str, cpuset: &CpuSet) {
+        if let Some(cpus) = self.resolve_cpus(cpuset) {
+            self.set_cpus(name, &cpus);
+        }
+    }
+
+    /// 1-10000, 100 is the default
+    pub fn set_cpu_weight(&self, name: &str, weight: u64) {
+        if let Err(e) = self.write_knob(name, "cpu.weight", &weight.clamp(1, 10000).to_string()) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Set the cgroup's utilisation clamps, None resets them. The
+    /// knobs only exist if the kernel has CONFIG_UCLAMP_TASK_GROUP.
+    pub fn set_uclamp(&self, name: &str, min: Option<f32>, max: Option<f32>) {
+        let knobs = [
+            ("cpu.uclamp.min", min.map_or("0".into(), |v| format!("{:.2}", v))),
+            ("cpu.uclamp.max", max.map_or("max".into(), |v| format!("{:.2}", v))),
+        ];
+        for (knob, value) in knobs {
+            if let Err(e) = self.write_knob(name, knob, &value) {
+                debug!("{}", e);
+            }
+        }
+    }
+
+    /// Check whether CPUs were hotplugged, returns true if cpusets
+    /// need to be applied again
+    pub fn refresh_cpus(&self) -> bool {
+        let mut layout = self.cpus.lock();
+        if layout.possible.is_empty() {
+            return false;
+        }
+        match layout.refresh_online() {
+            Ok(true) => {
+                info!("Online CPUs changed to {}", format_cpu_list(&layout.online));
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                debug!("Couldn't read the online CPUs: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn set_frozen(&self, name: &str, frozen: bool) -> Result<()> {
+        let path = self.path.join(name).join("cgroup.freeze");
+        std::fs::write(&path, if frozen { "1" } else { "0" })
+            .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    fn signal(&self, name: &str, signal: Signal) -> Result<()> {
+        for pid in self.procs(name)? {
+            // The process may have exited since we listed it
+            let _ = nix::sys::signal::kill(Pid::from_raw(pid as i32), signal);
+        }
+        Ok(())
+    }
+
+    /// Put an app cgroup into a freeze mode, undoing whatever prev
+    /// did. The freezer and cpu.max are always written so that an
+    /// app frozen by something else ends up in the right state.
+    pub fn set_freeze_mode(&self, name: &str, mode: FreezeMode, prev: FreezeMode, throttle: &ThrottleConfig) -> Result<()> {
+        let cpu_max = match mode {
+            FreezeMode::Throttle => format!("{} {}", throttle.quota_ms * 1000, throttle.period_ms * 1000),
+            _ => "max".into(),
         };
+        // The cpu controller might not be available, don't let
+        // that get in the way of freezing
+        if let Err(e) = self.write_knob(name, "cpu.max", &cpu_max) {
+            match mode {
+                FreezeMode::Throttle => bail!("{}", e),
+                _ => debug!("{}", e),
+            }
+        }
+
+        self.set_frozen(name, mode == FreezeMode::Freeze)?;
+
+        match (prev == FreezeMode::Sigstop, mode == FreezeMode::Sigstop) {
+            (false, true) => self.signal(name, Signal::SIGSTOP),
+            (true, false) => self.signal(name, Signal::SIGCONT),
+            _ => Ok(()),
+        }
+    }
+
+    /// Freeze every cgroup under name except allowlisted ones.
+    /// cgroup v2 can't freeze a parent without freezing its children
+    /// so we only freeze whole subtrees that have nothing allowlisted.
+    fn freeze_tree(&self, name: &str, report: &mut FreezeReport) -> Result<()> {
+        if !self.contains_allowed(name)? {
+            self.set_frozen(name, true)?;
+            report.frozen.push(name.to_string());
+            return Ok(());
+        }
+
+        for child in self.children(name)? {
+            match self.allowed(&child) {
+                Some(reason) => report.skipped.push((child, reason.to_string())),
+                None => self.freeze_tree(&child, report)?,
+            }
+        }
+
+        let procs = std::fs::read_to_string(self.path.join(name).join("cgroup.procs"))?;
+        let procs = procs.lines().count();
+        if procs > 0 {
+            let name = if name.is_empty() { "<root>" } else { name };
+            report.skipped.push((name.to_string(), format!("{} processes share a cgroup with allowlisted children", procs)));
+        }
+
+        Ok(())
+    }
 
+    pub fn freeze_all(&self, active: bool) -> Result<()> {
         if active {
-            freezer.freeze()?;
+            let report = self.freeze_except()?;
+            info!("Froze all user processes, {}", report);
         } else {
-            freezer.thaw()?;
+            self.thaw_except(&[])?;
         }
 
         Ok(())
     }
 
+    /// Freeze everything that isn't allowlisted
+    pub fn freeze_except(&self) -> Result<FreezeReport> {
+        let mut report = FreezeReport::default();
+        if !self.contains_allowed("")? {
+            let freezer: &FreezerController = match self.root.controller_of() {
+                Some(freezer) => freezer,
+                None => bail!("Failed to get root freezer controller"),
+            };
+            freezer.freeze()?;
+            *self.frozen.lock() = FrozenSet::Root;
+            report.frozen.push("<root>".into());
+            return Ok(report);
+        }
+
+        let res = self.freeze_tree("", &mut report);
+        // Record what we did manage to freeze so it gets thawed again
+        *self.frozen.lock() = FrozenSet::Cgroups(report.frozen.clone());
+        res.map(|_| report)
+    }
+
+    /// Undo freeze_except(), leaving the cgroups in keep frozen
+    pub fn thaw_except(&self, keep: &[String]) -> Result<()> {
+        info!("Thawing all user processes");
+        match std::mem::take(&mut *self.frozen.lock()) {
+            FrozenSet::Cgroups(cgroups) => {
+                for cgroup in cgroups.iter().filter(|cg| !keep.contains(cg)) {
+                    if let Err(e) = self.set_frozen(cgroup, false) {
+                        warn!("{}", e);
+                    }
+                }
+            }
+            FrozenSet::Root | FrozenSet::None => {
+                let freezer: &FreezerController = match self.root.controller_of() {
+                    Some(freezer) => freezer,
+                    None => bail!("Failed to get root freezer controller"),
+                };
+                freezer.thaw()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// cgroup.events of everything frozen by freeze_except(), the
+    /// kernel signals a modification when "frozen" changes
+    pub fn frozen_events(&self) -> Vec<PathBuf> {
+        match &*self.frozen.lock() {
+            FrozenSet::None => Vec::new(),
+            FrozenSet::Root => vec![self.path.join("cgroup.events")],
+            FrozenSet::Cgroups(cgroups) => cgroups.iter().map(|c| self.path.join(c).join("cgroup.events")).collect(),
+        }
+    }
+
+    /// Whether everything frozen by freeze_except() has settled
+    pub fn all_frozen(&self) -> Result<bool> {
+        match &*self.frozen.lock() {
+            FrozenSet::None => Ok(true),
+            FrozenSet::Root => self.is_frozen(""),
+            FrozenSet::Cgroups(cgroups) => {
+                for cgroup in cgroups {
+                    if !self.is_frozen(cgroup)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
     // #[cfg(target_arch = "x86_64")]
     // pub fn new_cgroup(
     //     &self,
@@ -110,6 +737,6 @@ impl CGHandler {
 
 impl Default for CGHandler {
     fn default() -> Self {
-        Self::new()
+        Self::new(Vec::new())
     }
 }
@@ -21,14 +21,23 @@
 
 use crate::{
     application::App,
+    hal::{format_cpu_list, parse_cpu_list, CpuLayout, MemInfo},
     match_rules::{MatchConditions, MatchRule},
 };
 use anyhow::Result;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{ArrayValidation, InstanceType, Metadata, Schema, SchemaObject, StringValidation};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use strum_macros::Display;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use strum_macros::{Display, EnumString};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone, Display)]
+pub mod layers;
+pub mod lint;
+
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Copy, Clone, Display, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub enum Rule {
     Foreground,
@@ -38,7 +47,21 @@ pub enum Rule {
     Media,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Copy, Clone, Display)]
+impl Rule {
+    /// Lower is more important to the user, used to decide
+    /// which apps get resources first (or lose them last)
+    pub fn priority(&self) -> u32 {
+        match self {
+            Rule::Foreground => 0,
+            Rule::Media => 1,
+            Rule::Recents => 2,
+            Rule::Background => 3,
+            Rule::Snooze => 4,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Copy, Clone, Display, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub enum Event {
     LowBattery,
@@ -48,9 +71,11 @@ pub enum Event {
     Wake,
     NetworkRestriction,
     Touch,
+    /// The device went up a thermal level
+    Hot,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Copy, Clone, Display)]
+#[derive(Debug, PartialEq, Deserialize, Copy, Clone, Display, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub enum Tag {
     PlayingMedia,
@@ -60,31 +85,107 @@ pub enum Tag {
     WasFocused,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+impl Event {
+    /// The events hammockd raises, the others can be written in a
+    /// config but nothing raises them yet. Keep in sync with the
+    /// evaluate_rules() calls in hammock.rs.
+    pub const RAISED: [Event; 3] = [Event::Sleep, Event::LowBattery, Event::Hot];
+}
+
+impl Tag {
+    /// The tags hammockd sets, see busy.rs
+    pub const SET: [Tag; 1] = [Tag::Busy];
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 enum EventConfig {
     LowBattery { threshold: u32 },
-    WorkReady { time_period: Option<u32> },
-    Idle,
-    Sleep { max_time: Option<u32> },
+    WorkReady {
+        /// "HH:MM-HH:MM", when the user is usually asleep
+        #[serde(rename(deserialize = "time-period"))]
+        time_period: Option<String>,
+        /// Longest an app can work for (seconds)
+        #[serde(rename(deserialize = "work-timeout"))]
+        work_timeout: Option<u32>,
+    },
+    // rename_all only renames the variants, their fields are done
+    // one by one
+    Idle {
+        #[serde(rename(deserialize = "enter-time"))]
+        enter_time: Option<u32>,
+    },
+    Sleep {
+        #[serde(rename(deserialize = "max-time"))]
+        max_time: Option<u32>,
+    },
     Wake,
     NetworkRestriction,
-    Touch,
+    Touch {
+        /// How long the boost lasts after the user lets go (seconds)
+        timeout: Option<f32>,
+        config: Option<TouchBoostConfig>,
+    },
+    Hot,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+struct TouchBoostConfig {
+    /// Only boost the big cores, leave the LITTLE ones be
+    #[serde(default)]
+    only_big: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 enum TagConfigInner {
     PlayingMedia,
     HammockAware,
     WorkPending,
-    Busy { timeout: u32 },
+    Busy(BusyConfig),
     WasFocused,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+/// When to consider an app busy, it has to be over either
+/// threshold for "sustain" seconds to get the tag
+#[derive(Debug, PartialEq, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct BusyConfig {
+    /// Longest the tag is kept for (seconds), after which the app
+    /// has to go quiet before it can be tagged again
+    pub timeout: u32,
+    /// Percent of a single CPU
+    pub cpu_percent: f32,
+    /// Reads and writes combined
+    pub io_kbps: u32,
+    pub sustain: u32,
+    /// Seconds between samples
+    pub interval: u32,
+}
+
+impl Default for BusyConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 300,
+            cpu_percent: 20.0,
+            io_kbps: 256,
+            sustain: 10,
+            interval: 2,
+        }
+    }
+}
+
+// Can't deny unknown fields because of the flatten, TagConfigInner
+// does instead
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 struct TagConfig {
     apply_latency: Option<f32>,
@@ -93,8 +194,7 @@ struct TagConfig {
     inner: TagConfigInner,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Copy, Clone)]
-#[serde(rename_all(deserialize = "kebab-case"))]
+#[derive(Debug, PartialEq, Copy, Clone)]
 enum Atom {
     Rule(Rule),
     Event(Event),
@@ -108,9 +208,8 @@ enum Atom {
 // that triggered this check, or a tag that is
 // checked against the current application.
 #[derive(Debug, Deserialize, PartialEq, Default, Clone)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(try_from = "ConditionalRaw")]
 pub struct Conditional {
-    #[serde(flatten)]
     atom: Option<Atom>,
 
     not: Option<Box<Conditional>>,
@@ -119,145 +218,1412 @@ pub struct Conditional {
     one_of: Option<Vec<Conditional>>,
 }
 
+// The atom is spelt out rather than flattened so that unknown keys
+// (e.g. a misspelt "anyof") are rejected rather than ignored
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(deny_unknown_fields)]
+struct ConditionalRaw {
+    rule: Option<Rule>,
+    event: Option<Event>,
+    tag: Option<Tag>,
+
+    not: Option<Box<Conditional>>,
+    any_of: Option<Vec<Conditional>>,
+    all_of: Option<Vec<Conditional>>,
+    one_of: Option<Vec<Conditional>>,
+}
+
+impl TryFrom<ConditionalRaw> for Conditional {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: ConditionalRaw) -> Result<Self> {
+        Ok(Self {
+            atom: Atom::from_parts(raw.rule, raw.event, raw.tag)?,
+            not: raw.not,
+            any_of: raw.any_of,
+            all_of: raw.all_of,
+            one_of: raw.one_of,
+        })
+    }
+}
+
+impl JsonSchema for Conditional {
+    fn schema_name() -> String {
+        "Conditional".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        ConditionalRaw::json_schema(gen)
+    }
+}
+
 // match-rules.enter-time.from array
 #[derive(Debug, PartialEq, Deserialize, Clone)]
-#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(try_from = "EnterTimeFromRaw")]
 struct EnterTimeFrom {
-    #[serde(flatten)]
     atom: Atom,
     time: f32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+struct EnterTimeFromRaw {
+    rule: Option<Rule>,
+    event: Option<Event>,
+    tag: Option<Tag>,
+    time: f32,
+}
+
+impl TryFrom<EnterTimeFromRaw> for EnterTimeFrom {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: EnterTimeFromRaw) -> Result<Self> {
+        match Atom::from_parts(raw.rule, raw.event, raw.tag)? {
+            Some(atom) => Ok(Self { atom, time: raw.time }),
+            None => bail!("enter-time from needs a rule, event or tag"),
+        }
+    }
+}
+
+impl JsonSchema for EnterTimeFrom {
+    fn schema_name() -> String {
+        "EnterTimeFrom".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        EnterTimeFromRaw::json_schema(gen)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     description: String,
-    cores: u32,
+    /// How many CPUs the config was written for, not needed now
+    /// that cpusets can refer to the topology
+    cores: Option<u32>,
+    /// [recommended, minimum] RAM in GB, 0 for no minimum
     memory: [u32; 2],
+    /// Values used in several places, referred to elsewhere as
+    /// "$name" or "$group.name"
+    #[schemars(schema_with = "any_yaml")]
+    tunables: Option<serde_yaml::Value>,
     match_rules: Vec<MatchRuleConfig>,
     events: Option<Vec<EventConfig>>,
     tags: Option<Vec<TagConfig>>,
+    autosleep: Option<AutosleepConfig>,
+    keep_awake: Option<KeepAwakeConfig>,
+    suspend: Option<SuspendConfig>,
+    freeze_all: Option<FreezeAllConfig>,
+    freezer: Option<FreezerConfig>,
+    low_memory: Option<LowMemoryConfig>,
+    reclaim: Option<ReclaimConfig>,
+    network_policy: Option<NetworkPolicyConfig>,
+    thermal: Option<ThermalConfig>,
+    power_profiles: Option<PowerProfilesConfig>,
+    /// The config as written, power profiles are merged onto its
+    /// match-rules and reloads are diffed against it
+    #[serde(skip)]
+    raw: serde_yaml::Value,
+    /// The base config file
+    #[serde(skip)]
+    path: PathBuf,
+    /// The config given on the command line, reloads start from it too
+    #[serde(skip)]
+    requested: Option<PathBuf>,
+    /// Every file merged into the config
+    #[serde(skip)]
+    sources: Vec<PathBuf>,
+    /// Value path -> the file it came from
+    #[serde(skip)]
+    provenance: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 pub struct RuleEnterTime {
     default: u32,
     from: Option<Vec<EnterTimeFrom>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 struct MatchRuleConfig {
     name: Rule,
     only_from: Option<Conditional>,
     never_from: Option<Conditional>,
     cgroup: CgroupConfig,
     enter_time: RuleEnterTime,
+    /// Applied to every process in the app, -1000 (never OOM
+    /// kill) to 1000 (kill first)
+    oom_score_adj: Option<i16>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
 pub struct CgroupConfig {
-    pub cpuset: String,
+    pub cpuset: CpuSet,
     pub cpushares: Option<u64>,
+    pub memory_high: Option<MemorySize>,
+    pub memory_max: Option<MemorySize>,
+    pub memory_swap_max: Option<MemorySize>,
+    pub memory_low: Option<MemorySize>,
+    pub io: Option<IoConfig>,
+    pub network: Option<NetworkConfig>,
+    /// How apps are stopped while in this rule, everything but the
+    /// foreground is frozen if not set
+    pub freeze_mode: Option<FreezeMode>,
+    pub throttle: Option<ThrottleConfig>,
+    pub cpu_budget: Option<CpuBudgetConfig>,
+    /// Utilisation clamps in percent (cpu.uclamp.min/max), hints to
+    /// the scheduler about how fast the CPUs should run for the app
+    pub uclamp_min: Option<f32>,
+    pub uclamp_max: Option<f32>,
+    pub activation_boost: Option<BoostConfig>,
+}
+
+impl CgroupConfig {
+    /// cpu.weight for cpushares, converted like the kernel does for
+    /// cgroup v1 shares (1024 -> 100)
+    pub fn cpu_weight(&self) -> u64 {
+        match self.cpushares {
+            Some(shares) => 1 + (shares.clamp(2, 262144) - 2) * 9999 / 262142,
+            None => 100,
+        }
+    }
+}
+
+/// A token bucket of CPU time, apps can use up to seconds of CPU
+/// which is refilled at a rate of seconds per "per" seconds
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct CpuBudgetConfig {
+    pub seconds: f32,
+    pub per: u32,
+    /// What to do once the budget runs out
+    #[serde(default = "CpuBudgetConfig::default_action")]
+    pub action: FreezeMode,
+    /// Let the app run again once this fraction of the budget
+    /// has been refilled
+    #[serde(default = "CpuBudgetConfig::default_resume_at")]
+    pub resume_at: f32,
+}
+
+impl CpuBudgetConfig {
+    fn default_action() -> FreezeMode {
+        FreezeMode::Throttle
+    }
+
+    fn default_resume_at() -> f32 {
+        0.1
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Display, Default, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[strum(serialize_all = "kebab-case")]
+pub enum FreezeMode {
+    /// Leave the app running
+    #[default]
+    Off,
+    /// The cgroup freezer
+    Freeze,
+    /// Starve the app with cpu.max, it can still answer pings
+    /// from the compositor and D-Bus, just slowly
+    Throttle,
+    /// SIGSTOP every process in the app
+    Sigstop,
+}
+
+/// The cpu.max quota for the throttle freeze mode, the app gets
+/// quota-ms of CPU time every period-ms
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub quota_ms: u32,
+    pub period_ms: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            quota_ms: 5,
+            period_ms: 1000,
+        }
+    }
+}
+
+/// A higher uclamp-min for the first few seconds after an app enters
+/// the rule, so switching to an app feels instant
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct BoostConfig {
+    pub uclamp_min: f32,
+    /// Seconds
+    pub duration: f32,
+}
+
+impl Default for BoostConfig {
+    fn default() -> Self {
+        Self {
+            uclamp_min: 80.0,
+            duration: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Cap in kbps, applies to both directions
+    pub bandwidth: Option<u32>,
+    /// Drop all traffic
+    #[serde(default)]
+    pub block: bool,
+    /// Only apply when the default route is over a metered network
+    #[serde(default)]
+    pub metered_only: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct IoConfig {
+    /// io.weight, 1-10000 where 100 is the default
+    pub weight: Option<u16>,
+    #[serde(default)]
+    pub devices: Vec<IoDeviceConfig>,
+}
+
+/// Limits for one block device, io.max and io.latency
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct IoDeviceConfig {
+    /// A mount point, device node or "MAJ:MIN"
+    pub device: String,
+    /// Bytes per second
+    pub rbps: Option<MemorySize>,
+    pub wbps: Option<MemorySize>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+    /// io.latency target, protects this cgroup from its siblings
+    pub latency_ms: Option<u32>,
+}
+
+/// A memory limit or rate, either an absolute size ("512M", 1073741824),
+/// a fraction of the detected RAM ("25%", 0.25) or "max".
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "MemorySizeRaw")]
+pub enum MemorySize {
+    Bytes(u64),
+    Fraction(f64),
+    Max,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum MemorySizeRaw {
+    Bytes(u64),
+    Fraction(f64),
+    Str(String),
+}
+
+impl TryFrom<MemorySizeRaw> for MemorySize {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: MemorySizeRaw) -> Result<Self> {
+        let size = match raw {
+            MemorySizeRaw::Bytes(b) => MemorySize::Bytes(b),
+            MemorySizeRaw::Fraction(f) => MemorySize::Fraction(f),
+            MemorySizeRaw::Str(s) => {
+                let s = s.trim();
+                if s == "max" {
+                    MemorySize::Max
+                } else if let Some(pct) = s.strip_suffix('%') {
+                    MemorySize::Fraction(pct.trim().parse::<f64>()? / 100.0)
+                } else {
+                    let (num, shift) = match s.chars().last() {
+                        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+                        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+                        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+                        Some('T') | Some('t') => (&s[..s.len() - 1], 40),
+                        _ => (s, 0),
+                    };
+                    // A plain shift would silently drop the high bits
+                    let bytes = num.trim().parse::<u64>()?.checked_mul(1 << shift);
+                    MemorySize::Bytes(bytes.ok_or_else(|| anyhow!("Memory size {} is too large", s))?)
+                }
+            }
+        };
+
+        match size {
+            MemorySize::Fraction(f) if !(0.0..=1.0).contains(&f) => {
+                bail!("Memory fraction {} must be between 0 and 1 (0-100%)", f)
+            }
+            size => Ok(size),
+        }
+    }
+}
+
+impl JsonSchema for MemorySize {
+    fn schema_name() -> String {
+        "MemorySize".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        MemorySizeRaw::json_schema(gen)
+    }
+}
+
+impl MemorySize {
+    /// The value to write to a cgroup memory.* file
+    pub fn resolve(&self, mem_total: u64) -> String {
+        match self {
+            MemorySize::Bytes(b) => b.to_string(),
+            MemorySize::Fraction(f) => ((mem_total as f64 * f) as u64).to_string(),
+            MemorySize::Max => "max".into(),
+        }
+    }
+}
+
+/// Part of the CPU topology a cpuset can refer to by name
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum CpuClass {
+    All,
+    /// The lowest capacity CPUs
+    Little,
+    /// Every CPU faster than the LITTLE ones
+    Big,
+    /// The single fastest CPU
+    TopBig,
+    AllButTopBig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuSetTerm {
+    Cpus(Vec<u32>),
+    Class(CpuClass),
+}
+
+/// The CPUs an app may run on, a comma separated mix of kernel CPU
+/// lists ("0-3,6") and names from CpuClass ("little,top-big"),
+/// resolved against the detected topology
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct CpuSet {
+    raw: String,
+    terms: Vec<CpuSetTerm>,
+}
+
+impl TryFrom<String> for CpuSet {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: String) -> Result<Self> {
+        let mut terms = Vec::new();
+        for term in raw.split(',').map(str::trim) {
+            if term.is_empty() {
+                bail!("Empty term in cpuset '{}'", raw);
+            }
+            let term = match term.parse::<CpuClass>() {
+                Ok(class) => CpuSetTerm::Class(class),
+                Err(_) => CpuSetTerm::Cpus(
+                    parse_cpu_list(term).map_err(|_| anyhow!("Invalid cpuset term '{}' in '{}'", term, raw))?,
+                ),
+            };
+            terms.push(term);
+        }
+
+        Ok(Self { raw, terms })
+    }
+}
+
+const CPUSET_PATTERN: &str =
+    r"^\s*(\d+(-\d+)?|all|little|big|top-big|all-but-top-big)(\s*,\s*(\d+(-\d+)?|all|little|big|top-big|all-but-top-big))*\s*$";
+
+impl JsonSchema for CpuSet {
+    fn schema_name() -> String {
+        "CpuSet".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(CPUSET_PATTERN.into()),
+                ..Default::default()
+            })),
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "CPU numbers and ranges or all, little, big, top-big and all-but-top-big separated by commas"
+                        .into(),
+                ),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl CpuSet {
+    /// Every CPU the set names, whether or not it's online
+    pub fn resolve(&self, layout: &CpuLayout) -> Vec<u32> {
+        let mut cpus = Vec::new();
+        for term in &self.terms {
+            match term {
+                CpuSetTerm::Cpus(list) => cpus.extend(list),
+                CpuSetTerm::Class(CpuClass::All) => cpus.extend(&layout.possible),
+                CpuSetTerm::Class(CpuClass::Little) => cpus.extend(layout.little()),
+                CpuSetTerm::Class(CpuClass::Big) => cpus.extend(layout.big()),
+                CpuSetTerm::Class(CpuClass::TopBig) => cpus.extend(layout.top_big()),
+                CpuSetTerm::Class(CpuClass::AllButTopBig) => {
+                    let top = layout.top_big();
+                    cpus.extend(layout.possible.iter().filter(|cpu| Some(**cpu) != top));
+                }
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        cpus
+    }
+
+    /// CPU numbers given explicitly, to check they exist
+    fn explicit(&self) -> impl Iterator<Item = &u32> {
+        self.terms.iter().flat_map(|term| match term {
+            CpuSetTerm::Cpus(list) => list.as_slice(),
+            CpuSetTerm::Class(_) => &[],
+        })
+    }
+}
+
+// Opportunistic suspend, all times are in seconds
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct AutosleepConfig {
+    pub enabled: bool,
+    /// How long the device must be idle with the screen off
+    /// before we try to suspend
+    pub idle_time: u32,
+    /// Stay awake for at least this long after resuming
+    pub min_awake: u32,
+    /// Delay before retrying after an aborted suspend, doubled
+    /// for each consecutive failure up to backoff-max
+    pub backoff_initial: u32,
+    pub backoff_max: u32,
+    /// How often to check the idle state and inhibitors
+    pub poll_interval: u32,
+}
+
+impl Default for AutosleepConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_time: 30,
+            min_awake: 5,
+            backoff_initial: 2,
+            backoff_max: 300,
+            poll_interval: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct KeepAwakeConfig {
+    /// Upper bound on how long a single hold can last (seconds),
+    /// also used when the client doesn't specify a timeout
+    pub max_timeout: u32,
+    /// How many holds a single D-Bus client can have at once
+    pub max_per_client: u32,
+}
+
+impl Default for KeepAwakeConfig {
+    fn default() -> Self {
+        Self {
+            max_timeout: 600,
+            max_per_client: 8,
+        }
+    }
+}
+
+// Suspend and resume sequences, all times are in seconds
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct SuspendConfig {
+    /// How long to wait for the compositor to power off the outputs
+    pub output_off_timeout: f32,
+    /// How long to wait for userspace to finish freezing
+    pub freeze_timeout: f32,
+    /// Delay between thawing each app on resume
+    pub thaw_stagger: f32,
+}
+
+impl Default for SuspendConfig {
+    fn default() -> Self {
+        Self {
+            output_off_timeout: 0.5,
+            freeze_timeout: 1.0,
+            thaw_stagger: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct FreezerConfig {
+    /// How long (seconds) to wait for a cgroup to report
+    /// that it's frozen before retrying
+    pub verify_timeout: f32,
+    /// How many times to retry before giving up on the app
+    pub retries: u32,
+}
+
+impl Default for FreezerConfig {
+    fn default() -> Self {
+        Self {
+            verify_timeout: 1.0,
+            retries: 2,
+        }
+    }
+}
+
+/// A cgroup that must keep running when all of userspace is frozen,
+/// matched either by a glob of its path relative to the hammock
+/// root or by app id.
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct FreezeAllowEntry {
+    pub cgroup: Option<String>,
+    pub app_id: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct FreezeAllConfig {
+    pub allowlist: Vec<FreezeAllowEntry>,
+}
+
+// Killing apps when memory pressure gets too high, before the
+// kernel OOM killer has to step in. Times are in seconds unless
+// the name says otherwise.
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct LowMemoryConfig {
+    pub enabled: bool,
+    /// PSI trigger, "some" or "full"
+    pub trigger: String,
+    /// Fire when tasks stall on memory for stall-ms within window-ms
+    pub stall_ms: u32,
+    pub window_ms: u32,
+    /// Apps are picked from these rules, first rule first
+    pub kill_order: Vec<Rule>,
+    /// Apps with any of these tags are never killed
+    pub protect_tags: Vec<Tag>,
+    /// App ids that are never killed
+    pub allowlist: Vec<String>,
+    /// How long an app gets to exit after SIGTERM before it's killed
+    pub grace_period: f32,
+    /// Give the kernel time to reclaim before killing anything else
+    pub cooldown: f32,
+}
+
+impl Default for LowMemoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trigger: "some".into(),
+            stall_ms: 150,
+            window_ms: 1000,
+            kill_order: vec![Rule::Snooze, Rule::Background, Rule::Recents],
+            protect_tags: vec![Tag::PlayingMedia, Tag::Busy],
+            allowlist: Vec::new(),
+            grace_period: 3.0,
+            cooldown: 2.0,
+        }
+    }
+}
+
+// Pushing the memory of snoozed apps out to swap/zram before
+// anything else needs it, times are in seconds
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct ReclaimConfig {
+    pub enabled: bool,
+    /// How long an app must be snoozed before we start
+    pub delay: u32,
+    /// Time between reclaiming chunks
+    pub interval: f32,
+    pub chunk_mb: u32,
+    /// Most we'll reclaim from an app each time it's snoozed, 0 for no limit
+    pub max_mb: u32,
+    /// Don't reclaim when on battery below this percentage,
+    /// swapping isn't free
+    pub min_battery: u32,
+}
+
+impl Default for ReclaimConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: 60,
+            interval: 1.0,
+            chunk_mb: 16,
+            max_mb: 0,
+            min_battery: 20,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct NetworkPolicyConfig {
+    pub enabled: bool,
+    /// Globs of interface names that are metered (e.g. "rmnet*")
+    pub metered_interfaces: Vec<String>,
+    /// How often to check which interface the default route is on (seconds)
+    pub check_interval: u32,
+}
+
+impl Default for NetworkPolicyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            metered_interfaces: vec!["rmnet*".into(), "wwan*".into()],
+            check_interval: 5,
+        }
+    }
+}
+
+/// Narrowing rules as the device heats up, each level applies once
+/// the temperature reaches it and until it drops hysteresis below
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct ThermalConfig {
+    pub enabled: bool,
+    /// Globs of thermal zone types to watch, the hottest one counts
+    pub zones: Vec<String>,
+    /// Seconds between reading the temperature
+    pub interval: f32,
+    /// Degrees C
+    pub hysteresis: f32,
+    /// The rules that get narrowed
+    pub rules: Vec<Rule>,
+    /// From coolest to hottest
+    pub levels: Vec<ThermalLevelConfig>,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            zones: vec!["cpu*".into()],
+            interval: 2.0,
+            hysteresis: 3.0,
+            rules: vec![Rule::Recents, Rule::Background],
+            levels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+pub struct ThermalLevelConfig {
+    /// Degrees C
+    pub temp: Option<f32>,
+    /// Or a trip point type of the watched zones ("passive"), the
+    /// lowest one found is used
+    pub trip: Option<String>,
+    /// Added to the trip point temperature
+    #[serde(default)]
+    pub offset: f32,
+    /// Apps are confined to the CPUs in both this and their rule's cpuset
+    pub cpuset: Option<CpuSet>,
+    /// Percent of the rule's CPU weight
+    pub weight_percent: Option<u32>,
+}
+
+/// Named overlays on top of the match rules and when to use them
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct PowerProfilesConfig {
+    /// power-profiles-daemon profile ("power-saver", "balanced",
+    /// "performance") -> the profile to use while it's active
+    pub daemon: HashMap<String, String>,
+    /// Profile to use while the battery is low, takes priority over
+    /// power-profiles-daemon
+    pub low_battery: Option<String>,
+    pub profiles: HashMap<String, PowerProfileConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct PowerProfileConfig {
+    /// Partial match rules, merged onto the rule with the same name
+    #[schemars(schema_with = "any_yaml_list")]
+    pub match_rules: Vec<serde_yaml::Value>,
+}
+
+/// Schema for values kept as YAML, anything goes
+fn any_yaml(_: &mut SchemaGenerator) -> Schema {
+    Schema::Bool(true)
+}
+
+fn any_yaml_list(gen: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(any_yaml(gen).into()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Describe the differences between two YAML values, mappings are
+/// compared key by key and anything else as a whole
+fn diff_yaml(path: &str, old: Option<&serde_yaml::Value>, new: Option<&serde_yaml::Value>, changes: &mut Vec<String>) {
+    let show = |v: Option<&serde_yaml::Value>| match v {
+        Some(v) => serde_yaml::to_string(v).map_or("?".into(), |s| s.trim_end().replace('\n', " ")),
+        None => "(unset)".into(),
+    };
+    match (old, new) {
+        (Some(serde_yaml::Value::Mapping(old)), Some(serde_yaml::Value::Mapping(new))) => {
+            for (key, value) in old {
+                let key_name = key.as_str().map_or_else(|| show(Some(key)), |k| k.to_string());
+                diff_yaml(&format!("{}.{}", path, key_name), Some(value), new.get(key), changes);
+            }
+            for (key, value) in new.iter().filter(|(k, _)| !old.contains_key(*k)) {
+                let key_name = key.as_str().map_or_else(|| show(Some(key)), |k| k.to_string());
+                diff_yaml(&format!("{}.{}", path, key_name), None, Some(value), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(format!("{}: {} -> {}", path, show(old), show(new))),
+        _ => {}
+    }
+}
+
+/// Merge a YAML mapping onto another, mappings are merged key by key
+/// and anything else replaces what was there
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: &serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+impl Atom {
+    /// At most one of rule, event or tag
+    fn from_parts(rule: Option<Rule>, event: Option<Event>, tag: Option<Tag>) -> Result<Option<Self>> {
+        match (rule, event, tag) {
+            (Some(rule), None, None) => Ok(Some(Atom::Rule(rule))),
+            (None, Some(event), None) => Ok(Some(Atom::Event(event))),
+            (None, None, Some(tag)) => Ok(Some(Atom::Tag(tag))),
+            (None, None, None) => Ok(None),
+            _ => bail!("Only one of rule, event or tag can be given"),
+        }
+    }
+
+    fn matches(&self, app: &App, event: Option<&Event>) -> bool {
+        match self {
+            Atom::Rule(r) => app.info.read().match_rule == *r,
+            Atom::Event(e) => event.map(|e2| e2 == e).unwrap_or(false),
+            Atom::Tag(t) => app.info.read().tags.contains(t),
+        }
+    }
+}
+
+impl RuleEnterTime {
+    /// How long (seconds) the app has to wait before the rule can
+    /// be applied, the first matching entry in "from" wins
+    pub fn for_app(&self, app: &App, event: Option<&Event>) -> f32 {
+        self.from
+            .iter()
+            .flatten()
+            .find(|from| from.atom.matches(app, event))
+            .map_or(self.default as f32, |from| from.time)
+    }
 }
 
 impl Conditional {
     pub fn evaluate(&self, app: &App, event: Option<&Event>) -> bool {
+        self.evaluate_with(&|atom| atom.matches(app, event))
+    }
+
+    /// Evaluate with matches() deciding each leaf, conditions that mix
+    /// more than one kind of node never match
+    fn evaluate_with(&self, matches: &dyn Fn(&Atom) -> bool) -> bool {
         match self {
             Conditional {
-                atom: Some(Atom::Rule(r)),
-                not: None,
-                any_of: None,
-                all_of: None,
-                one_of: None,
-            } => app.info.write().match_rule == *r,
-            Conditional {
-                atom: Some(Atom::Event(e)),
-                not: None,
-                any_of: None,
-                all_of: None,
-                one_of: None,
-            } => event.map(|e2| e2 == e).unwrap_or(false),
-            Conditional {
-                atom: Some(Atom::Tag(t)),
+                atom: Some(atom),
                 not: None,
                 any_of: None,
                 all_of: None,
                 one_of: None,
-            } => app.info.read().tags.contains(t),
+            } => matches(atom),
             Conditional {
                 not: Some(c),
                 atom: None,
                 any_of: None,
                 all_of: None,
                 one_of: None,
-            } => !c.evaluate(app, event),
+            } => !c.evaluate_with(matches),
             Conditional {
                 any_of: Some(cs),
                 not: None,
                 atom: None,
                 all_of: None,
                 one_of: None,
-            } => cs.iter().any(|c| c.evaluate(app, event)),
+            } => cs.iter().any(|c| c.evaluate_with(matches)),
             Conditional {
                 all_of: Some(cs),
                 not: None,
                 any_of: None,
                 atom: None,
                 one_of: None,
-            } => cs.iter().all(|c| c.evaluate(app, event)),
+            } => cs.iter().all(|c| c.evaluate_with(matches)),
             Conditional {
                 one_of: Some(cs),
                 not: None,
                 any_of: None,
                 all_of: None,
                 atom: None,
-            } => cs.iter().filter(|c| c.evaluate(app, event)).count() == 1,
+            } => cs.iter().filter(|c| c.evaluate_with(matches)).count() == 1,
             _ => false,
         }
     }
 }
 
 impl Config {
+    /// Load the config with all of its layers, see config/layers.rs
     pub fn load(path: Option<PathBuf>) -> Result<Self> {
-        let path = path.unwrap_or(PathBuf::from("docs/config.default.yaml"));
-        info!("Reading config: {}", path.display());
-        let config = match std::fs::read_to_string(path) {
-            Ok(config) => config,
-            Err(e) => {
-                error!("Failed to read config: {}", e);
-                return Err(e.into());
+        let loaded = Self::load_layers(path.clone(), true);
+        // The session user's overlay shouldn't be able to stop us
+        // starting, try again without it
+        let loaded = match loaded {
+            Err((e, true)) => match Self::load_layers(path, false) {
+                Ok(config) => {
+                    warn!("Ignoring the session user's config, the config doesn't load with it: {}", e);
+                    Ok(config)
+                }
+                Err((e, _)) => Err(e),
+            },
+            loaded => loaded.map_err(|(e, _)| e),
+        };
+        loaded.map_err(|e| {
+            error!("Failed to load config: {}", e);
+            e
+        })
+    }
+
+    /// Load the config, with the session user's overlay if user. The
+    /// error says whether the user's overlay was used.
+    fn load_layers(path: Option<PathBuf>, user: bool) -> std::result::Result<Self, (anyhow::Error, bool)> {
+        let layers = layers::load(path.as_deref(), user).map_err(|e| (e, user))?;
+        let with_user = layers.user;
+
+        // A config that's a single file as written is parsed straight
+        // from it so errors have its line and column. Otherwise parse
+        // the merged document and map errors back to the file they
+        // came from.
+        let single = match layers.sources.as_slice() {
+            [source] => std::fs::read_to_string(source)
+                .ok()
+                .filter(|text| serde_yaml::from_str::<serde_yaml::Value>(text).ok().as_ref() == Some(&layers.value))
+                .map(|text| (source.clone(), text)),
+            _ => None,
+        };
+        let parsed = match &single {
+            Some((source, text)) => {
+                serde_yaml::from_str::<Config>(text).map_err(|e| anyhow!("{}: {}", source.display(), e))
             }
+            None => serde_yaml::to_string(&layers.value)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| serde_yaml::from_str::<Config>(&text).map_err(|e| layers.explain::<Config>(&e))),
         };
+        let mut parsed = parsed.map_err(|e| (e, with_user))?;
+        parsed.raw = layers.value;
+        parsed.path = layers.base;
+        parsed.requested = path;
+        parsed.sources = layers.sources;
+        parsed.provenance = layers.provenance;
+        Ok(parsed)
+    }
+
+    /// The merged config as YAML, without parsing it
+    pub fn merged(path: Option<PathBuf>) -> Result<String> {
+        Ok(serde_yaml::to_string(&layers::load(path.as_deref(), true)?.value)?)
+    }
+
+    /// Every value in the config with the file it came from, as
+    /// (path, value, source)
+    pub fn show(&self) -> Vec<(String, String, String)> {
+        let mut values = Vec::new();
+        layers::walk(&self.raw, "", &mut |path, value| {
+            let value = match value {
+                serde_yaml::Value::String(s) => s.clone(),
+                value => serde_yaml::to_string(value)
+                    .map_or("?".into(), |s| s.trim_end().replace('\n', " ")),
+            };
+            let source = self.provenance.get(path).cloned().unwrap_or_default();
+            values.push((path.to_string(), value, source));
+        });
+        values
+    }
+
+    pub fn requested(&self) -> Option<PathBuf> {
+        self.requested.clone()
+    }
 
-        match serde_yaml::from_str(&config) {
-            Ok(config) => Ok(config),
-            Err(e) => {
-                error!("Failed to parse config: {}", e);
-                Err(e.into())
+    /// The files to watch for changes to the config
+    pub fn watch(&self) -> layers::Watch {
+        layers::Watch::new(&self.sources)
+    }
+
+    /// Everything that can be checked without looking at the device,
+    /// validate_memory() and validate_cpus() do the rest
+    pub fn check(&self) -> Result<()> {
+        self.parse_rules().map_err(|e| anyhow!("match-rules: {}", e))?;
+        self.power_profiles().map_err(|e| anyhow!("power-profiles: {}", e))?;
+        self.freeze_all().map_err(|e| anyhow!("freeze-all: {}", e))?;
+        self.low_memory().map_err(|e| anyhow!("low-memory: {}", e))?;
+        self.network_policy().map_err(|e| anyhow!("network-policy: {}", e))?;
+        self.thermal().map_err(|e| anyhow!("thermal: {}", e))?;
+        Ok(())
+    }
+
+    /// JSON schema of the config, generated from the types so it can't
+    /// drift from what we actually accept. Written as YAML.
+    pub fn schema() -> Result<String> {
+        Ok(serde_yaml::to_string(&schemars::schema_for!(Config))?)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What changed between this config and a newer one, one line per
+    /// changed value. Only match-rules and power-profiles are applied
+    /// on reload, changes to anything else are marked as needing a
+    /// restart.
+    pub fn diff(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        let empty = serde_yaml::Mapping::new();
+        let old_map = self.raw.as_mapping().unwrap_or(&empty);
+        let new_map = new.raw.as_mapping().unwrap_or(&empty);
+
+        let keys: Vec<&serde_yaml::Value> = old_map.keys().chain(new_map.keys()).collect();
+        let mut seen = Vec::new();
+        for key in keys {
+            let name = match key.as_str() {
+                Some(name) if !seen.contains(&name) => name,
+                _ => continue,
+            };
+            seen.push(name);
+
+            let mut section = Vec::new();
+            let (old, new) = (old_map.get(key), new_map.get(key));
+            if name == "match-rules" {
+                // Rules are compared by name rather than position
+                let by_name = |rules: Option<&serde_yaml::Value>| -> Vec<(String, serde_yaml::Value)> {
+                    rules
+                        .and_then(|r| r.as_sequence())
+                        .into_iter()
+                        .flatten()
+                        .map(|rule| {
+                            let name = rule.get("name").and_then(|n| n.as_str()).unwrap_or("?");
+                            (name.to_string(), rule.clone())
+                        })
+                        .collect()
+                };
+                let (old, new) = (by_name(old), by_name(new));
+                for (rule, value) in &old {
+                    let other = new.iter().find(|(n, _)| n == rule).map(|(_, v)| v);
+                    diff_yaml(&format!("match-rules.{}", rule), Some(value), other, &mut section);
+                }
+                for (rule, value) in new.iter().filter(|(n, _)| !old.iter().any(|(o, _)| o == n)) {
+                    diff_yaml(&format!("match-rules.{}", rule), None, Some(value), &mut section);
+                }
+            } else {
+                diff_yaml(name, old, new, &mut section);
+            }
+
+            if !matches!(name, "match-rules" | "power-profiles" | "description") {
+                for change in section.iter_mut() {
+                    change.push_str(" (needs a restart)");
+                }
             }
+            changes.append(&mut section);
         }
+
+        changes
     }
 
-    pub fn parse_rules(self) -> Result<Vec<MatchRule>> {
-        let mut rules: Vec<MatchRule> = vec![];
+    /// Threshold (percent) of the low-battery event, if it's configured
+    pub fn low_battery_threshold(&self) -> Option<u32> {
+        self.events.iter().flatten().find_map(|event| match event {
+            EventConfig::LowBattery { threshold } => Some(*threshold),
+            _ => None,
+        })
+    }
+
+    pub fn power_profiles(&self) -> Result<PowerProfilesConfig> {
+        let config = self.power_profiles.clone().unwrap_or_default();
+        for name in config.daemon.values().chain(config.low_battery.iter()) {
+            if !config.profiles.contains_key(name) {
+                bail!("Unknown power profile '{}'", name);
+            }
+        }
+        if config.low_battery.is_some() && self.low_battery_threshold().is_none() {
+            bail!("power-profiles low-battery needs the low-battery event to be configured");
+        }
+        for name in config.profiles.keys() {
+            self.profile_rules(Some(name)).map_err(|e| anyhow!("Power profile '{}': {}", name, e))?;
+        }
+        Ok(config)
+    }
+
+    /// The match rules with a power profile merged on top
+    pub fn profile_rules(&self, profile: Option<&str>) -> Result<Vec<MatchRule>> {
+        let profile = match profile {
+            Some(name) => self
+                .power_profiles
+                .as_ref()
+                .and_then(|p| p.profiles.get(name))
+                .ok_or_else(|| anyhow!("No power profile named '{}'", name))?,
+            None => return self.parse_rules(),
+        };
+        Self::build_rules(&self.overlay_rules(profile)?)
+    }
+
+    /// The match rules as written with a power profile's overlays
+    /// merged onto them
+    fn overlay_rules(&self, profile: &PowerProfileConfig) -> Result<Vec<MatchRuleConfig>> {
+        let mut raw = self.raw.get("match-rules").cloned().unwrap_or_default();
+        let rules = raw
+            .as_sequence_mut()
+            .ok_or_else(|| anyhow!("match-rules isn't a list"))?;
+        for overlay in &profile.match_rules {
+            let name = overlay.get("name").ok_or_else(|| anyhow!("Rule overlay without a name"))?;
+            let base = rules
+                .iter_mut()
+                .find(|rule| rule.get("name") == Some(name))
+                .ok_or_else(|| anyhow!("No match rule named {:?} to overlay", name))?;
+            merge_yaml(base, overlay);
+        }
+
+        Ok(serde_yaml::from_value(raw)?)
+    }
+
+    /// Check the memory profile this config was written for against
+    /// the RAM we actually have, returns the detected memory info.
+    pub fn validate_memory(&self) -> Result<MemInfo> {
+        let info = MemInfo::read()?;
+        // MemTotal doesn't include memory reserved by the kernel
+        // and firmware, so a "4GB" phone reports a bit less than that.
+        let detected = (info.total as f64 / (1u64 << 30) as f64).ceil() as u32;
+        let [recommended, minimum] = self.memory;
+
+        if minimum > 0 && detected < minimum {
+            bail!("This config needs at least {}GB of RAM, only {}GB detected", minimum, detected);
+        }
+        if detected < recommended {
+            warn!("This config recommends {}GB of RAM, only {}GB detected", recommended, detected);
+        }
+        info!("Detected {}GB of RAM ({}MB swap)", detected, info.swap_total >> 20);
+
+        Ok(info)
+    }
+
+    /// Detect the CPU topology and make sure every rule's cpuset
+    /// makes sense on it
+    pub fn validate_cpus(&self) -> Result<CpuLayout> {
+        let layout = CpuLayout::detect()?;
+        info!("Detected CPUs (cpus@capacity): {}, online {}", layout.describe(), format_cpu_list(&layout.online));
+
+        if let Some(cores) = self.cores {
+            if cores as usize != layout.possible.len() {
+                warn!("This config was written for {} CPUs, {} detected", cores, layout.possible.len());
+            }
+        }
 
         for rule in &self.match_rules {
+            let cpuset = &rule.cgroup.cpuset;
+            if let Some(cpu) = cpuset.explicit().find(|cpu| !layout.possible.contains(cpu)) {
+                bail!("{}: cpuset '{}' has CPU {} which doesn't exist", rule.name, cpuset, cpu);
+            }
+            let cpus = cpuset.resolve(&layout);
+            if !cpus.iter().any(|cpu| layout.online.contains(cpu)) {
+                warn!("{}: none of the CPUs in cpuset '{}' are online, all CPUs will be used", rule.name, cpuset);
+            }
+            debug!("{}: cpuset '{}' is CPUs {}", rule.name, cpuset, format_cpu_list(&cpus));
+        }
+
+        Ok(layout)
+    }
+
+    pub fn autosleep(&self) -> AutosleepConfig {
+        self.autosleep.clone().unwrap_or_default()
+    }
+
+    pub fn keep_awake(&self) -> KeepAwakeConfig {
+        self.keep_awake.clone().unwrap_or_default()
+    }
+
+    pub fn suspend(&self) -> SuspendConfig {
+        self.suspend.clone().unwrap_or_default()
+    }
+
+    pub fn freeze_all(&self) -> Result<FreezeAllConfig> {
+        let config = self.freeze_all.clone().unwrap_or_default();
+        for entry in &config.allowlist {
+            match (&entry.cgroup, &entry.app_id) {
+                (None, None) => bail!("freeze-all allowlist entry needs a cgroup or app-id ({})", entry.reason),
+                (Some(p), _) if glob::Pattern::new(p).is_err() => bail!("Invalid cgroup pattern '{}'", p),
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn freezer(&self) -> FreezerConfig {
+        self.freezer.clone().unwrap_or_default()
+    }
+
+    pub fn low_memory(&self) -> Result<LowMemoryConfig> {
+        let config = self.low_memory.clone().unwrap_or_default();
+        if config.trigger != "some" && config.trigger != "full" {
+            bail!("low-memory trigger must be 'some' or 'full', not '{}'", config.trigger);
+        }
+        // Limits imposed by the kernel
+        if !(500..=10000).contains(&config.window_ms) {
+            bail!("low-memory window-ms must be between 500 and 10000");
+        }
+        if config.stall_ms == 0 || config.stall_ms > config.window_ms {
+            bail!("low-memory stall-ms must be between 1 and window-ms");
+        }
+        if let Some(rule) = config.kill_order.iter().find(|r| **r == Rule::Foreground) {
+            bail!("low-memory kill-order can't include the {} rule", rule);
+        }
+        Ok(config)
+    }
+
+    /// None if the busy tag isn't configured
+    pub fn busy(&self) -> Option<BusyConfig> {
+        self.tags.iter().flatten().find_map(|tag| match &tag.inner {
+            TagConfigInner::Busy(busy) => Some(busy.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn reclaim(&self) -> ReclaimConfig {
+        self.reclaim.clone().unwrap_or_default()
+    }
+
+    pub fn network_policy(&self) -> Result<NetworkPolicyConfig> {
+        let config = self.network_policy.clone().unwrap_or_default();
+        if let Some(p) = config.metered_interfaces.iter().find(|p| glob::Pattern::new(p).is_err()) {
+            bail!("Invalid metered interface pattern '{}'", p);
+        }
+        Ok(config)
+    }
+
+    pub fn thermal(&self) -> Result<ThermalConfig> {
+        let config = self.thermal.clone().unwrap_or_default();
+        if let Some(p) = config.zones.iter().find(|p| glob::Pattern::new(p).is_err()) {
+            bail!("Invalid thermal zone pattern '{}'", p);
+        }
+        if config.interval <= 0.0 || config.hysteresis < 0.0 {
+            bail!("thermal interval must be above 0 and hysteresis can't be negative");
+        }
+        for (i, level) in config.levels.iter().enumerate() {
+            if level.temp.is_some() == level.trip.is_some() {
+                bail!("thermal level {} needs one of temp or trip", i);
+            }
+            if level.weight_percent.map_or(false, |w| !(1..=100).contains(&w)) {
+                bail!("thermal level {}: weight-percent must be between 1 and 100", i);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn parse_rules(&self) -> Result<Vec<MatchRule>> {
+        Self::build_rules(&self.match_rules)
+    }
+
+    fn build_rules(configs: &[MatchRuleConfig]) -> Result<Vec<MatchRule>> {
+        let mut rules: Vec<MatchRule> = vec![];
+
+        for rule in configs {
             let conds = MatchConditions::new(
                 rule.only_from.clone(),
                 rule.never_from.clone(),
                 rule.enter_time.clone(),
             );
 
+            if let Some(io) = &rule.cgroup.io {
+                if io.weight.map_or(false, |w| !(1..=10000).contains(&w)) {
+                    bail!("{}: io weight must be between 1 and 10000", rule.name);
+                }
+                let fraction = |size: &Option<MemorySize>| matches!(size, Some(MemorySize::Fraction(_)));
+                if io.devices.iter().any(|dev| fraction(&dev.rbps) || fraction(&dev.wbps)) {
+                    bail!("{}: io rbps and wbps can't be a fraction", rule.name);
+                }
+            }
+
+            if let Some(throttle) = &rule.cgroup.throttle {
+                // The kernel limits for cpu.max
+                if throttle.quota_ms == 0 || !(1..=1000).contains(&throttle.period_ms) {
+                    bail!("{}: throttle needs a quota-ms above 0 and a period-ms of 1-1000", rule.name);
+                }
+            }
+
+            if let Some(budget) = &rule.cgroup.cpu_budget {
+                if budget.seconds <= 0.0 || budget.per == 0 {
+                    bail!("{}: cpu-budget needs seconds and per above 0", rule.name);
+                }
+                if budget.action == FreezeMode::Off {
+                    bail!("{}: cpu-budget action can't be off", rule.name);
+                }
+                if !(0.0..=1.0).contains(&budget.resume_at) {
+                    bail!("{}: cpu-budget resume-at must be between 0 and 1", rule.name);
+                }
+            }
+
+            let percent = |v: Option<f32>| v.map_or(true, |v| (0.0..=100.0).contains(&v));
+            let boost = rule.cgroup.activation_boost.as_ref();
+            if !percent(rule.cgroup.uclamp_min)
+                || !percent(rule.cgroup.uclamp_max)
+                || !percent(boost.map(|b| b.uclamp_min))
+            {
+                bail!("{}: uclamp values must be between 0 and 100", rule.name);
+            }
+            if rule.cgroup.uclamp_min.unwrap_or(0.0) > rule.cgroup.uclamp_max.unwrap_or(100.0) {
+                bail!("{}: uclamp-min can't be above uclamp-max", rule.name);
+            }
+            if boost.map_or(false, |b| b.duration <= 0.0) {
+                bail!("{}: activation-boost duration must be above 0", rule.name);
+            }
+
+            let oom_score_adj = rule.oom_score_adj.unwrap_or(0);
+            if !(-1000..=1000).contains(&oom_score_adj) {
+                bail!("{}: oom-score-adj must be between -1000 and 1000", rule.name);
+            }
+
             rules.push(MatchRule::new(
                 rule.name,
                 conds,
                 rule.cgroup.clone(),
+                oom_score_adj,
             ))
         }
 
         Ok(rules)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 LITTLE, 3 big and a prime core
+    fn layout() -> CpuLayout {
+        let cpus: Vec<u32> = (0..8).collect();
+        let capacity = cpus.iter().map(|&cpu| (cpu, [325, 325, 325, 325, 870, 870, 870, 1024][cpu as usize])).collect();
+        CpuLayout::new(cpus.clone(), cpus, capacity)
+    }
+
+    fn resolve(cpuset: &str) -> Vec<u32> {
+        CpuSet::try_from(cpuset.to_string()).unwrap().resolve(&layout())
+    }
+
+    #[test]
+    fn cpuset_classes() {
+        assert_eq!(resolve("all"), (0..8).collect::<Vec<_>>());
+        assert_eq!(resolve("little"), vec![0, 1, 2, 3]);
+        assert_eq!(resolve("big"), vec![4, 5, 6, 7]);
+        assert_eq!(resolve("top-big"), vec![7]);
+        assert_eq!(resolve("all-but-top-big"), (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cpuset_mixed_terms() {
+        assert_eq!(resolve("little, top-big"), vec![0, 1, 2, 3, 7]);
+        assert_eq!(resolve("0-1,6,big"), vec![0, 1, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn cpuset_symmetric() {
+        let cpus: Vec<u32> = (0..4).collect();
+        let layout = CpuLayout::new(cpus.clone(), cpus.clone(), cpus.iter().map(|&cpu| (cpu, 1024)).collect());
+        let cpuset = CpuSet::try_from("big".to_string()).unwrap();
+        assert_eq!(cpuset.resolve(&layout), cpus);
+    }
+
+    #[test]
+    fn cpuset_invalid() {
+        assert!(CpuSet::try_from("little,".to_string()).is_err());
+        assert!(CpuSet::try_from("medium".to_string()).is_err());
+    }
+
+    /// docs/schema/config.yaml is generated, regenerate it with
+    /// `hammockd --dump-schema > docs/schema/config.yaml`
+    #[test]
+    fn schema_up_to_date() {
+        assert_eq!(Config::schema().unwrap(), include_str!("../docs/schema/config.yaml"));
+    }
+}
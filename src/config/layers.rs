@@ -0,0 +1,672 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Config layering. The effective config is merged from, in order:
+//
+// * the base config, the one given with -c or otherwise
+//   /usr/share/hammock/devices/<compatible>.yaml for the most specific
+//   of the device tree's compatibles that has one
+// * /etc/hammock/config.d/*.yaml, in name order
+// * ~/.config/hammock/config.yaml of the user logged in on seat0, we
+//   run as root so it's looked up through logind rather than taken
+//   from our own environment. The user owns it, so it may only set
+//   the tunables, can't include anything and is skipped with a
+//   warning if it's broken.
+//
+// Any file can pull in others with "include:" (paths are relative to
+// the file), they're merged before the file itself. A file including
+// itself, directly or through others, is an error. Mappings are merged
+// key by key and lists of mappings that all have a name or type are
+// merged item by item, so an overlay can change one match rule or event
+// without repeating the rest. Anything else replaces what was there.
+//
+// Once merged, "$name" strings outside the tunables section are
+// replaced by the tunable of that name, e.g. "$thresholds.low-battery"
+// for thresholds: {low-battery: 20}. "$$" escapes a literal "$".
+//
+// We remember which file each value came from for "hammockctl config
+// show" and to point parse errors of a merged config at the file and
+// line they came from. Values are named by their path, e.g.
+// "match-rules[background].cgroup.cpuset".
+
+use crate::hal;
+use anyhow::Result;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use nix::unistd::{Uid, User};
+use serde::de::DeserializeOwned;
+use nix::fcntl::OFlag;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEVICES_DIR: &str = "/usr/share/hammock/devices";
+const CONFIG_DIR: &str = "/etc/hammock/config.d";
+/// For running from a checkout
+const FALLBACK: &str = "docs/config.default.yaml";
+/// What the session user's overlay may set
+const USER_KEYS: [&str; 1] = ["tunables"];
+/// Tunables may refer to other tunables, but not forever
+const MAX_TUNABLE_DEPTH: usize = 8;
+
+pub struct Layers {
+    /// The merged config with tunables substituted
+    pub value: Value,
+    /// The base config file
+    pub base: PathBuf,
+    /// Every file that was read, in the order they were merged
+    pub sources: Vec<PathBuf>,
+    /// Value path -> where it came from
+    pub provenance: HashMap<String, String>,
+    /// Whether the session user's overlay was merged
+    pub user: bool,
+}
+
+/// The base config when none was given
+fn device_config() -> PathBuf {
+    hal::compatibles()
+        .iter()
+        .map(|compatible| Path::new(DEVICES_DIR).join(format!("{}.yaml", compatible)))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(FALLBACK))
+}
+
+/// The owner of the active session on seat0, if anyone is logged in
+fn session_uid() -> Result<Option<u32>> {
+    let conn = Connection::new_system()?;
+    let seat = conn.with_proxy(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1/seat/seat0",
+        Duration::from_millis(1000),
+    );
+    let (_, session): (String, dbus::Path) = seat.get("org.freedesktop.login1.Seat", "ActiveSession")?;
+    if &*session == "/" {
+        return Ok(None);
+    }
+
+    let session = conn.with_proxy("org.freedesktop.login1", session, Duration::from_millis(1000));
+    let (uid, _): (u32, dbus::Path) = session.get("org.freedesktop.login1.Session", "User")?;
+    Ok(Some(uid))
+}
+
+/// The session user's overlay and their uid, their $XDG_CONFIG_HOME
+/// isn't something we can know so it's always under ~/.config
+fn user_config() -> Option<(PathBuf, u32)> {
+    let uid = match session_uid() {
+        Ok(uid) => uid?,
+        Err(e) => {
+            warn!("Couldn't find the session user, not reading their config: {}", e);
+            return None;
+        }
+    };
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => Some((user.dir.join(".config/hammock/config.yaml"), uid)),
+        Ok(None) => {
+            warn!("Session user {} has no passwd entry, not reading their config", uid);
+            None
+        }
+        Err(e) => {
+            warn!("Couldn't look up session user {}: {}", uid, e);
+            None
+        }
+    }
+}
+
+/// Read the session user's overlay, None if they don't have one. We're
+/// root and the user can put anything there, so it mustn't get us to
+/// read other files: a symlink isn't followed, the file has to be
+/// theirs and it may only set USER_KEYS (so no include either).
+fn read_user_config(path: &Path, uid: u32) -> Result<Option<Value>> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        bail!("not a regular file");
+    }
+    if meta.uid() != uid {
+        bail!("owned by uid {} rather than the session user ({})", meta.uid(), uid);
+    }
+
+    info!("Reading config: {}", path.display());
+    let value: Value = serde_yaml::from_reader(file)?;
+    match &value {
+        Value::Mapping(map) => {
+            if let Some(key) = map.keys().map(key_name).find(|key| !USER_KEYS.contains(&key.as_str())) {
+                bail!("{} can't be set per user, only {}", key, USER_KEYS.join(", "));
+            }
+            Ok(Some(value))
+        }
+        Value::Null => Ok(None),
+        _ => bail!("not a mapping"),
+    }
+}
+
+/// The files that make up the config, for noticing when it changes.
+/// Editors tend to replace files when saving them so the directories
+/// are watched and their events matched against the files.
+pub struct Watch {
+    pub dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Watch {
+    pub fn new(sources: &[PathBuf]) -> Self {
+        let mut files: Vec<PathBuf> = sources.iter().cloned().chain(user_config().map(|(path, _)| path)).collect();
+        files.sort();
+        files.dedup();
+        let mut dirs: Vec<PathBuf> = files
+            .iter()
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .chain(std::iter::once(PathBuf::from(CONFIG_DIR)))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        Self { dirs, files }
+    }
+
+    /// Is path one of the layers, new overlays in config.d count too
+    pub fn matches(&self, path: &Path) -> bool {
+        self.files.iter().any(|file| file == path)
+            || (path.parent() == Some(Path::new(CONFIG_DIR)) && path.extension().map_or(false, |ext| ext == "yaml"))
+    }
+}
+
+/// The name (or type) a list item is merged by
+fn item_id(item: &Value) -> Option<&str> {
+    item.get("name").or_else(|| item.get("type")).and_then(Value::as_str)
+}
+
+fn keyed(list: &[Value]) -> bool {
+    !list.is_empty() && list.iter().all(|item| item_id(item).is_some())
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other).map_or("?".into(), |s| s.trim_end().to_string()),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        path => format!("{}.{}", path, key),
+    }
+}
+
+/// Call cb with the path of every leaf, lists that aren't merged item
+/// by item are leaves
+pub fn walk(value: &Value, path: &str, cb: &mut dyn FnMut(&str, &Value)) {
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map {
+                walk(value, &join(path, &key_name(key)), cb);
+            }
+        }
+        Value::Sequence(list) if keyed(list) => {
+            for item in list {
+                walk(item, &format!("{}[{}]", path, item_id(item).unwrap_or("?")), cb);
+            }
+        }
+        value => cb(path, value),
+    }
+}
+
+struct Merger {
+    value: Value,
+    sources: Vec<PathBuf>,
+    provenance: HashMap<String, String>,
+}
+
+impl Merger {
+    fn record(&mut self, value: &Value, path: &str, source: &str) {
+        // Whatever was there before has been replaced
+        let prefixes = [format!("{}.", path), format!("{}[", path)];
+        self.provenance
+            .retain(|p, _| p != path && !prefixes.iter().any(|prefix| p.starts_with(prefix.as_str())));
+        let provenance = &mut self.provenance;
+        walk(value, path, &mut |leaf, _| {
+            provenance.insert(leaf.to_string(), source.to_string());
+        });
+    }
+
+    fn merge(&mut self, base: &mut Value, overlay: Value, path: &str, source: &str) {
+        match (base, overlay) {
+            (Value::Mapping(base), Value::Mapping(overlay)) => {
+                for (key, value) in overlay {
+                    let path = join(path, &key_name(&key));
+                    match base.get_mut(&key) {
+                        Some(existing) => self.merge(existing, value, &path, source),
+                        None => {
+                            self.record(&value, &path, source);
+                            base.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (Value::Sequence(base), Value::Sequence(overlay)) if keyed(base) && keyed(&overlay) => {
+                for item in overlay {
+                    let id = item_id(&item).unwrap_or("?").to_string();
+                    let path = format!("{}[{}]", path, id);
+                    match base.iter_mut().find(|existing| item_id(existing) == Some(id.as_str())) {
+                        Some(existing) => self.merge(existing, item, &path, source),
+                        None => {
+                            self.record(&item, &path, source);
+                            base.push(item);
+                        }
+                    }
+                }
+            }
+            (base, overlay) => {
+                self.record(&overlay, path, source);
+                *base = overlay;
+            }
+        }
+    }
+
+    /// Merge a file and everything it includes, including is the
+    /// chain of files that led to this one
+    fn merge_file(&mut self, path: &Path, including: &mut Vec<PathBuf>) -> Result<()> {
+        let path = path.canonicalize().map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let path = path.as_path();
+        if including.iter().any(|file| file == path) {
+            let chain: Vec<String> = including
+                .iter()
+                .map(|file| file.display().to_string())
+                .chain(std::iter::once(path.display().to_string()))
+                .collect();
+            bail!("Config includes itself: {}", chain.join(" -> "));
+        }
+        info!("Reading config: {}", path.display());
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let mut value: Value = serde_yaml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+        let includes = match value.as_mapping_mut().and_then(|map| map.remove("include")) {
+            Some(Value::String(include)) => vec![include],
+            Some(Value::Sequence(list)) => list
+                .into_iter()
+                .map(|include| match include {
+                    Value::String(include) => Ok(include),
+                    _ => Err(anyhow!("{}: include must be a path or a list of paths", path.display())),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => bail!("{}: include must be a path or a list of paths", path.display()),
+            None => Vec::new(),
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        including.push(path.to_path_buf());
+        for include in includes {
+            self.merge_file(&dir.join(include), including)?;
+        }
+        including.pop();
+
+        self.merge_layer(path, value);
+        Ok(())
+    }
+
+    fn merge_layer(&mut self, path: &Path, value: Value) {
+        self.sources.push(path.to_path_buf());
+        let mut merged = std::mem::take(&mut self.value);
+        self.merge(&mut merged, value, "", &path.display().to_string());
+        self.value = merged;
+    }
+
+    /// Replace "$name" strings with tunables
+    fn substitute(&mut self, tunables: &Value, value: &mut Value, path: &str) -> Result<()> {
+        match value {
+            Value::Mapping(map) => {
+                for (key, value) in map.iter_mut() {
+                    self.substitute(tunables, value, &join(path, &key_name(key)))?;
+                }
+            }
+            Value::Sequence(list) => {
+                // Lists that aren't merged by item are a single value
+                let keyed = keyed(list);
+                for item in list.iter_mut() {
+                    let path = match keyed {
+                        true => format!("{}[{}]", path, item_id(item).unwrap_or("?")),
+                        false => path.to_string(),
+                    };
+                    self.substitute(tunables, item, &path)?;
+                }
+            }
+            Value::String(s) if s.starts_with("$$") => *s = s[1..].to_string(),
+            Value::String(s) if s.starts_with('$') => {
+                let name = s[1..].to_string();
+                *value = lookup(tunables, &name, 0)?;
+                // Every leaf of the value comes from the same place
+                // under the tunable
+                let tunable = join("tunables", &name);
+                let mut sources = Vec::new();
+                self.provenance.remove(path);
+                walk(value, path, &mut |leaf, _| {
+                    let from = self.provenance.get(&format!("{}{}", tunable, &leaf[path.len()..]));
+                    sources.push((leaf.to_string(), format!("${} from {}", name, from.map_or("", String::as_str))));
+                });
+                self.provenance.extend(sources);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Layers {
+    /// Point an error from parsing the merged config as T at the file
+    /// (and line) the value came from. serde_yaml starts the message
+    /// with the path of the value, e.g. "match-rules[1].cgroup: ...",
+    /// its line and column are in the merged config so they're dropped.
+    pub fn explain<T: DeserializeOwned>(&self, e: &serde_yaml::Error) -> anyhow::Error {
+        let mut message = e.to_string();
+        if let Some(at) = message.rfind(" at line ").filter(|_| e.location().is_some()) {
+            message.truncate(at);
+        }
+        let (path, message) = match message.split_once(": ") {
+            Some((path, rest)) if !path.contains(' ') => {
+                (self.named_path(&self.failing_item::<T>(path)), rest.to_string())
+            }
+            _ => (String::new(), message),
+        };
+
+        // Unknown fields are reported at their parent, look for the
+        // field itself first
+        let field = message
+            .strip_prefix("unknown field `")
+            .and_then(|rest| rest.split('`').next())
+            .map(|field| join(&path, field))
+            .filter(|field| self.provenance.contains_key(field));
+        let path = field.unwrap_or(path);
+        let message = match path.as_str() {
+            "" => message,
+            path => format!("{}: {}", path, message),
+        };
+
+        let sources: Vec<String> = self
+            .sources(&path)
+            .into_iter()
+            .map(|(file, path)| match std::fs::read_to_string(&file).ok().and_then(|text| line_of(&text, &path)) {
+                Some(line) => format!("{}:{}", file, line),
+                None => file,
+            })
+            .collect();
+        match sources.as_slice() {
+            [] => anyhow!("{} (in the merged config, see hammockd --dump-config)", message),
+            [source] => anyhow!("{}: {}", source, message),
+            sources => anyhow!("{} (set in {})", message, sources.join(", ")),
+        }
+    }
+
+    /// serde_yaml loses track of where it is inside internally tagged
+    /// enums (events and tags) and only reports the list, so find the
+    /// item that's wrong by parsing the config with just that one
+    fn failing_item<T: DeserializeOwned>(&self, path: &str) -> String {
+        let len = self.value.get(path).and_then(Value::as_sequence).map_or(0, Vec::len);
+        for i in 0..len {
+            let mut value = self.value.clone();
+            if let Some(Value::Sequence(list)) = value.get_mut(path) {
+                *list = vec![list.swap_remove(i)];
+            }
+            let failed = serde_yaml::to_string(&value)
+                .map(|text| serde_yaml::from_str::<T>(&text))
+                .map_or(false, |parsed| parsed.err().map_or(false, |e| e.to_string().starts_with(&format!("{}: ", path))));
+            if failed {
+                return format!("{}[{}]", path, i);
+            }
+        }
+        path.to_string()
+    }
+
+    /// Turn list indices in a path into the names provenance uses
+    fn named_path(&self, path: &str) -> String {
+        let mut named = String::new();
+        let mut value = Some(&self.value);
+        for part in path.split('.') {
+            let mut pieces = part.split('[');
+            if let Some(key) = pieces.next().filter(|key| !key.is_empty()) {
+                value = value.and_then(|v| v.get(key));
+                named = join(&named, key);
+            }
+            for index in pieces {
+                let list = value.and_then(Value::as_sequence);
+                value = list.and_then(|list| list.get(index.trim_end_matches(']').parse::<usize>().ok()?));
+                match (list, value) {
+                    (Some(list), Some(item)) if keyed(list) => {
+                        named = format!("{}[{}]", named, item_id(item).unwrap_or("?"))
+                    }
+                    // Other lists are a single value
+                    _ => return named,
+                }
+            }
+        }
+        named
+    }
+
+    /// The files a value came from, with the path to look for in each.
+    /// Values that aren't leaves (e.g. a rule with a misspelt field)
+    /// come from wherever the leaves under them did, values taken from
+    /// a tunable point at the tunable.
+    fn sources(&self, path: &str) -> Vec<(String, String)> {
+        let prefixes = [format!("{}.", path), format!("{}[", path)];
+        let mut leaves: Vec<(&String, &String)> = match self.provenance.get_key_value(path) {
+            Some(leaf) => vec![leaf],
+            None if path.is_empty() => Vec::new(),
+            None => self
+                .provenance
+                .iter()
+                .filter(|(leaf, _)| prefixes.iter().any(|prefix| leaf.starts_with(prefix.as_str())))
+                .collect(),
+        };
+        leaves.sort();
+
+        let mut sources: Vec<(String, String)> = Vec::new();
+        for (leaf, source) in leaves {
+            let source = match source.strip_prefix('$').and_then(|tunable| tunable.split_once(" from ")) {
+                Some((name, file)) => (file.to_string(), join("tunables", name)),
+                None => (source.clone(), leaf.clone()),
+            };
+            if !sources.iter().any(|(file, _)| *file == source.0) {
+                sources.push(source);
+            }
+        }
+        sources
+    }
+}
+
+/// The line a value is on, found by looking for each part of its path
+/// in turn. A guess, but configs are written as block YAML with one
+/// key per line.
+fn line_of(text: &str, path: &str) -> Option<usize> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim_start().trim_start_matches("- ")))
+        .filter(|(_, line)| !line.starts_with('#'));
+    let mut found = None;
+    // The name or type key of the item we just found, that's on the
+    // item's first line rather than after it
+    let mut item_key = None;
+    for part in path.split('.') {
+        let mut pieces = part.split('[');
+        if let Some(key) = pieces.next().filter(|key| !key.is_empty()) {
+            if item_key != Some(key) {
+                let key = format!("{}:", key);
+                found = Some(lines.find(|(_, line)| line.starts_with(&key))?.0);
+            }
+        }
+        item_key = None;
+        for id in pieces {
+            let id = id.trim_end_matches(']');
+            let (line, key) = lines.find_map(|(i, line)| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.split(" #").next().unwrap_or_default();
+                let matches = value.trim().trim_matches(|c| c == '"' || c == '\'') == id;
+                ((key == "name" || key == "type") && matches).then(|| (i, if key == "name" { "name" } else { "type" }))
+            })?;
+            found = Some(line);
+            item_key = Some(key);
+        }
+    }
+    found.map(|i| i + 1)
+}
+
+fn lookup(tunables: &Value, name: &str, depth: usize) -> Result<Value> {
+    if depth > MAX_TUNABLE_DEPTH {
+        bail!("Tunable ${} refers to itself", name);
+    }
+    let mut value = tunables;
+    for part in name.split('.') {
+        value = value.get(part).ok_or_else(|| anyhow!("No tunable named ${}", name))?;
+    }
+    match value {
+        Value::String(s) if s.starts_with('$') && !s.starts_with("$$") => lookup(tunables, &s[1..], depth + 1),
+        value => Ok(value.clone()),
+    }
+}
+
+/// Read and merge every layer of the config, base is the config given
+/// on the command line if any. The session user's overlay is only
+/// read if user.
+pub fn load(base: Option<&Path>, user: bool) -> Result<Layers> {
+    let base = base.map_or_else(device_config, Path::to_path_buf);
+    let mut merger = Merger {
+        value: Value::Mapping(Mapping::new()),
+        sources: Vec::new(),
+        provenance: HashMap::new(),
+    };
+    merger.merge_file(&base, &mut Vec::new())?;
+
+    let mut overlays: Vec<PathBuf> = glob::glob(&format!("{}/*.yaml", CONFIG_DIR))?.flatten().collect();
+    overlays.sort();
+    for overlay in overlays {
+        merger.merge_file(&overlay, &mut Vec::new())?;
+    }
+
+    let mut merged_user = false;
+    if let Some((path, uid)) = user_config().filter(|_| user) {
+        match read_user_config(&path, uid) {
+            Ok(Some(value)) => {
+                merger.merge_layer(&path, value);
+                merged_user = true;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring {}: {}", path.display(), e),
+        }
+    }
+
+    let tunables = merger.value.get("tunables").cloned().unwrap_or(Value::Null);
+    let mut value = std::mem::take(&mut merger.value);
+    if let Value::Mapping(map) = &mut value {
+        for (key, value) in map.iter_mut().filter(|(key, _)| key.as_str() != Some("tunables")) {
+            merger.substitute(&tunables, value, &key_name(key))?;
+        }
+    }
+
+    Ok(Layers {
+        value,
+        base,
+        sources: merger.sources,
+        provenance: merger.provenance,
+        user: merged_user,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "# match-rules: in a comment
+tunables:
+  boost:
+    only-big: true
+match-rules:
+  - name: foreground
+    cgroup:
+      cpuset: all
+  - name: background # LITTLE only
+    cgroup:
+      cpuset: little
+events:
+  - type: touch
+    config: $boost
+";
+
+    #[test]
+    fn lines_of_paths() {
+        assert_eq!(line_of(CONFIG, "tunables.boost.only-big"), Some(4));
+        assert_eq!(line_of(CONFIG, "match-rules[background]"), Some(9));
+        assert_eq!(line_of(CONFIG, "match-rules[background].cgroup.cpuset"), Some(11));
+        assert_eq!(line_of(CONFIG, "events[touch].type"), Some(13));
+        assert_eq!(line_of(CONFIG, "events[idle]"), None);
+    }
+
+    #[test]
+    fn tunable_provenance() {
+        let mut merger = Merger {
+            value: Value::Mapping(Mapping::new()),
+            sources: Vec::new(),
+            provenance: HashMap::new(),
+        };
+        let mut value = Value::Mapping(Mapping::new());
+        merger.merge(&mut value, serde_yaml::from_str(CONFIG).unwrap(), "", "base.yaml");
+        let tunables = value.get("tunables").cloned().unwrap();
+        let events = value.get_mut("events").unwrap();
+        merger.substitute(&tunables, events, "events").unwrap();
+
+        assert_eq!(events[0]["config"]["only-big"], Value::Bool(true));
+        assert_eq!(merger.provenance["events[touch].config.only-big"], "$boost from base.yaml");
+        assert!(!merger.provenance.contains_key("events[touch].config"));
+    }
+
+    #[test]
+    fn user_config_checks() {
+        let dir = std::env::temp_dir().join(format!("hammock-layers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let uid = std::fs::metadata(&dir).unwrap().uid();
+        let write = |name: &str, text: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        };
+
+        let good = write("good.yaml", "tunables:\n  timeouts:\n    idle-timeout: 60\n");
+        assert!(read_user_config(&good, uid).unwrap().is_some());
+        assert!(read_user_config(&dir.join("missing.yaml"), uid).unwrap().is_none());
+        assert!(read_user_config(&good, uid + 1).is_err());
+        assert!(read_user_config(&write("include.yaml", "include: /etc/shadow\n"), uid).is_err());
+        assert!(read_user_config(&write("rules.yaml", "match-rules: []\n"), uid).is_err());
+
+        let link = dir.join("link.yaml");
+        std::os::unix::fs::symlink(&good, &link).unwrap();
+        assert!(read_user_config(&link, uid).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,599 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Static checks of the match rules. A config can parse fine and still
+// never do what its author meant, so we build the graph of which rule
+// an app can move to from which other rule and look for:
+//
+// * rules nothing can move an app into
+// * rules (or groups of rules) apps only ever leave by being focused
+// * conditions that can never match, or never-from that always does
+// * rules, tags and events that are referenced but never defined, or
+//   that hammockd never sets or raises
+// * cpusets that partly overlap
+//
+// The conditions are evaluated for every combination of current rule,
+// event and tags they mention, there are few enough of those to just
+// try them all. Moving between rules only considers the tags and events
+// hammockd actually produces. Each power profile's rules are checked
+// too, reporting only what the profile changes.
+
+use super::{Atom, Conditional, Config, EventConfig, MatchRuleConfig, TagConfigInner};
+use super::{Event, Rule, Tag};
+use crate::hal::CpuLayout;
+use std::fmt;
+use strum_macros::Display;
+
+/// Ordered most serious first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+pub struct Lint {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// What a condition gets to look at
+struct State<'a> {
+    rule: Rule,
+    event: Option<Event>,
+    tags: &'a [Tag],
+}
+
+impl State<'_> {
+    fn matches(&self, atom: &Atom) -> bool {
+        match atom {
+            Atom::Rule(r) => self.rule == *r,
+            Atom::Event(e) => self.event == Some(*e),
+            Atom::Tag(t) => self.tags.contains(t),
+        }
+    }
+}
+
+impl Conditional {
+    fn children(&self) -> impl Iterator<Item = &Conditional> {
+        let lists = [&self.any_of, &self.all_of, &self.one_of];
+        self.not
+            .as_deref()
+            .into_iter()
+            .chain(lists.into_iter().flatten().flatten())
+    }
+
+    fn atoms(&self, atoms: &mut Vec<Atom>) {
+        if let Some(atom) = self.atom {
+            if !atoms.contains(&atom) {
+                atoms.push(atom);
+            }
+        }
+        for child in self.children() {
+            child.atoms(atoms);
+        }
+    }
+
+    /// How many of atom, not, anyOf, allOf and oneOf are set
+    fn kinds(&self) -> usize {
+        [
+            self.atom.is_some(),
+            self.not.is_some(),
+            self.any_of.is_some(),
+            self.all_of.is_some(),
+            self.one_of.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+    }
+}
+
+impl MatchRuleConfig {
+    fn atoms(&self) -> Vec<Atom> {
+        let mut atoms = Vec::new();
+        for cond in self.only_from.iter().chain(self.never_from.iter()) {
+            cond.atoms(&mut atoms);
+        }
+        for from in self.enter_time.from.iter().flatten() {
+            if !atoms.contains(&from.atom) {
+                atoms.push(from.atom);
+            }
+        }
+        atoms
+    }
+
+    fn allows(&self, state: &State) -> bool {
+        let matches = |atom: &Atom| state.matches(atom);
+        self.only_from.as_ref().map_or(true, |c| c.evaluate_with(&matches))
+            && !self.never_from.as_ref().map_or(false, |c| c.evaluate_with(&matches))
+    }
+}
+
+struct Linter<'a> {
+    config: &'a Config,
+    configs: &'a [MatchRuleConfig],
+    rules: Vec<Rule>,
+    events: Vec<Event>,
+    tags: Vec<Tag>,
+    /// The events and tags that can actually happen
+    live_events: Vec<Event>,
+    live_tags: Vec<Tag>,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(config: &'a Config, configs: &'a [MatchRuleConfig]) -> Self {
+        let mut rules = Vec::new();
+        let mut events = Vec::new();
+        let mut tags = Vec::new();
+        for atom in configs.iter().flat_map(|rule| rule.atoms()) {
+            match atom {
+                Atom::Rule(r) if !rules.contains(&r) => rules.push(r),
+                Atom::Event(e) if !events.contains(&e) => events.push(e),
+                Atom::Tag(t) if !tags.contains(&t) => tags.push(t),
+                _ => {}
+            }
+        }
+        for rule in configs {
+            if !rules.contains(&rule.name) {
+                rules.push(rule.name);
+            }
+        }
+
+        let live_events = events
+            .iter()
+            .copied()
+            .filter(|e| Event::RAISED.contains(e) && config.defined_events().contains(e))
+            .collect();
+        let live_tags = tags
+            .iter()
+            .copied()
+            .filter(|t| Tag::SET.contains(t) && config.defined_tags().contains(t))
+            .collect();
+
+        Self {
+            config,
+            configs,
+            rules,
+            events,
+            tags,
+            live_events,
+            live_tags,
+            lints: Vec::new(),
+        }
+    }
+
+    fn lint(&mut self, severity: Severity, message: String) {
+        self.lints.push(Lint { severity, message });
+    }
+
+    /// Call cb with every state a rule's conditions could see while
+    /// the app is in rule, only with the events and tags that can
+    /// happen if live
+    fn states(&self, rule: Rule, live: bool, cb: &mut dyn FnMut(&State) -> bool) -> bool {
+        let (events, tags) = match live {
+            true => (&self.live_events, &self.live_tags),
+            false => (&self.events, &self.tags),
+        };
+        let events: Vec<Option<Event>> = std::iter::once(None).chain(events.iter().copied().map(Some)).collect();
+        for event in events {
+            for mask in 0..(1u32 << tags.len()) {
+                let tags: Vec<Tag> = (0..tags.len())
+                    .filter(|i| mask & (1 << i) != 0)
+                    .map(|i| tags[i])
+                    .collect();
+                if cb(&State { rule, event, tags: &tags }) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn satisfiable(&self, cond: &Conditional) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| self.states(*rule, false, &mut |state| cond.evaluate_with(&|atom| state.matches(atom))))
+    }
+
+    fn tautology(&self, cond: &Conditional) -> bool {
+        !self
+            .rules
+            .iter()
+            .any(|rule| self.states(*rule, false, &mut |state| !cond.evaluate_with(&|atom| state.matches(atom))))
+    }
+
+    /// Can an app in from move to rule without being focused
+    fn can_move(&self, from: Rule, rule: &MatchRuleConfig) -> bool {
+        from != rule.name && rule.name != Rule::Foreground && self.states(from, true, &mut |state| rule.allows(state))
+    }
+
+    fn check_definitions(&mut self) {
+        let defined_tags = self.config.defined_tags();
+        let defined_events = self.config.defined_events();
+        let config = self.config;
+        let configs = self.configs;
+
+        for (i, rule) in configs.iter().enumerate() {
+            if configs[..i].iter().any(|r| r.name == rule.name) {
+                self.lint(Severity::Error, format!("{} is defined more than once, only the first is used", rule.name));
+            }
+            for atom in rule.atoms() {
+                match atom {
+                    Atom::Rule(r) if !configs.iter().any(|c| c.name == r) => {
+                        self.lint(Severity::Error, format!("{} refers to rule {} which isn't defined", rule.name, r))
+                    }
+                    Atom::Tag(t) if !Tag::SET.contains(&t) => self.lint(
+                        Severity::Warning,
+                        format!("{} checks for tag {} but hammockd never sets it", rule.name, t),
+                    ),
+                    Atom::Event(e) if !Event::RAISED.contains(&e) => self.lint(
+                        Severity::Warning,
+                        format!("{} checks for event {} but hammockd never raises it", rule.name, e),
+                    ),
+                    Atom::Tag(t) if !defined_tags.contains(&t) => self.lint(
+                        Severity::Warning,
+                        format!("{} checks for tag {} but it isn't in tags so no app ever has it", rule.name, t),
+                    ),
+                    Atom::Event(e) if !defined_events.contains(&e) => self.lint(
+                        Severity::Warning,
+                        format!("{} checks for event {} but it isn't in events so it never happens", rule.name, e),
+                    ),
+                    _ => {}
+                }
+            }
+        }
+
+        if !configs.iter().any(|r| r.name == Rule::Foreground) {
+            self.lint(Severity::Error, "There's no foreground rule, apps start out in it".into());
+        }
+
+        let mut referenced: Vec<(&str, Rule)> = Vec::new();
+        if let Some(thermal) = &config.thermal {
+            referenced.extend(thermal.rules.iter().map(|r| ("thermal", *r)));
+        }
+        if let Some(lowmem) = &config.low_memory {
+            referenced.extend(lowmem.kill_order.iter().map(|r| ("low-memory kill-order", *r)));
+        }
+        for (section, rule) in referenced {
+            if !configs.iter().any(|c| c.name == rule) {
+                self.lint(Severity::Warning, format!("{} refers to rule {} which isn't defined", section, rule));
+            }
+        }
+    }
+
+    /// Report the outermost parts of a condition that can never match
+    fn check_condition(&mut self, path: &str, cond: &Conditional) {
+        if cond.kinds() != 1 {
+            self.lint(
+                Severity::Error,
+                format!("{} has to be exactly one of rule, event, tag, not, anyOf, allOf or oneOf, it never matches", path),
+            );
+            return;
+        }
+        if !self.satisfiable(cond) {
+            self.lint(Severity::Error, format!("{} contradicts itself, it never matches", path));
+            return;
+        }
+
+        let mut children: Vec<(String, &Conditional)> = Vec::new();
+        if let Some(not) = &cond.not {
+            children.push((format!("{}.not", path), not));
+        }
+        for (name, list) in [("anyOf", &cond.any_of), ("allOf", &cond.all_of), ("oneOf", &cond.one_of)] {
+            for (i, child) in list.iter().flatten().enumerate() {
+                children.push((format!("{}.{}[{}]", path, name, i), child));
+            }
+        }
+        for (path, child) in children {
+            self.check_condition(&path, child);
+        }
+    }
+
+    fn check_conditions(&mut self) {
+        for rule in self.configs {
+            if let Some(cond) = &rule.only_from {
+                self.check_condition(&format!("{}.only-from", rule.name), cond);
+            }
+            if let Some(cond) = &rule.never_from {
+                self.check_condition(&format!("{}.never-from", rule.name), cond);
+                if cond.kinds() == 1 && self.tautology(cond) {
+                    self.lint(
+                        Severity::Error,
+                        format!("{}.never-from always matches, apps can't move into {}", rule.name, rule.name),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_graph(&mut self) {
+        let configs = self.configs;
+        // Rules an app in each rule can move to without being focused
+        let edges: Vec<Vec<usize>> = configs
+            .iter()
+            .map(|from| (0..configs.len()).filter(|to| self.can_move(from.name, &configs[*to])).collect())
+            .collect();
+
+        let reach = |start: usize| {
+            let mut seen = vec![start];
+            let mut i = 0;
+            while i < seen.len() {
+                for next in &edges[seen[i]] {
+                    if !seen.contains(next) {
+                        seen.push(*next);
+                    }
+                }
+                i += 1;
+            }
+            seen.sort_unstable();
+            seen
+        };
+
+        // Focus moves an app in any rule to the foreground, which is
+        // where they all start
+        let foreground = configs.iter().position(|r| r.name == Rule::Foreground);
+        let reachable = foreground.map(&reach).unwrap_or_default();
+        for (i, rule) in configs.iter().enumerate() {
+            if foreground.is_some() && !reachable.contains(&i) {
+                self.lint(Severity::Warning, format!("Apps can never move into {}", rule.name));
+            }
+        }
+
+        // Sets of rules that only lead to each other, apps that get
+        // there stay until they're focused again
+        let mut traps: Vec<Vec<usize>> = Vec::new();
+        for i in reachable {
+            let set = reach(i);
+            if set.iter().all(|j| reach(*j) == set) && !traps.contains(&set) {
+                traps.push(set);
+            }
+        }
+        for trap in traps {
+            let names: Vec<String> = trap.iter().map(|i| configs[*i].name.to_string()).collect();
+            match names.as_slice() {
+                [name] if Some(trap[0]) == foreground => self.lint(
+                    Severity::Warning,
+                    format!("Apps can't move out of {} once they lose focus", name),
+                ),
+                [name] => self.lint(Severity::Info, format!("Apps in {} only leave it when they're focused", name)),
+                names => self.lint(
+                    Severity::Info,
+                    format!("Apps in {} only leave them when they're focused", names.join(", ")),
+                ),
+            }
+        }
+
+        // enter-time from a rule apps can't come from does nothing
+        for (to, rule) in configs.iter().enumerate() {
+            for from in rule.enter_time.from.iter().flatten() {
+                if let Atom::Rule(r) = from.atom {
+                    let from_index = configs.iter().position(|c| c.name == r);
+                    if from_index.map_or(false, |f| !edges[f].contains(&to)) && rule.name != Rule::Foreground {
+                        self.lint(
+                            Severity::Info,
+                            format!("{}.enter-time from {} never applies, apps can't move from {} to {}", rule.name, r, r, rule.name),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn run(mut self, layout: Option<&CpuLayout>) -> Vec<Lint> {
+        self.check_definitions();
+        self.check_conditions();
+        self.check_graph();
+        if let Some(layout) = layout {
+            self.check_cpusets(layout);
+        }
+        self.lints
+    }
+
+    fn check_cpusets(&mut self, layout: &CpuLayout) {
+        let configs = self.configs;
+        let cpus: Vec<Vec<u32>> = configs.iter().map(|rule| rule.cgroup.cpuset.resolve(layout)).collect();
+        for a in 0..configs.len() {
+            for b in a + 1..configs.len() {
+                let shared = cpus[a].iter().filter(|cpu| cpus[b].contains(cpu)).count();
+                // Sharing all of one set is deliberate, sharing some
+                // of both is more likely a typo
+                if shared > 0 && shared < cpus[a].len() && shared < cpus[b].len() {
+                    self.lint(
+                        Severity::Warning,
+                        format!(
+                            "cpusets of {} ({}) and {} ({}) partly overlap",
+                            configs[a].name, configs[a].cgroup.cpuset, configs[b].name, configs[b].cgroup.cpuset
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl TagConfigInner {
+    fn tag(&self) -> Tag {
+        match self {
+            TagConfigInner::PlayingMedia => Tag::PlayingMedia,
+            TagConfigInner::HammockAware => Tag::HammockAware,
+            TagConfigInner::WorkPending => Tag::WorkPending,
+            TagConfigInner::Busy(_) => Tag::Busy,
+            TagConfigInner::WasFocused => Tag::WasFocused,
+        }
+    }
+}
+
+impl EventConfig {
+    fn event(&self) -> Event {
+        match self {
+            EventConfig::LowBattery { .. } => Event::LowBattery,
+            EventConfig::WorkReady { .. } => Event::WorkReady,
+            EventConfig::Idle { .. } => Event::Idle,
+            EventConfig::Sleep { .. } => Event::Sleep,
+            EventConfig::Wake => Event::Wake,
+            EventConfig::NetworkRestriction => Event::NetworkRestriction,
+            EventConfig::Touch { .. } => Event::Touch,
+            EventConfig::Hot => Event::Hot,
+        }
+    }
+}
+
+impl Config {
+    fn defined_tags(&self) -> Vec<Tag> {
+        self.tags.iter().flatten().map(|tag| tag.inner.tag()).collect()
+    }
+
+    fn defined_events(&self) -> Vec<Event> {
+        self.events.iter().flatten().map(|event| event.event()).collect()
+    }
+
+    /// Check the match rules for mistakes that parse fine, most
+    /// serious first. cpusets are only compared given the CPU layout.
+    pub fn lint(&self, layout: Option<&CpuLayout>) -> Vec<Lint> {
+        let mut lints = Linter::new(self, &self.match_rules).run(layout);
+
+        let mut profiles: Vec<_> = self.power_profiles.iter().flat_map(|p| &p.profiles).collect();
+        profiles.sort_by_key(|(name, _)| *name);
+        for (name, profile) in profiles {
+            let configs = match self.overlay_rules(profile) {
+                Ok(configs) => configs,
+                Err(e) => {
+                    lints.push(Lint {
+                        severity: Severity::Error,
+                        message: format!("Power profile {}: {}", name, e),
+                    });
+                    continue;
+                }
+            };
+            let new: Vec<Lint> = Linter::new(self, &configs)
+                .run(layout)
+                .into_iter()
+                .filter(|lint| !lints.iter().any(|l| l.message == lint.message))
+                .collect();
+            lints.extend(new.into_iter().map(|lint| Lint {
+                severity: lint.severity,
+                message: format!("With power profile {}: {}", name, lint.message),
+            }));
+        }
+
+        lints.sort_by_key(|lint| lint.severity);
+        lints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(rules: &str) -> Vec<String> {
+        let yaml = format!("description: test\nmemory: [0, 0]\n{}", rules);
+        let mut config: Config = serde_yaml::from_str(&yaml).unwrap();
+        config.raw = serde_yaml::from_str(&yaml).unwrap();
+        config.lint(None).iter().map(|lint| lint.to_string()).collect()
+    }
+
+    #[test]
+    fn unreachable_rules() {
+        let lints = lint(
+            "match-rules:
+  - {name: foreground, cgroup: {cpuset: all}, enter-time: {default: 0}}
+  - {name: background, only-from: {rule: snooze}, cgroup: {cpuset: all}, enter-time: {default: 10}}
+  - {name: snooze, only-from: {rule: background}, cgroup: {cpuset: all}, enter-time: {default: 60}}
+",
+        );
+        assert!(lints.contains(&"warning: Apps can never move into Background".to_string()));
+        assert!(lints.contains(&"warning: Apps can never move into Snooze".to_string()));
+    }
+
+    #[test]
+    fn traps() {
+        let lints = lint(
+            "match-rules:
+  - {name: foreground, cgroup: {cpuset: all}, enter-time: {default: 0}}
+  - {name: background, only-from: {rule: foreground}, cgroup: {cpuset: all}, enter-time: {default: 10}}
+",
+        );
+        assert_eq!(lints, vec!["info: Apps in Background only leave it when they're focused"]);
+    }
+
+    #[test]
+    fn contradictions() {
+        let lints = lint(
+            "match-rules:
+  - {name: foreground, cgroup: {cpuset: all}, enter-time: {default: 0}}
+  - name: background
+    only-from: {allOf: [{rule: foreground}, {rule: snooze}]}
+    cgroup: {cpuset: all}
+    enter-time: {default: 10}
+  - {name: snooze, only-from: {rule: foreground}, never-from: {anyOf: [{rule: recents}, {not: {rule: recents}}]}, cgroup: {cpuset: all}, enter-time: {default: 60}}
+",
+        );
+        assert!(lints.contains(&"error: Background.only-from contradicts itself, it never matches".to_string()));
+        assert!(lints.contains(&"error: Snooze.never-from always matches, apps can't move into Snooze".to_string()));
+    }
+
+    #[test]
+    fn unproduced_tags_and_events() {
+        let lints = lint(
+            "tags:
+  - {type: playing-media}
+events:
+  - {type: idle}
+match-rules:
+  - {name: foreground, cgroup: {cpuset: all}, enter-time: {default: 0}}
+  - name: media
+    only-from: {tag: playing-media}
+    cgroup: {cpuset: all}
+    enter-time: {default: 0}
+  - name: background
+    only-from: {anyOf: [{event: idle}, {rule: foreground}]}
+    cgroup: {cpuset: all}
+    enter-time: {default: 10}
+",
+        );
+        assert!(lints.contains(&"warning: Media checks for tag PlayingMedia but hammockd never sets it".to_string()));
+        assert!(lints.contains(&"warning: Background checks for event Idle but hammockd never raises it".to_string()));
+        assert!(lints.contains(&"warning: Apps can never move into Media".to_string()));
+    }
+
+    #[test]
+    fn power_profiles() {
+        let lints = lint(
+            "match-rules:
+  - {name: foreground, cgroup: {cpuset: all}, enter-time: {default: 0}}
+  - {name: background, only-from: {rule: foreground}, cgroup: {cpuset: all}, enter-time: {default: 10}}
+power-profiles:
+  profiles:
+    powersave:
+      match-rules:
+        - {name: background, only-from: {rule: background}}
+",
+        );
+        assert!(lints.contains(&"warning: With power profile powersave: Apps can never move into Background".to_string()));
+    }
+}
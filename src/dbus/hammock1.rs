@@ -25,3 +25,93 @@
 
 //     fn top_level_closed(&mut self, toplevel: &TopLevel) -> zbus::Result<()>;
 // }
+
+use zbus::dbus_proxy;
+
+/// Client side of the keep-awake API served by hammockd,
+/// see dbus::server for the details.
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.KeepAwake",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait KeepAwake {
+    fn acquire(&self, scope: &str, reason: &str, timeout: u32) -> zbus::Result<u32>;
+
+    fn release(&self, id: u32) -> zbus::Result<()>;
+
+    fn list(&self) -> zbus::Result<Vec<(u32, String, String, String, String, u64, u64)>>;
+
+    fn stats(&self) -> zbus::Result<Vec<(String, u32, u32, u64, u64)>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Freezer",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Freezer {
+    fn unfreezable(&self) -> zbus::Result<Vec<(String, String)>>;
+
+    fn stats(&self) -> zbus::Result<Vec<(String, u32, u64, u64, u32)>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Memory",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Memory {
+    fn reclaimed(&self) -> zbus::Result<Vec<(String, u64, u32)>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Budget",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Budget {
+    fn remaining(&self) -> zbus::Result<Vec<(String, String, String, f64, f64, bool)>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Accounting",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Accounting {
+    #[allow(clippy::type_complexity)]
+    fn usage(
+        &self,
+        rollup: &str,
+        count: u32,
+    ) -> zbus::Result<Vec<(String, u64, f64, u64, u64, u64, u64, std::collections::HashMap<String, u64>)>>;
+
+    fn history(&self, rollup: &str, count: u32, app_id: &str) -> zbus::Result<Vec<(u64, u64, f64, u64, u64, u64)>>;
+
+    fn battery(&self, rollup: &str, count: u32) -> zbus::Result<Vec<(u64, f64, f64)>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Profile",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Profile {
+    fn set(&self, name: &str) -> zbus::Result<()>;
+
+    fn get(&self) -> zbus::Result<(String, String)>;
+
+    fn list(&self) -> zbus::Result<Vec<String>>;
+}
+
+#[dbus_proxy(
+    interface = "dev.calebs.Hammock1.Config",
+    default_service = "dev.calebs.Hammock1",
+    default_path = "/dev/calebs/Hammock1"
+)]
+pub trait Config {
+    fn reload(&self) -> zbus::Result<Vec<String>>;
+
+    fn show(&self) -> zbus::Result<Vec<(String, String, String)>>;
+}
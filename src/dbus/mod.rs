@@ -1,4 +1,5 @@
 
 pub mod hammock1;
+pub mod power_profiles;
 pub mod server;
 pub mod systemd1;
@@ -0,0 +1,44 @@
+// Client for power-profiles-daemon on the system bus
+
+use crate::profiles::PowerProfiles;
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::thread;
+use zbus::blocking::Connection;
+use zbus::dbus_proxy;
+
+#[dbus_proxy(
+    interface = "net.hadess.PowerProfiles",
+    default_service = "net.hadess.PowerProfiles",
+    default_path = "/net/hadess/PowerProfiles"
+)]
+trait PowerProfilesDaemon {
+    #[dbus_proxy(property)]
+    fn active_profile(&self) -> zbus::Result<String>;
+}
+
+/// Follow the daemon's ActiveProfile from a thread, it's fine for
+/// the daemon not to be running
+pub fn watch(profiles: Arc<Mutex<PowerProfiles>>) {
+    thread::spawn(move || {
+        if let Err(e) = watch_profile(profiles) {
+            info!("Not following power-profiles-daemon: {}", e);
+        }
+    });
+}
+
+fn watch_profile(profiles: Arc<Mutex<PowerProfiles>>) -> Result<()> {
+    let conn = Connection::system()?;
+    let proxy = PowerProfilesDaemonProxyBlocking::new(&conn)?;
+    profiles.lock().set_daemon_profile(&proxy.active_profile()?);
+
+    for change in proxy.receive_active_profile_changed() {
+        match change.get() {
+            Ok(profile) => profiles.lock().set_daemon_profile(&profile),
+            Err(e) => warn!("Bad ActiveProfile from power-profiles-daemon: {}", e),
+        }
+    }
+
+    Ok(())
+}
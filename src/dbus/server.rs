@@ -1,76 +1,372 @@
-// /// Server AKA root daemon...
-
-// use anyhow::Result;
-// use zbus::dbus_interface;
-// use zbus::dbus_proxy;
-// use zbus::blocking::{ConnectionBuilder, Connection};
-// use crate::app_track::DesktopAppInfo;
-// use crate::app_track::TopLevel;
-// use crate::events::HammockEvent;
-// use crate::hammock::Hammock;
-
-// struct AppHandler {
-//     hammock: Hammock,
-// }
-
-// #[dbus_interface(name = "dev.calebs.Hammock1.AppHandler")]
-// impl AppHandler {
-//     fn app_launched(&mut self, app_info: DesktopAppInfo) {
-//         trace!("New application launched: {:?}", app_info);
-//         match hammock.handle_event(HammockEvent::NewApplication(app_info)) {
-//             Ok(_) => {},
-//             Err(e) => {
-//                 warn!("{}", e);
-//             }
-//         };
-//     }
-
-//     fn new_top_level(&mut self, toplevel: TopLevel) {
-//         trace!("New toplevel: {:?}", toplevel);
-//         match hammock.handle_event(HammockEvent::NewTopLevel(toplevel)) {
-//             Ok(_) => {},
-//             Err(e) => {
-//                 warn!("{}", e);
-//             }
-//         };
-//     }
-
-//     fn top_level_changed(&mut self, toplevel: TopLevel) {
-//         trace!("Toplevel changed: {:?}", toplevel);
-//         match hammock.handle_event(HammockEvent::TopLevelChanged(toplevel)) {
-//             Ok(_) => {},
-//             Err(e) => {
-//                 warn!("{}", e);
-//             }
-//         };
-//     }
-
-//     fn top_level_closed(&mut self, toplevel: TopLevel) {
-//         trace!("Toplevel closed: {:?}", toplevel);
-//         match hammock.handle_event(HammockEvent::TopLevelClosed(toplevel)) {
-//             Ok(_) => {},
-//             Err(e) => {
-//                 warn!("{}", e);
-//             }
-//         };
-//     }
-// }
-
-// /// Implements the D-Bus service that the root daemon runs
-// pub struct Server {
-//     connection: Connection,
-// }
-
-// impl Server {
-//     pub fn new(hammock: Hammock) -> Result<Self> {
-//         let app_handler = AppHandler { hammock };
-//         let connection = ConnectionBuilder::session()?
-//             .name("dev.calebs.Hammock1.AppHandler")?
-//             .serve_at("/dev/calebs/Hammock1/AppHandler", app_handler)?
-//             .build()?;
-
-//         Ok(Self {
-//             connection,
-//         })
-//     }
-// }
+/// Server AKA root daemon...
+
+use crate::accounting::{Accounting, Rollup};
+use crate::budget::CpuBudgets;
+use crate::cgroups::CGHandler;
+use crate::config::Config;
+use crate::events::HammockEvent;
+use crate::freezer::FreezeTracker;
+use crate::keepawake::{HoldScope, KeepAwake};
+use crate::profiles::PowerProfiles;
+use crate::reclaim::Reclaimer;
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo, MessageHeader};
+
+struct KeepAwakeIface {
+    keepawake: Arc<Mutex<KeepAwake>>,
+}
+
+impl KeepAwakeIface {
+    fn sender(hdr: &MessageHeader<'_>) -> fdo::Result<String> {
+        match hdr.sender()? {
+            Some(sender) => Ok(sender.to_string()),
+            None => Err(fdo::Error::Failed("Message has no sender".into())),
+        }
+    }
+
+    /// Look up which of our cgroups the caller is in
+    async fn sender_cgroup(hdr: &MessageHeader<'_>, conn: &zbus::Connection) -> fdo::Result<Option<String>> {
+        let sender = match hdr.sender()? {
+            Some(sender) => sender.to_owned(),
+            None => return Err(fdo::Error::Failed("Message has no sender".into())),
+        };
+        let pid = fdo::DBusProxy::new(conn)
+            .await?
+            .get_connection_unix_process_id(sender.into())
+            .await?;
+
+        match CGHandler::cgroup_of_pid(pid) {
+            Ok(cgroup) => Ok(cgroup),
+            Err(e) => {
+                warn!("Couldn't get cgroup of pid {}: {}", pid, e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.KeepAwake")]
+impl KeepAwakeIface {
+    /// Acquire a keep-awake hold. scope is either "device" to stop the
+    /// device from suspending or "app" to only stop the caller from being
+    /// frozen. The timeout is in seconds, 0 means the maximum allowed.
+    async fn acquire(
+        &self,
+        #[zbus(header)] hdr: MessageHeader<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+        scope: &str,
+        reason: &str,
+        timeout: u32,
+    ) -> fdo::Result<u32> {
+        let scope: HoldScope = scope
+            .parse()
+            .map_err(|_| fdo::Error::InvalidArgs(format!("Invalid scope '{}'", scope)))?;
+        let owner = Self::sender(&hdr)?;
+        let cgroup = Self::sender_cgroup(&hdr, conn).await?;
+
+        self.keepawake
+            .lock()
+            .acquire(&owner, cgroup, scope, reason, timeout)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    async fn release(&self, #[zbus(header)] hdr: MessageHeader<'_>, id: u32) -> fdo::Result<()> {
+        let owner = Self::sender(&hdr)?;
+        self.keepawake
+            .lock()
+            .release(&owner, id)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+    }
+
+    /// (id, app id, owner, scope, reason, seconds held, seconds remaining)
+    fn list(&self) -> Vec<(u32, String, String, String, String, u64, u64)> {
+        let now = Instant::now();
+        self.keepawake
+            .lock()
+            .list()
+            .iter()
+            .map(|h| {
+                (
+                    h.id,
+                    h.app_id.clone(),
+                    h.owner.clone(),
+                    h.scope.to_string(),
+                    h.reason.clone(),
+                    now.duration_since(h.acquired).as_secs(),
+                    h.expires.saturating_duration_since(now).as_secs(),
+                )
+            })
+            .collect()
+    }
+
+    /// (app id, holds acquired, holds expired, total seconds held, longest hold)
+    fn stats(&self) -> Vec<(String, u32, u32, u64, u64)> {
+        self.keepawake
+            .lock()
+            .stats()
+            .iter()
+            .map(|(app_id, s)| {
+                (app_id.clone(), s.acquired, s.expired, s.held_for.as_secs(), s.longest.as_secs())
+            })
+            .collect()
+    }
+}
+
+struct FreezerIface {
+    freezer: Arc<Mutex<FreezeTracker>>,
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Freezer")]
+impl FreezerIface {
+    /// (cgroup, reason) for each app we gave up trying to freeze
+    fn unfreezable(&self) -> Vec<(String, String)> {
+        self.freezer
+            .lock()
+            .unfreezable()
+            .iter()
+            .map(|(cgroup, reason)| (cgroup.clone(), reason.clone()))
+            .collect()
+    }
+
+    /// (app id, freezes, average ms, max ms, timeouts)
+    fn stats(&self) -> Vec<(String, u32, u64, u64, u32)> {
+        self.freezer
+            .lock()
+            .stats()
+            .iter()
+            .map(|(app_id, s)| {
+                let avg = s.total.as_millis() as u64 / (s.freezes.max(1) as u64);
+                (app_id.clone(), s.freezes, avg, s.max.as_millis() as u64, s.timeouts)
+            })
+            .collect()
+    }
+}
+
+struct MemoryIface {
+    reclaimer: Arc<Mutex<Reclaimer>>,
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Memory")]
+impl MemoryIface {
+    /// (app id, bytes reclaimed, times reclaimed until the kernel ran out)
+    fn reclaimed(&self) -> Vec<(String, u64, u32)> {
+        self.reclaimer
+            .lock()
+            .stats()
+            .iter()
+            .map(|(app_id, s)| (app_id.clone(), s.reclaimed, s.runs))
+            .collect()
+    }
+}
+
+struct BudgetIface {
+    budgets: Arc<Mutex<CpuBudgets>>,
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Budget")]
+impl BudgetIface {
+    /// (app id, cgroup, rule, seconds remaining, seconds per refill, exhausted)
+    fn remaining(&self) -> Vec<(String, String, String, f64, f64, bool)> {
+        self.budgets
+            .lock()
+            .list()
+            .into_iter()
+            .map(|b| (b.app_id, b.cgroup, b.rule.to_string(), b.remaining, b.capacity, b.exhausted))
+            .collect()
+    }
+}
+
+struct AccountingIface {
+    accounting: Arc<Mutex<Accounting>>,
+}
+
+impl AccountingIface {
+    fn rollup(rollup: &str) -> fdo::Result<Rollup> {
+        rollup
+            .parse()
+            .map_err(|_| fdo::Error::InvalidArgs(format!("Invalid rollup '{}', expected hour or day", rollup)))
+    }
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Accounting")]
+impl AccountingIface {
+    /// Usage of each app over the last count hours or days. (app id,
+    /// CPU µs, estimated CPU mWh, peak memory bytes, average memory
+    /// bytes, seconds frozen, seconds thawed, seconds in each rule)
+    #[allow(clippy::type_complexity)]
+    fn usage(
+        &self,
+        rollup: &str,
+        count: u32,
+    ) -> fdo::Result<Vec<(String, u64, f64, u64, u64, u64, u64, HashMap<String, u64>)>> {
+        let rollup = Self::rollup(rollup)?;
+        Ok(self
+            .accounting
+            .lock()
+            .total(rollup, count as usize)
+            .into_iter()
+            .map(|(app_id, u)| {
+                let rules = u.rules.iter().map(|(rule, time)| (rule.to_string(), time.as_secs())).collect();
+                (app_id, u.cpu_usec, u.energy_mwh, u.memory_peak, u.memory_avg(), u.frozen.as_secs(), u.thawed.as_secs(), rules)
+            })
+            .collect())
+    }
+
+    /// An app's usage in each of the last count hours or days. (unix
+    /// time the period starts, CPU µs, estimated CPU mWh, peak memory
+    /// bytes, seconds frozen, seconds thawed)
+    fn history(&self, rollup: &str, count: u32, app_id: &str) -> fdo::Result<Vec<(u64, u64, f64, u64, u64, u64)>> {
+        let rollup = Self::rollup(rollup)?;
+        Ok(self
+            .accounting
+            .lock()
+            .history(rollup, count as usize)
+            .map(|period| match period.apps.get(app_id) {
+                Some(u) => (period.start, u.cpu_usec, u.energy_mwh, u.memory_peak, u.frozen.as_secs(), u.thawed.as_secs()),
+                None => (period.start, 0, 0.0, 0, 0, 0),
+            })
+            .collect())
+    }
+
+    /// Battery drain against what we think the apps used, for each of
+    /// the last count hours or days. (unix time the period starts, mWh
+    /// out of the battery, estimated CPU mWh of all apps while on battery)
+    fn battery(&self, rollup: &str, count: u32) -> fdo::Result<Vec<(u64, f64, f64)>> {
+        let rollup = Self::rollup(rollup)?;
+        Ok(self
+            .accounting
+            .lock()
+            .history(rollup, count as usize)
+            .map(|period| (period.start, period.battery_mwh, period.apps_mwh))
+            .collect())
+    }
+}
+
+/// Implements the dev.calebs.Hammock1 D-Bus service
+pub struct Server {
+    connection: Connection,
+}
+
+struct ProfileIface {
+    profiles: Arc<Mutex<PowerProfiles>>,
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Profile")]
+impl ProfileIface {
+    /// Pick a power profile, an empty name goes back to choosing one
+    /// automatically
+    fn set(&self, name: &str) -> fdo::Result<()> {
+        let profile = match name {
+            "" => None,
+            name => Some(name.to_string()),
+        };
+        self.profiles
+            .lock()
+            .set_manual(profile)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+    }
+
+    /// The profile we're switching to and why (manual, low-battery,
+    /// daemon or none), the name is empty when no profile applies
+    fn get(&self) -> (String, String) {
+        let (profile, source) = self.profiles.lock().wanted();
+        (profile.unwrap_or_default(), source.to_string())
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.profiles.lock().profiles()
+    }
+}
+
+/// How long to wait for the event loop to get around to a reload
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ConfigIface {
+    config: Arc<RwLock<Config>>,
+    events: Mutex<Sender<HammockEvent>>,
+}
+
+#[dbus_interface(name = "dev.calebs.Hammock1.Config")]
+impl ConfigIface {
+    /// Reload the config file, returns what changed. The current
+    /// config is kept if the new one is invalid.
+    fn reload(&self) -> fdo::Result<Vec<String>> {
+        let (tx, rx) = mpsc::channel();
+        self.events
+            .lock()
+            .send(HammockEvent::ReloadConfig(Some(tx)))
+            .map_err(|_| fdo::Error::Failed("The event loop went away".into()))?;
+        match rx.recv_timeout(RELOAD_TIMEOUT) {
+            Ok(result) => result.map_err(fdo::Error::Failed),
+            Err(_) => Err(fdo::Error::Failed("Timed out waiting for the reload".into())),
+        }
+    }
+
+    /// Every value in the config in effect as (path, value, source),
+    /// the source being the file it came from or the tunable it's from
+    fn show(&self) -> Vec<(String, String, String)> {
+        self.config.read().show()
+    }
+}
+
+impl Server {
+    pub fn new(
+        keepawake: Arc<Mutex<KeepAwake>>,
+        freezer: Arc<Mutex<FreezeTracker>>,
+        reclaimer: Arc<Mutex<Reclaimer>>,
+        budgets: Arc<Mutex<CpuBudgets>>,
+        accounting: Arc<Mutex<Accounting>>,
+        profiles: Arc<Mutex<PowerProfiles>>,
+        config: Arc<RwLock<Config>>,
+        events: Sender<HammockEvent>,
+    ) -> Result<Self> {
+        let iface = KeepAwakeIface {
+            keepawake: keepawake.clone(),
+        };
+        let connection = ConnectionBuilder::system()?
+            .name("dev.calebs.Hammock1")?
+            .serve_at("/dev/calebs/Hammock1", iface)?
+            .serve_at("/dev/calebs/Hammock1", FreezerIface { freezer })?
+            .serve_at("/dev/calebs/Hammock1", MemoryIface { reclaimer })?
+            .serve_at("/dev/calebs/Hammock1", BudgetIface { budgets })?
+            .serve_at("/dev/calebs/Hammock1", AccountingIface { accounting })?
+            .serve_at("/dev/calebs/Hammock1", ProfileIface { profiles })?
+            .serve_at(
+                "/dev/calebs/Hammock1",
+                ConfigIface {
+                    config,
+                    events: Mutex::new(events),
+                },
+            )?
+            .build()?;
+
+        let conn = connection.clone();
+        thread::spawn(move || {
+            if let Err(e) = Self::watch_names(conn, keepawake) {
+                error!("Stopped watching for D-Bus clients going away: {}", e);
+            }
+        });
+
+        Ok(Self { connection })
+    }
+
+    /// Release holds belonging to clients that disconnect from the bus
+    fn watch_names(conn: Connection, keepawake: Arc<Mutex<KeepAwake>>) -> Result<()> {
+        let proxy = zbus::blocking::fdo::DBusProxy::new(&conn)?;
+        for signal in proxy.receive_name_owner_changed()? {
+            let args = signal.args()?;
+            if args.new_owner().is_none() {
+                keepawake.lock().release_owner(args.name().as_str());
+            }
+        }
+
+        Ok(())
+    }
+}
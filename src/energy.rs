@@ -0,0 +1,123 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Estimating how much energy apps use on the CPU. A second on a big
+// core costs several times one on a LITTLE core, and more again at
+// high frequencies, so CPU time alone doesn't say much.
+//
+// Each time we sample, cpufreq time_in_state tells us how long each
+// performance domain spent at each frequency since last time, which
+// with the power model gives the average power of a busy CPU in that
+// domain. We don't know which CPUs an app actually ran on, so its CPU
+// time is spread evenly over the CPUs in its cpuset.
+//
+// This only covers the CPU, the screen, modem and GPU are usually the
+// bigger part of what comes out of the battery.
+
+use crate::hal::{CpuTopology, PerfDomain};
+use std::collections::HashMap;
+
+pub struct EnergyModel {
+    topology: Option<CpuTopology>,
+    /// Last time_in_state of each domain, keyed by policy
+    time_in_state: HashMap<u32, Vec<(u32, u64)>>,
+    /// Average power (mW) of a busy CPU in each domain over the
+    /// last sample, keyed by policy
+    power: HashMap<u32, f64>,
+}
+
+impl EnergyModel {
+    pub fn new() -> Self {
+        let topology = match CpuTopology::read() {
+            Ok(topology) => Some(topology),
+            Err(e) => {
+                warn!("No CPU energy model, can't estimate app energy use: {}", e);
+                None
+            }
+        };
+
+        Self {
+            topology,
+            time_in_state: HashMap::new(),
+            power: HashMap::new(),
+        }
+    }
+
+    /// Work out the average power of each domain since the last call
+    pub fn sample(&mut self) {
+        let topology = match &self.topology {
+            Some(topology) => topology,
+            None => return,
+        };
+
+        for domain in topology.domains() {
+            let power = match domain.time_in_state() {
+                Ok(now) => {
+                    let last = self.time_in_state.insert(domain.policy, now.clone());
+                    Self::average_power(domain, last.as_deref(), &now)
+                }
+                // cpufreq stats aren't enabled
+                Err(_) => None,
+            };
+            let power = power.or_else(|| domain.cur_freq().ok().map(|khz| domain.power_at(khz)));
+            if let Some(power) = power {
+                self.power.insert(domain.policy, power);
+            }
+        }
+    }
+
+    fn average_power(domain: &PerfDomain, last: Option<&[(u32, u64)]>, now: &[(u32, u64)]) -> Option<f64> {
+        let last = last?;
+        let mut ticks = 0;
+        let mut energy = 0.0;
+        for (khz, t) in now {
+            let before = last.iter().find(|(k, _)| k == khz).map_or(0, |(_, t)| *t);
+            let delta = t.saturating_sub(before);
+            ticks += delta;
+            energy += delta as f64 * domain.power_at(*khz);
+        }
+        (ticks > 0).then(|| energy / ticks as f64)
+    }
+
+    /// Energy (mWh) used by some CPU time on a set of CPUs
+    pub fn estimate(&self, cpus: &[u32], cpu_usec: u64) -> f64 {
+        let topology = match &self.topology {
+            Some(topology) => topology,
+            None => return 0.0,
+        };
+
+        let powers: Vec<f64> = cpus
+            .iter()
+            .filter_map(|cpu| topology.domain_of(*cpu))
+            .filter_map(|domain| self.power.get(&domain.policy).copied())
+            .collect();
+        if powers.is_empty() {
+            return 0.0;
+        }
+
+        let mw = powers.iter().sum::<f64>() / powers.len() as f64;
+        mw * cpu_usec as f64 / 1e6 / 3600.0
+    }
+}
+
+impl Default for EnergyModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
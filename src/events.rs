@@ -21,6 +21,7 @@ use anyhow::Result;
 
 use crate::app_track::{AppId, DesktopAppInfo, TopLevelInner};
 use crate::hammock::Hammock;
+use std::sync::mpsc::Sender;
 use strum_macros;
 
 #[derive(Debug, Clone, strum_macros::Display)]
@@ -30,6 +31,9 @@ pub enum HammockEvent {
     TopLevelChanged(TopLevelInner),
     TopLevelClosed(TopLevelInner),
     SystemSuspend(bool), // Active = true
+    /// Reload the config, the result (what changed or why the reload
+    /// failed) is sent back if there's anyone waiting for it
+    ReloadConfig(Option<Sender<Result<Vec<String>, String>>>),
 }
 
 pub struct HammockEventLoop;
@@ -0,0 +1,201 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Writing to cgroup.freeze only asks the kernel to freeze the cgroup,
+// it isn't frozen until "frozen 1" shows up in cgroup.events. A task
+// stuck in uninterruptible sleep (NFS, waiting on a GPU fence etc)
+// can stop that from ever happening. We check up on every freeze from
+// the event loop, retry a few times and then give up on the app,
+// marking it as unfreezable with whatever we could find out about the
+// tasks that were blocking it.
+
+use crate::cgroups::CGHandler;
+use crate::config::FreezerConfig;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Pending {
+    cgroup: String,
+    started: Instant,
+    /// When the current attempt was started
+    attempt_started: Instant,
+    retries: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FreezeStats {
+    pub freezes: u32,
+    pub total: Duration,
+    pub max: Duration,
+    /// Attempts that didn't settle in time
+    pub timeouts: u32,
+}
+
+pub struct FreezeTracker {
+    config: FreezerConfig,
+    pending: Vec<Pending>,
+    /// cgroup -> why it couldn't be frozen
+    unfreezable: HashMap<String, String>,
+    /// Keyed by app id
+    stats: HashMap<String, FreezeStats>,
+}
+
+impl FreezeTracker {
+    pub fn new(config: FreezerConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            unfreezable: HashMap::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Start verifying a freeze that was just requested
+    pub fn start(&mut self, cgroup: &str) {
+        if self.pending.iter().any(|p| p.cgroup == cgroup) {
+            return;
+        }
+        let now = Instant::now();
+        self.pending.push(Pending {
+            cgroup: cgroup.to_string(),
+            started: now,
+            attempt_started: now,
+            retries: 0,
+        });
+    }
+
+    /// The cgroup was thawed, stop waiting for it
+    pub fn cancel(&mut self, cgroup: &str) {
+        self.pending.retain(|p| p.cgroup != cgroup);
+    }
+
+    pub fn is_unfreezable(&self, cgroup: &str) -> bool {
+        self.unfreezable.contains_key(cgroup)
+    }
+
+    /// Give the app another chance next time it's frozen
+    pub fn forget(&mut self, cgroup: &str) {
+        self.unfreezable.remove(cgroup);
+    }
+
+    pub fn unfreezable(&self) -> &HashMap<String, String> {
+        &self.unfreezable
+    }
+
+    pub fn stats(&self) -> &HashMap<String, FreezeStats> {
+        &self.stats
+    }
+
+    /// Check up on pending freezes, returns the cgroups we gave up
+    /// on, these should be thawed rather than left half frozen.
+    pub fn process(&mut self, handler: &CGHandler) -> Vec<String> {
+        let now = Instant::now();
+        let timeout = Duration::from_secs_f32(self.config.verify_timeout);
+        let mut failed = Vec::new();
+
+        for mut pending in std::mem::take(&mut self.pending) {
+            let stats = self.stats.entry(CGHandler::app_id_of(&pending.cgroup)).or_default();
+            match handler.is_frozen(&pending.cgroup) {
+                Ok(true) => {
+                    let latency = now.duration_since(pending.started);
+                    trace!("{} frozen after {}ms", pending.cgroup, latency.as_millis());
+                    stats.freezes += 1;
+                    stats.total += latency;
+                    stats.max = stats.max.max(latency);
+                    continue;
+                }
+                Ok(false) if now.duration_since(pending.attempt_started) < timeout => {}
+                Ok(false) => {
+                    stats.timeouts += 1;
+                    let blockers = Self::blockers(handler, &pending.cgroup);
+                    if pending.retries < self.config.retries {
+                        debug!("{} still not frozen after {}ms, retrying. {}",
+                            pending.cgroup, now.duration_since(pending.started).as_millis(), blockers);
+                        let res = handler
+                            .set_frozen(&pending.cgroup, false)
+                            .and_then(|_| handler.set_frozen(&pending.cgroup, true));
+                        if let Err(e) = res {
+                            warn!("Failed to retry freezing {}: {}", pending.cgroup, e);
+                        }
+                        pending.retries += 1;
+                        pending.attempt_started = now;
+                    } else {
+                        warn!("Giving up freezing {}: {}", pending.cgroup, blockers);
+                        self.unfreezable.insert(pending.cgroup.clone(), blockers);
+                        failed.push(pending.cgroup);
+                        continue;
+                    }
+                }
+                // Probably went away, nothing to wait for
+                Err(e) => {
+                    debug!("Can't check if {} is frozen: {}", pending.cgroup, e);
+                    continue;
+                }
+            }
+            self.pending.push(pending);
+        }
+
+        failed
+    }
+
+    /// Describe the tasks that are stopping a cgroup from freezing
+    fn blockers(handler: &CGHandler, cgroup: &str) -> String {
+        let tids = match handler.threads(cgroup) {
+            Ok(tids) => tids,
+            Err(e) => return format!("couldn't list tasks: {}", e),
+        };
+
+        let stuck: Vec<String> = tids
+            .iter()
+            .filter_map(|tid| match task_state(*tid) {
+                Ok((comm, 'D')) => {
+                    let wchan = std::fs::read_to_string(format!("/proc/{}/wchan", tid)).unwrap_or_default();
+                    Some(match wchan.trim() {
+                        "" | "0" => format!("{} ({})", tid, comm),
+                        wchan => format!("{} ({}) in {}", tid, comm, wchan),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        match stuck.len() {
+            0 => "freezer didn't settle, no tasks in uninterruptible sleep".into(),
+            _ => format!("tasks in uninterruptible sleep: {}", stuck.join(", ")),
+        }
+    }
+}
+
+/// Get the command name and state of a task from /proc/<tid>/stat
+fn task_state(tid: u32) -> Result<(String, char)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", tid))?;
+    // comm can contain spaces and brackets, it's between the
+    // first '(' and the last ')'
+    let (start, end) = match (stat.find('('), stat.rfind(')')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => bail!("Malformed stat for {}", tid),
+    };
+    let state = match stat[end + 1..].trim_start().chars().next() {
+        Some(state) => state,
+        None => bail!("Malformed stat for {}", tid),
+    };
+
+    Ok((stat[start + 1..end].to_string(), state))
+}
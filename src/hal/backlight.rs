@@ -43,7 +43,7 @@ impl std::default::Default for Backlight {
 
 impl Backlight {
     fn get(&self, filename: &str) -> Result<i32, io::Error> {
-        let path = self.path.as_path();
+        let path = self.path.join(filename);
         let mut file = File::open(path)?;
 
         let mut content = String::new();
@@ -65,7 +65,7 @@ impl Backlight {
             value = 0;
         }
 
-        let path = self.path.as_path();
+        let path = self.path.join("brightness");
 
         let mut file = OpenOptions::new().write(true).open(path)?;
 
@@ -86,6 +86,16 @@ impl Backlight {
         return self.get("brightness");
     }
 
+    /// The panel is considered off if it's blanked or the
+    /// brightness is zero.
+    pub fn is_off(&self) -> Result<bool, io::Error> {
+        // bl_power uses the FB_BLANK_* values, 0 is unblanked
+        if self.get("bl_power")? > 0 {
+            return Ok(true);
+        }
+        Ok(self.get_brightness()? == 0)
+    }
+
     pub fn get_percent(&self) -> Result<i32, io::Error> {
         let value = self.get_brightness()? as f32;
         let max = self.get_max_brightness()? as f32;
@@ -0,0 +1,438 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// CPU topology and power. Phones are big.LITTLE, CPUs are grouped
+// into performance domains (one per cpufreq policy) which share a
+// clock and differ in capacity and power draw. The power each domain
+// draws at each frequency comes from, in order of preference:
+//
+// * the kernel energy model in /sys/kernel/debug/energy_model
+// * the dynamic-power-coefficient devicetree property and the OPP
+//   voltages in /sys/kernel/debug/opp, the same way the kernel
+//   builds the energy model
+// * a guess from cpu_capacity, which is only good for comparing
+//   domains against each other
+//
+// See Documentation/power/energy-model.rst
+//
+// CpuLayout is the simpler view used for cpusets, it only groups CPUs
+// by capacity so configs can say "little" or "big" rather than
+// hardcoding CPU numbers for one SoC.
+
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+const CPU_PATH: &str = "/sys/devices/system/cpu";
+const ENERGY_MODEL_PATH: &str = "/sys/kernel/debug/energy_model";
+const OPP_PATH: &str = "/sys/kernel/debug/opp";
+/// What we assume a capacity 1024 CPU draws at its highest
+/// frequency when there's nothing better to go on
+const FALLBACK_MAX_MW: f64 = 1000.0;
+
+/// Parse a kernel CPU list like "0-3,6"
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (u32, u32) = (start.parse()?, end.parse()?);
+                if start > end {
+                    bail!("Invalid CPU range '{}'", part);
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Format CPUs back into a kernel CPU list
+pub fn format_cpu_list(cpus: &[u32]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort_unstable();
+    cpus.dedup();
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let start = cpus[i];
+        while i + 1 < cpus.len() && cpus[i + 1] == cpus[i] + 1 {
+            i += 1;
+        }
+        match cpus[i] {
+            end if end == start => ranges.push(start.to_string()),
+            end => ranges.push(format!("{}-{}", start, end)),
+        }
+        i += 1;
+    }
+    ranges.join(",")
+}
+
+fn read(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerState {
+    pub khz: u32,
+    pub mw: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSource {
+    EnergyModel,
+    Devicetree,
+    Capacity,
+}
+
+/// A group of CPUs that share a clock
+#[derive(Debug, Clone)]
+pub struct PerfDomain {
+    /// The cpufreq policy number
+    pub policy: u32,
+    pub cpus: Vec<u32>,
+    /// 1024 for the biggest CPU in the system
+    pub capacity: u32,
+    /// Sorted by frequency
+    pub states: Vec<PowerState>,
+    pub source: PowerSource,
+}
+
+impl PerfDomain {
+    /// Power drawn by one busy CPU at a frequency, interpolating
+    /// between the states we know about
+    pub fn power_at(&self, khz: u32) -> f64 {
+        let above = self.states.iter().position(|s| s.khz >= khz);
+        match above {
+            Some(0) => self.states[0].mw,
+            Some(i) => {
+                let (lo, hi) = (self.states[i - 1], self.states[i]);
+                lo.mw + (hi.mw - lo.mw) * (khz - lo.khz) as f64 / (hi.khz - lo.khz).max(1) as f64
+            }
+            None => self.states.last().map_or(0.0, |s| s.mw),
+        }
+    }
+
+    /// Ticks (10ms) spent at each frequency since boot
+    pub fn time_in_state(&self) -> Result<Vec<(u32, u64)>> {
+        let path = Path::new(CPU_PATH).join(format!("cpufreq/policy{}/stats/time_in_state", self.policy));
+        let mut states = Vec::new();
+        for line in read(&path)?.lines() {
+            if let Some((khz, ticks)) = line.split_once(' ') {
+                states.push((khz.trim().parse()?, ticks.trim().parse()?));
+            }
+        }
+        Ok(states)
+    }
+
+    /// The frequency the domain is running at right now
+    pub fn cur_freq(&self) -> Result<u32> {
+        let path = Path::new(CPU_PATH).join(format!("cpufreq/policy{}/scaling_cur_freq", self.policy));
+        Ok(read(&path)?.parse()?)
+    }
+}
+
+/// Which CPUs there are and how fast they are relative to each other,
+/// used to resolve symbolic cpusets
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuLayout {
+    pub possible: Vec<u32>,
+    pub online: Vec<u32>,
+    /// (cpu, capacity) for every possible CPU
+    capacity: Vec<(u32, u32)>,
+}
+
+impl CpuLayout {
+    /// Capacities come from cpu_capacity, or the highest frequency of
+    /// each CPU's cpufreq policy on kernels without it. One metric is
+    /// used for every CPU, mixing them would rank capacities against
+    /// kHz. CPUs are all treated the same if neither is complete.
+    pub fn detect() -> Result<Self> {
+        let possible = CpuTopology::possible()?;
+        let online = CpuTopology::online()?;
+        let read_all = |file: &str| -> Option<Vec<(u32, u32)>> {
+            possible
+                .iter()
+                .map(|cpu| {
+                    let path = Path::new(CPU_PATH).join(format!("cpu{}/{}", cpu, file));
+                    Some((*cpu, read(&path).ok()?.parse().ok()?))
+                })
+                .collect()
+        };
+        let capacity = match read_all("cpu_capacity").or_else(|| read_all("cpufreq/cpuinfo_max_freq")) {
+            Some(capacity) => capacity,
+            None => {
+                debug!("No cpu_capacity or cpuinfo_max_freq for every CPU, treating them all the same");
+                possible.iter().map(|cpu| (*cpu, 1024)).collect()
+            }
+        };
+
+        Ok(Self::new(possible, online, capacity))
+    }
+
+    /// capacity is (cpu, capacity) for every possible CPU, in any unit
+    /// as long as it's the same for all of them
+    pub fn new(possible: Vec<u32>, online: Vec<u32>, capacity: Vec<(u32, u32)>) -> Self {
+        Self {
+            possible,
+            online,
+            capacity,
+        }
+    }
+
+    /// Re-read the online CPUs, returns true if they changed
+    pub fn refresh_online(&mut self) -> Result<bool> {
+        let online = CpuTopology::online()?;
+        let changed = online != self.online;
+        self.online = online;
+        Ok(changed)
+    }
+
+    fn min_capacity(&self) -> u32 {
+        self.capacity.iter().map(|(_, c)| *c).min().unwrap_or(0)
+    }
+
+    fn max_capacity(&self) -> u32 {
+        self.capacity.iter().map(|(_, c)| *c).max().unwrap_or(0)
+    }
+
+    /// The slowest CPUs
+    pub fn little(&self) -> Vec<u32> {
+        let min = self.min_capacity();
+        self.capacity.iter().filter(|(_, c)| *c == min).map(|(cpu, _)| *cpu).collect()
+    }
+
+    /// Everything faster than the LITTLE CPUs, or all of them if
+    /// they're all the same
+    pub fn big(&self) -> Vec<u32> {
+        let min = self.min_capacity();
+        match self.capacity.iter().filter(|(_, c)| *c > min).map(|(cpu, _)| *cpu).collect::<Vec<_>>() {
+            big if big.is_empty() => self.possible.clone(),
+            big => big,
+        }
+    }
+
+    /// The single fastest CPU, the last one if there are several
+    pub fn top_big(&self) -> Option<u32> {
+        let max = self.max_capacity();
+        self.capacity.iter().filter(|(_, c)| *c == max).map(|(cpu, _)| *cpu).max()
+    }
+
+    /// Clusters as "cpus@capacity" for logging
+    pub fn describe(&self) -> String {
+        let mut capacities: Vec<u32> = self.capacity.iter().map(|(_, c)| *c).collect();
+        capacities.sort_unstable();
+        capacities.dedup();
+        capacities
+            .iter()
+            .map(|cap| {
+                let cpus: Vec<u32> = self.capacity.iter().filter(|(_, c)| c == cap).map(|(cpu, _)| *cpu).collect();
+                format!("{}@{}", format_cpu_list(&cpus), cap)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+pub struct CpuTopology {
+    domains: Vec<PerfDomain>,
+}
+
+impl CpuTopology {
+    /// Work out the performance domains and their power
+    pub fn read() -> Result<Self> {
+        let mut domains = Vec::new();
+        for entry in fs::read_dir(Path::new(CPU_PATH).join("cpufreq"))? {
+            let path = entry?.path();
+            let policy = match path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("policy"))
+                .and_then(|n| n.parse::<u32>().ok())
+            {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let cpus = parse_cpu_list(&read(&path.join("related_cpus"))?)?;
+            let capacity = read(&Path::new(CPU_PATH).join(format!("cpu{}/cpu_capacity", policy)))
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(1024);
+            let freqs = Self::frequencies(&path)?;
+
+            let (states, source) = match Self::energy_model(&cpus) {
+                Some(states) => (states, PowerSource::EnergyModel),
+                None => match Self::devicetree_power(policy, &freqs) {
+                    Some(states) => (states, PowerSource::Devicetree),
+                    None => (Self::capacity_power(capacity, &freqs), PowerSource::Capacity),
+                },
+            };
+            debug!("CPUs {} at capacity {}: {} power states from {:?}",
+                format_cpu_list(&cpus), capacity, states.len(), source);
+
+            domains.push(PerfDomain {
+                policy,
+                cpus,
+                capacity,
+                states,
+                source,
+            });
+        }
+        domains.sort_by_key(|d| d.policy);
+
+        Ok(Self { domains })
+    }
+
+    pub fn domains(&self) -> &[PerfDomain] {
+        &self.domains
+    }
+
+    /// The domain a CPU belongs to
+    pub fn domain_of(&self, cpu: u32) -> Option<&PerfDomain> {
+        self.domains.iter().find(|d| d.cpus.contains(&cpu))
+    }
+
+    /// CPUs that are currently online
+    pub fn online() -> Result<Vec<u32>> {
+        parse_cpu_list(&read(&Path::new(CPU_PATH).join("online"))?)
+    }
+
+    /// Every CPU the kernel knows about, online or not
+    pub fn possible() -> Result<Vec<u32>> {
+        parse_cpu_list(&read(&Path::new(CPU_PATH).join("possible"))?)
+    }
+
+    /// Available frequencies in kHz, from the time_in_state table
+    /// if the driver doesn't list them
+    fn frequencies(policy: &Path) -> Result<Vec<u32>> {
+        let mut freqs: Vec<u32> = match read(&policy.join("scaling_available_frequencies")) {
+            Ok(freqs) => freqs.split_whitespace().filter_map(|f| f.parse().ok()).collect(),
+            Err(_) => read(&policy.join("stats/time_in_state"))
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|l| l.split_whitespace().next()?.parse().ok())
+                .collect(),
+        };
+        if freqs.is_empty() {
+            freqs.push(read(&policy.join("cpuinfo_max_freq"))?.parse()?);
+        }
+        freqs.sort_unstable();
+        Ok(freqs)
+    }
+
+    /// Power states from the kernel energy model, the directories are
+    /// named pdN or cpuN depending on the kernel version
+    fn energy_model(cpus: &[u32]) -> Option<Vec<PowerState>> {
+        for entry in fs::read_dir(ENERGY_MODEL_PATH).ok()? {
+            let path = entry.ok()?.path();
+            let pd_cpus = read(&path.join("cpus")).ok().and_then(|c| parse_cpu_list(&c).ok());
+            if pd_cpus.as_deref() != Some(cpus) {
+                continue;
+            }
+
+            let mut states = Vec::new();
+            for ps in fs::read_dir(&path).ok()?.flatten() {
+                let ps = ps.path();
+                if !ps.file_name()?.to_str()?.starts_with("ps:") {
+                    continue;
+                }
+                let khz = read(&ps.join("frequency")).ok()?.parse().ok()?;
+                let power: f64 = read(&ps.join("power")).ok()?.parse().ok()?;
+                states.push(PowerState { khz, mw: power });
+            }
+            // The energy model switched from mW to µW in 5.19, no
+            // CPU draws anywhere near 100W
+            if states.iter().any(|s| s.mw > 100_000.0) {
+                states.iter_mut().for_each(|s| s.mw /= 1000.0);
+            }
+            states.sort_by_key(|s| s.khz);
+            return (!states.is_empty()).then_some(states);
+        }
+        None
+    }
+
+    /// Power states like the kernel builds them from devicetree,
+    /// mW = coefficient * mV^2 * MHz / 10^9
+    fn devicetree_power(policy: u32, freqs: &[u32]) -> Option<Vec<PowerState>> {
+        let path = Path::new(CPU_PATH).join(format!("cpu{}/of_node/dynamic-power-coefficient", policy));
+        let raw = fs::read(path).ok()?;
+        // Devicetree cells are big endian
+        let coefficient = u32::from_be_bytes(raw.get(..4)?.try_into().ok()?) as f64;
+
+        let mut states = Vec::new();
+        for &khz in freqs {
+            let opp = Path::new(OPP_PATH).join(format!("cpu{}/opp:{}", policy, khz as u64 * 1000));
+            let uv: f64 = read(&opp.join("supply-0/u_volt_target"))
+                .or_else(|_| read(&opp.join("supply/u_volt_target")))
+                .ok()?
+                .parse()
+                .ok()?;
+            let mv = uv / 1000.0;
+            states.push(PowerState {
+                khz,
+                mw: coefficient * mv * mv * (khz as f64 / 1000.0) / 1e9,
+            });
+        }
+        (!states.is_empty()).then_some(states)
+    }
+
+    /// Without voltages, assume power scales with capacity and
+    /// roughly with the cube of the frequency (voltage goes up with it)
+    fn capacity_power(capacity: u32, freqs: &[u32]) -> Vec<PowerState> {
+        let max = freqs.last().copied().unwrap_or(1).max(1) as f64;
+        freqs
+            .iter()
+            .map(|&khz| PowerState {
+                khz,
+                mw: FALLBACK_MAX_MW * capacity as f64 / 1024.0 * (khz as f64 / max).powi(3),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,6").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert_eq!(parse_cpu_list("5\n").unwrap(), vec![5]);
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<u32>::new());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn format_cpu_lists() {
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 6]), "0-3,6");
+        assert_eq!(format_cpu_list(&[7, 4, 5, 4]), "4-5,7");
+        assert_eq!(format_cpu_list(&[2]), "2");
+        assert_eq!(format_cpu_list(&[]), "");
+    }
+
+    #[test]
+    fn cpu_lists_round_trip() {
+        for list in ["0-7", "0,2,4", "0-3,6-7"] {
+            assert_eq!(format_cpu_list(&parse_cpu_list(list).unwrap()), list);
+        }
+    }
+}
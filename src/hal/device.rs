@@ -0,0 +1,36 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+// What device we're running on, from the device tree
+
+use std::fs;
+
+const COMPATIBLE_PATH: &str = "/sys/firmware/devicetree/base/compatible";
+
+/// The machine's compatible strings, most specific first, e.g.
+/// ["oneplus,enchilada", "qcom,sdm845"]. Empty without a device tree.
+pub fn compatibles() -> Vec<String> {
+    match fs::read(COMPATIBLE_PATH) {
+        Ok(raw) => raw
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -0,0 +1,62 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+use anyhow::Result;
+use std::fs;
+
+/// The bits of /proc/meminfo we care about, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemInfo {
+    pub total: u64,
+    pub available: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+}
+
+impl MemInfo {
+    pub fn read() -> Result<Self> {
+        let content = fs::read_to_string("/proc/meminfo")?;
+        let mut info = MemInfo::default();
+
+        for line in content.lines() {
+            // e.g. "MemTotal:        3809452 kB"
+            let (key, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let value = match value.trim().trim_end_matches("kB").trim().parse::<u64>() {
+                Ok(v) => v * 1024,
+                Err(_) => continue,
+            };
+            match key {
+                "MemTotal" => info.total = value,
+                "MemAvailable" => info.available = value,
+                "SwapTotal" => info.swap_total = value,
+                "SwapFree" => info.swap_free = value,
+                _ => {}
+            }
+        }
+
+        if info.total == 0 {
+            bail!("No MemTotal in /proc/meminfo");
+        }
+
+        Ok(info)
+    }
+}
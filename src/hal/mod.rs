@@ -20,13 +20,29 @@
 use anyhow::Result;
 
 mod backlight;
+mod cpu;
+mod device;
+mod memory;
+mod power;
+mod power_supply;
+mod pressure;
+mod thermal;
 mod wakeup;
 
 pub use backlight::Backlight;
+pub use cpu::{format_cpu_list, parse_cpu_list, CpuLayout, CpuTopology, PerfDomain, PowerSource, PowerState};
+pub use device::compatibles;
+pub use memory::MemInfo;
+pub use power::{Power, SuspendStats};
+pub use power_supply::{Battery, PowerSupply};
+pub use pressure::{Pressure, PsiTrigger};
+pub use thermal::{ThermalZone, Trip};
 pub use wakeup::{Wakeup, WakeupType};
 
 pub struct Hal {
     backlight: Backlight,
+    power: Power,
+    power_supply: PowerSupply,
     wakeup: Wakeup,
 }
 
@@ -34,6 +50,8 @@ impl Hal {
     pub fn new() -> Self {
         Self {
             backlight: backlight::Backlight::default(),
+            power: power::Power::new(),
+            power_supply: power_supply::PowerSupply::new(),
             wakeup: wakeup::Wakeup::new(),
         }
     }
@@ -42,6 +60,14 @@ impl Hal {
         &self.backlight
     }
 
+    pub fn power(&self) -> &Power {
+        &self.power
+    }
+
+    pub fn power_supply(&self) -> &PowerSupply {
+        &self.power_supply
+    }
+
     pub fn wakeup(&self) -> &Wakeup {
         &self.wakeup
     }
@@ -0,0 +1,85 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Interface to /sys/power, see Documentation/ABI/testing/sysfs-power
+
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct Power {
+    path: PathBuf,
+}
+
+/// Counters from /sys/power/suspend_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SuspendStats {
+    pub success: u32,
+    pub fail: u32,
+}
+
+impl Power {
+    pub fn new() -> Self {
+        Self {
+            path: PathBuf::from("/sys/power"),
+        }
+    }
+
+    fn read_u32(path: &Path) -> Result<u32> {
+        let content = fs::read_to_string(path)?;
+        match content.trim().parse::<u32>() {
+            Ok(value) => Ok(value),
+            Err(e) => Err(anyhow!("Failed to parse {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Read the current wakeup count. The kernel blocks this read
+    /// while wakeup events are being processed, so callers shouldn't
+    /// do this from the event loop.
+    pub fn wakeup_count(&self) -> Result<u32> {
+        Self::read_u32(&self.path.join("wakeup_count"))
+    }
+
+    /// Write back a count previously returned by wakeup_count().
+    /// Returns false if the kernel rejected it, meaning some wakeup
+    /// event happened in between and we shouldn't try to suspend.
+    pub fn set_wakeup_count(&self, count: u32) -> Result<bool> {
+        let mut file = OpenOptions::new().write(true).open(self.path.join("wakeup_count"))?;
+        match file.write_all(count.to_string().as_bytes()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn suspend_stats(&self) -> Result<SuspendStats> {
+        let stats = self.path.join("suspend_stats");
+        Ok(SuspendStats {
+            success: Self::read_u32(&stats.join("success"))?,
+            fail: Self::read_u32(&stats.join("fail"))?,
+        })
+    }
+}
+
+impl Default for Power {
+    fn default() -> Self {
+        Self::new()
+    }
+}
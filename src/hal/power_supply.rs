@@ -0,0 +1,116 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Interface to /sys/class/power_supply, see
+// Documentation/ABI/testing/sysfs-class-power
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct PowerSupply {
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Battery {
+    /// Percent
+    pub capacity: u32,
+    /// "Charging", "Discharging", "Full" or "Not charging"
+    pub status: String,
+    /// µW, fuel gauges that only measure current don't have it
+    pub power_now: Option<u64>,
+    /// µA
+    pub current_now: Option<i64>,
+    /// µV
+    pub voltage_now: Option<u64>,
+    /// µWh
+    pub energy_now: Option<u64>,
+    pub energy_full: Option<u64>,
+}
+
+impl Battery {
+    pub fn discharging(&self) -> bool {
+        self.status == "Discharging"
+    }
+
+    /// Power going in or out of the battery in mW, from the current
+    /// and voltage if the fuel gauge doesn't report it
+    pub fn power(&self) -> Option<f64> {
+        match (self.power_now, self.current_now, self.voltage_now) {
+            (Some(uw), _, _) => Some(uw as f64 / 1000.0),
+            // Some gauges report discharge current as negative
+            (None, Some(ua), Some(uv)) => Some(ua.unsigned_abs() as f64 * uv as f64 / 1e9),
+            _ => None,
+        }
+    }
+}
+
+impl PowerSupply {
+    pub fn new() -> Self {
+        Self {
+            path: PathBuf::from("/sys/class/power_supply"),
+        }
+    }
+
+    fn read(path: &Path, attr: &str) -> Result<String> {
+        Ok(fs::read_to_string(path.join(attr))?.trim().to_string())
+    }
+
+    /// The battery powering the device, peripherals like
+    /// bluetooth headphones also show up as batteries
+    fn battery_path(&self) -> Result<Option<PathBuf>> {
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if Self::read(&path, "type").map_or(false, |t| t == "Battery")
+                && Self::read(&path, "scope").map_or(true, |s| s != "Device")
+            {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// None if the device doesn't have a battery
+    pub fn battery(&self) -> Result<Option<Battery>> {
+        let path = match self.battery_path()? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let capacity = Self::read(&path, "capacity")?;
+        Ok(Some(Battery {
+            capacity: capacity
+                .parse()
+                .map_err(|e| anyhow!("Failed to parse {}/capacity: {}", path.display(), e))?,
+            status: Self::read(&path, "status")?,
+            power_now: Self::read(&path, "power_now").ok().and_then(|v| v.parse().ok()),
+            current_now: Self::read(&path, "current_now").ok().and_then(|v| v.parse().ok()),
+            voltage_now: Self::read(&path, "voltage_now").ok().and_then(|v| v.parse().ok()),
+            energy_now: Self::read(&path, "energy_now").ok().and_then(|v| v.parse().ok()),
+            energy_full: Self::read(&path, "energy_full").ok().and_then(|v| v.parse().ok()),
+        }))
+    }
+}
+
+impl Default for PowerSupply {
+    fn default() -> Self {
+        Self::new()
+    }
+}
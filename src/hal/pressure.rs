@@ -0,0 +1,112 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Pressure stall information, see Documentation/accounting/psi.rst
+//
+// Both /proc/pressure/* and the per-cgroup *.pressure files have the
+// same format. Writing "<some|full> <stall us> <window us>" to one of
+// them creates a trigger which fires (POLLPRI) when tasks were stalled
+// for at least that long within the window. The trigger lives as long
+// as the file stays open.
+
+use anyhow::Result;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The averages from a pressure file, in percent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pressure {
+    pub some_avg10: f32,
+    pub full_avg10: f32,
+}
+
+impl Pressure {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut pressure = Pressure::default();
+
+        // e.g. "some avg10=0.12 avg60=0.05 avg300=0.01 total=123456"
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let kind = fields.next();
+            let avg10 = fields
+                .find_map(|f| f.strip_prefix("avg10="))
+                .and_then(|v| v.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            match kind {
+                Some("some") => pressure.some_avg10 = avg10,
+                Some("full") => pressure.full_avg10 = avg10,
+                _ => {}
+            }
+        }
+
+        Ok(pressure)
+    }
+}
+
+pub struct PsiTrigger {
+    path: PathBuf,
+    file: File,
+}
+
+impl PsiTrigger {
+    /// kind is either "some" or "full"
+    pub fn new(path: &Path, kind: &str, stall: Duration, window: Duration) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let trigger = format!("{} {} {}", kind, stall.as_micros(), window.as_micros());
+        if let Err(e) = file.write_all(trigger.as_bytes()) {
+            bail!("Failed to set PSI trigger '{}' on {}: {}", trigger, path.display(), e);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check if the trigger fired since the last call, doesn't block
+    pub fn fired(&self) -> Result<bool> {
+        let mut fds = [PollFd::new(self.file.as_raw_fd(), PollFlags::POLLPRI)];
+        if poll(&mut fds, 0)? == 0 {
+            return Ok(false);
+        }
+
+        match fds[0].revents() {
+            // The cgroup was removed
+            Some(revents) if revents.contains(PollFlags::POLLERR) => {
+                bail!("PSI trigger on {} went away", self.path.display())
+            }
+            Some(revents) => Ok(revents.contains(PollFlags::POLLPRI)),
+            None => Ok(false),
+        }
+    }
+
+    /// The current averages for the file this trigger is on
+    pub fn pressure(&self) -> Result<Pressure> {
+        Pressure::read(&self.path)
+    }
+}
@@ -0,0 +1,82 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Interface to /sys/class/thermal, see
+// Documentation/driver-api/thermal/sysfs-api.rst
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const THERMAL_PATH: &str = "/sys/class/thermal";
+
+fn read(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct Trip {
+    /// "passive", "active", "hot" or "critical"
+    pub kind: String,
+    /// Degrees C
+    pub temp: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalZone {
+    path: PathBuf,
+    /// The zone's type, e.g. "cpu0-thermal" or "cpuss-0"
+    pub kind: String,
+}
+
+impl ThermalZone {
+    /// Every thermal zone the kernel knows about
+    pub fn all() -> Result<Vec<Self>> {
+        let mut zones = Vec::new();
+        for entry in fs::read_dir(THERMAL_PATH)? {
+            let path = entry?.path();
+            if !path.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.starts_with("thermal_zone")) {
+                continue;
+            }
+            let kind = read(&path.join("type"))?;
+            zones.push(Self { path, kind });
+        }
+        zones.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(zones)
+    }
+
+    /// Current temperature in degrees C, fails if the zone is disabled
+    pub fn temp(&self) -> Result<f32> {
+        let millicelsius: i64 = read(&self.path.join("temp"))?.parse()?;
+        Ok(millicelsius as f32 / 1000.0)
+    }
+
+    pub fn trips(&self) -> Vec<Trip> {
+        (0..)
+            .map_while(|i| {
+                let temp: i64 = read(&self.path.join(format!("trip_point_{}_temp", i))).ok()?.parse().ok()?;
+                let kind = read(&self.path.join(format!("trip_point_{}_type", i))).ok()?;
+                Some(Trip {
+                    kind,
+                    temp: temp as f32 / 1000.0,
+                })
+            })
+            .collect()
+    }
+}
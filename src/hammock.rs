@@ -17,38 +17,503 @@
 * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 */
 
+use std::collections::HashMap;
 use std::net::{UdpSocket, SocketAddr};
 use std::os::unix::thread;
 use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::accounting::Accounting;
 use crate::app_track::{TopLevelState, AppTrack};
 use crate::application::{App, AppFilter};
+use crate::autosleep::Autosleep;
+use crate::budget::CpuBudgets;
+use crate::busy::BusyDetector;
 use crate::cgroups::CGHandler;
-use crate::config::Rule;
+use crate::config::{Config, Event, FreezeMode, Rule};
+use crate::dbus::server::Server;
 use crate::events::{HammockEvent, HammockEventSource};
-use crate::match_rules::MatchRules;
+use crate::freezer::FreezeTracker;
+use crate::keepawake::KeepAwake;
+use crate::lowmem::LowMemoryKiller;
+use crate::network::NetworkPolicy;
+use crate::profiles::PowerProfiles;
+use crate::reload;
+use crate::reclaim::Reclaimer;
+use crate::suspend::Suspend;
+use crate::thermal::ThermalPolicy;
+use crate::match_rules::{MatchRule, MatchRules};
 use crate::hal::{Hal, Backlight, Wakeup, WakeupType};
 use anyhow::Result;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+
+const OOM_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+const CPU_HOTPLUG_INTERVAL: Duration = Duration::from_secs(2);
 
 pub struct Hammock {
-    pub rules: MatchRules,
+    /// Swapped out when the power profile changes or the config is
+    /// reloaded
+    rules: RwLock<Arc<MatchRules>>,
+    config: Arc<RwLock<Config>>,
     pub handler: CGHandler,
     pub hal: Hal,
     apps: Mutex<Vec<App>>,
+    /// None if autosleep is disabled
+    autosleep: Mutex<Option<Autosleep>>,
+    keepawake: Arc<Mutex<KeepAwake>>,
+    freezer: Arc<Mutex<FreezeTracker>>,
+    suspend: Mutex<Suspend>,
+    lowmem: Mutex<LowMemoryKiller>,
+    reclaimer: Arc<Mutex<Reclaimer>>,
+    next_oom_sync: Mutex<Instant>,
+    next_cpu_check: Mutex<Instant>,
+    /// Apps with an activation boost and when it ends, by cgroup
+    boosts: Mutex<HashMap<String, Instant>>,
+    thermal: Mutex<ThermalPolicy>,
+    network: Mutex<NetworkPolicy>,
+    budgets: Arc<Mutex<CpuBudgets>>,
+    busy: Mutex<BusyDetector>,
+    accounting: Arc<Mutex<Accounting>>,
+    profiles: Arc<Mutex<PowerProfiles>>,
 }
 
 impl Hammock {
-    pub fn new(rules: MatchRules, handler: CGHandler) -> Self {
-        Self {
-            rules,
+    pub fn new(config: Config, rules: MatchRules, handler: CGHandler) -> Result<Self> {
+        let lowmem = LowMemoryKiller::new(config.low_memory()?, &handler);
+        let network = NetworkPolicy::new(config.network_policy()?, &handler.cgroup_path(""));
+        let profiles = PowerProfiles::new(config.power_profiles()?, config.low_battery_threshold());
+        Ok(Self {
+            rules: RwLock::new(Arc::new(rules)),
             handler,
             hal: Hal::new(),
             apps: Mutex::new(Vec::new()),
+            autosleep: Mutex::new(Autosleep::new(config.autosleep())?),
+            keepawake: Arc::new(Mutex::new(KeepAwake::new(config.keep_awake()))),
+            freezer: Arc::new(Mutex::new(FreezeTracker::new(config.freezer()))),
+            suspend: Mutex::new(Suspend::new(config.suspend())),
+            lowmem: Mutex::new(lowmem),
+            reclaimer: Arc::new(Mutex::new(Reclaimer::new(config.reclaim()))),
+            next_oom_sync: Mutex::new(Instant::now()),
+            next_cpu_check: Mutex::new(Instant::now()),
+            boosts: Mutex::new(HashMap::new()),
+            thermal: Mutex::new(ThermalPolicy::new(config.thermal()?)),
+            network: Mutex::new(network),
+            budgets: Arc::new(Mutex::new(CpuBudgets::new())),
+            busy: Mutex::new(BusyDetector::new(config.busy())),
+            accounting: Arc::new(Mutex::new(Accounting::new())),
+            profiles: Arc::new(Mutex::new(profiles)),
+            config: Arc::new(RwLock::new(config)),
+        })
+    }
+
+    /// The rules currently in effect
+    pub fn rules(&self) -> Arc<MatchRules> {
+        self.rules.read().clone()
+    }
+
+    /// Periodic work that isn't triggered by an event,
+    /// called once per event loop iteration
+    fn tick(&self) -> Result<()> {
+        let released = {
+            let mut keepawake = self.keepawake.lock();
+            keepawake.expire();
+            if let Some(autosleep) = self.autosleep.lock().as_mut() {
+                if let Err(e) = autosleep.process(&keepawake) {
+                    warn!("Autosleep: {}", e);
+                }
+            }
+            keepawake.take_released()
+        };
+
+        self.process_thaw_queue();
+        self.update_profile();
+
+        self.busy.lock().process(&self.handler, &self.apps.lock());
+        self.evaluate_rules(None);
+
+        let closing = self.lowmem.lock().process(&self.handler, &self.apps.lock(), &self.keepawake.lock());
+        match closing {
+            Ok(Some(cgroup)) => self.refresh_freezer(&cgroup),
+            Ok(None) => {}
+            Err(e) => warn!("Low memory killer: {}", e),
+        }
+
+        self.reclaimer.lock().process(&self.handler, &self.hal, &self.apps.lock());
+        self.sync_oom_score_adj();
+        self.sync_cpusets();
+        self.expire_boosts();
+        self.update_thermal();
+        self.network.lock().process(&self.apps.lock(), &self.rules());
+        self.accounting.lock().process(&self.handler, &self.hal, &self.apps.lock());
+
+        let changed = self.budgets.lock().process(&self.handler, &self.apps.lock(), &self.rules());
+        for cgroup in changed {
+            self.refresh_freezer(&cgroup);
+        }
+
+        // Don't leave apps that wouldn't freeze half frozen,
+        // they're throttled instead
+        let failed = self.freezer.lock().process(&self.handler);
+        for cgroup in failed {
+            self.refresh_freezer(&cgroup);
+        }
+
+        // Apps may have been kept thawed by a hold that is now gone
+        for cgroup in released.iter().filter_map(|h| h.cgroup.as_ref()) {
+            self.refresh_freezer(cgroup);
+        }
+
+        Ok(())
+    }
+
+    /// update_freezer() for the app in a cgroup. Errors are only
+    /// logged, most likely the app just exited and either way one app
+    /// shouldn't take the rest down with it.
+    fn refresh_freezer(&self, cgroup: &str) {
+        for app in self.apps.lock().iter().filter(|app| app.cgroup_name() == cgroup) {
+            if let Err(e) = self.update_freezer(app) {
+                warn!("{}: failed to update freezer state: {}", cgroup, e);
+            }
+        }
+    }
+
+    fn resume(&self, app_track: &AppTrack) -> Result<()> {
+        self.suspend.lock().resume(&self.handler, &self.apps.lock())?;
+        // Get the last foreground app going straight away
+        self.process_thaw_queue();
+        app_track.handle_suspend(false)
+    }
+
+    /// Restore the freezer state of apps that are due after resume
+    fn process_thaw_queue(&self) {
+        loop {
+            let cgroup = match self.suspend.lock().next_thaw() {
+                Some(cgroup) => cgroup,
+                None => return,
+            };
+            for app in self.apps.lock().iter().filter(|app| app.cgroup_name() == cgroup) {
+                match self.update_freezer(app) {
+                    Ok(frozen) => trace!("Resume: {} {}", cgroup, if frozen { "stays frozen" } else { "thawed" }),
+                    Err(e) => warn!("Resume: failed to restore {}: {}", cgroup, e),
+                }
+            }
+        }
+    }
+
+    /// Switch power profile if the one we want changed, rebuilding the
+    /// rules and applying them to every app
+    fn update_profile(&self) {
+        let became_low = self.profiles.lock().check_battery(&self.hal);
+        if became_low {
+            self.evaluate_rules(Some(&Event::LowBattery));
+        }
+
+        let (wanted, source) = {
+            let profiles = self.profiles.lock();
+            match profiles.wanted() {
+                (wanted, _) if wanted.as_deref() == profiles.active() => return,
+                wanted => wanted,
+            }
+        };
+
+        let rules = match self.config.read().profile_rules(wanted.as_deref()) {
+            Ok(rules) => MatchRules(rules),
+            Err(e) => {
+                // Validated at startup, so this shouldn't happen
+                error!("Failed to apply power profile {:?}: {}", wanted, e);
+                self.profiles.lock().set_active(wanted);
+                return;
+            }
+        };
+        info!("Power profile: {} ({})", wanted.as_deref().unwrap_or("default"), source);
+        self.profiles.lock().set_active(wanted);
+        self.swap_rules(rules);
+    }
+
+    /// Load the config file again and apply what can be applied without
+    /// a restart. If the new config is invalid the current one is kept.
+    /// Returns what changed.
+    fn reload_config(&self) -> Result<Vec<String>> {
+        // Build and check everything before touching any state so
+        // that a bad config leaves the current one fully in place
+        let new = Config::load(self.config.read().requested())?;
+        new.validate_memory()?;
+        new.validate_cpus()?;
+
+        let base = new.parse_rules()?;
+        let profiles = new.power_profiles()?;
+        let mut profile_rules = HashMap::new();
+        for name in profiles.profiles.keys() {
+            profile_rules.insert(name.clone(), new.profile_rules(Some(name))?);
+        }
+        let cgroups = base.iter().chain(profile_rules.values().flatten()).map(|r| r.cgroup());
+        let io_devices = self.handler.lookup_io_devices(cgroups)?;
+        // Profiles only overlay existing rules so checking the base
+        // rules covers them too
+        for app in self.apps.lock().iter() {
+            let rule = app.info.read().match_rule;
+            if !base.iter().any(|r| r.name == rule) {
+                bail!("{} is in the {} rule which the new config doesn't have", app.cgroup_name(), rule);
+            }
+        }
+
+        let changes = self.config.read().diff(&new);
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+
+        // Nothing can fail from here on
+        self.handler.add_io_devices(io_devices);
+        let wanted = {
+            let mut profiles_state = self.profiles.lock();
+            profiles_state.set_config(profiles, new.low_battery_threshold());
+            let (wanted, _) = profiles_state.wanted();
+            profiles_state.set_active(wanted.clone());
+            wanted
+        };
+        let rules = match wanted.and_then(|name| profile_rules.remove(&name)) {
+            Some(rules) => rules,
+            None => base,
+        };
+        *self.config.write() = new;
+        self.swap_rules(MatchRules(rules));
+        self.evaluate_rules(None);
+
+        Ok(changes)
+    }
+
+    /// Replace the rules and re-apply them to every app
+    fn swap_rules(&self, rules: MatchRules) {
+        *self.rules.write() = Arc::new(rules);
+
+        let rules = self.rules();
+        for app in self.apps.lock().iter() {
+            let rule = app.info.read().match_rule;
+            let res = rules.get(rule).and_then(|rule| {
+                self.apply_limits(app, rule);
+                self.update_freezer(app)
+            });
+            if let Err(e) = res {
+                warn!("{}: failed to apply the new rules: {}", app.cgroup_name(), e);
+            }
+        }
+    }
+
+    /// Move apps that aren't focused on to the next rule their
+    /// conditions and enter-time allow
+    fn evaluate_rules(&self, event: Option<&Event>) {
+        let rules = self.rules();
+        for app in self.apps.lock().iter() {
+            let since = {
+                let info = app.info.read();
+                if info.focused {
+                    continue;
+                }
+                info.last_focused.map_or(info.rule_since, |t| t.max(info.rule_since))
+            };
+            if let Some(rule) = rules.next_rule(app, event, since) {
+                debug!("{} moving to {}", app.cgroup_name(), rule.name);
+                if let Err(e) = self.apply_rule(app, rule) {
+                    warn!("{}: failed to move to {}: {}", app.cgroup_name(), rule.name, e);
+                }
+            }
+        }
+    }
+
+    /// Move an app to a rule, applying the rule's cgroup limits if
+    /// it changed. Returns true if the app is frozen.
+    fn apply_rule(&self, app: &App, rule: &MatchRule) -> Result<bool> {
+        let changed = {
+            let mut info = app.info.write();
+            let changed = info.match_rule != rule.name;
+            if changed {
+                info.rule_since = Instant::now();
+            }
+            info.match_rule = rule.name;
+            changed
+        };
+
+        if changed {
+            self.apply_limits(app, rule);
+            self.boost(app, rule);
+        }
+
+        self.update_freezer(app)
+    }
+
+    /// Give an app that just entered a rule its activation boost
+    fn boost(&self, app: &App, rule: &MatchRule) {
+        let cgroup = app.cgroup_name();
+        let mut boosts = self.boosts.lock();
+        boosts.remove(&cgroup);
+
+        if let Some(boost) = &rule.cgroup().activation_boost {
+            trace!("Boosting {} to {}% for {}s", cgroup, boost.uclamp_min, boost.duration);
+            self.handler.set_uclamp(&cgroup, Some(boost.uclamp_min), rule.cgroup().uclamp_max);
+            boosts.insert(cgroup, Instant::now() + Duration::from_secs_f32(boost.duration));
+        }
+    }
+
+    /// Put apps back to their rule's uclamp once their boost is over
+    fn expire_boosts(&self) {
+        let now = Instant::now();
+        let expired: Vec<String> = {
+            let mut boosts = self.boosts.lock();
+            let expired = boosts.iter().filter(|(_, until)| now >= **until).map(|(cg, _)| cg.clone()).collect();
+            boosts.retain(|_, until| now < *until);
+            expired
+        };
+
+        for app in self.apps.lock().iter().filter(|app| expired.contains(&app.cgroup_name())) {
+            let rule = app.info.read().match_rule;
+            if let Ok(rule) = self.rules().get(rule) {
+                self.handler.set_uclamp(&app.cgroup_name(), rule.cgroup().uclamp_min, rule.cgroup().uclamp_max);
+            }
+        }
+    }
+
+    /// Apply the resource limits for a rule to an app
+    fn apply_limits(&self, app: &App, rule: &MatchRule) {
+        let cgroup = app.cgroup_name();
+        if let Err(e) = self.handler.apply_config(&cgroup, rule.cgroup()) {
+            warn!("Failed to apply {} limits to {}: {}", rule.name, cgroup, e);
+        }
+        if let Err(e) = self.handler.set_oom_score_adj(&cgroup, rule.oom_score_adj()) {
+            warn!("Failed to set oom_score_adj for {}: {}", cgroup, e);
+        }
+        self.thermal.lock().apply(&self.handler, &cgroup, rule);
+    }
+
+    /// Start tracking a new app, it starts off in the foreground
+    fn add_app(&self, app: App) -> Result<()> {
+        let rules = self.rules();
+        let rule = rules.get(Rule::Foreground)?;
+        self.apply_limits(&app, rule);
+        self.boost(&app, rule);
+        self.apps.lock().push(app);
+        Ok(())
+    }
+
+    /// Catch processes that were forked into an app before
+    /// its oom_score_adj was changed
+    fn sync_oom_score_adj(&self) {
+        let mut next = self.next_oom_sync.lock();
+        if Instant::now() < *next {
+            return;
+        }
+        *next = Instant::now() + OOM_SYNC_INTERVAL;
+
+        for app in self.apps.lock().iter() {
+            let rule = app.info.read().match_rule;
+            let value = match self.rules().get(rule) {
+                Ok(rule) => rule.oom_score_adj(),
+                Err(_) => continue,
+            };
+            match self.handler.set_oom_score_adj(&app.cgroup_name(), value) {
+                Ok(0) | Err(_) => {}
+                Ok(n) => debug!("Set oom_score_adj {} on {} new processes in {}", value, n, app.cgroup_name()),
+            }
+        }
+    }
+
+    /// Re-resolve cpusets when CPUs are hotplugged, so apps get the
+    /// CPUs back that they lost when they went offline
+    fn sync_cpusets(&self) {
+        let mut next = self.next_cpu_check.lock();
+        if Instant::now() < *next {
+            return;
+        }
+        *next = Instant::now() + CPU_HOTPLUG_INTERVAL;
+
+        if !self.handler.refresh_cpus() {
+            return;
+        }
+        for app in self.apps.lock().iter() {
+            let rule = app.info.read().match_rule;
+            if let Ok(rule) = self.rules().get(rule) {
+                let cgroup = app.cgroup_name();
+                self.handler.apply_cpuset(&cgroup, &rule.cgroup().cpuset);
+                self.thermal.lock().apply(&self.handler, &cgroup, rule);
+            }
         }
     }
 
+    /// Narrow or restore cpusets and CPU weights when the thermal
+    /// level changes, apps may also move rules on the hot event
+    fn update_thermal(&self) {
+        let event = match self.thermal.lock().process() {
+            Some(event) => event,
+            None => return,
+        };
+        info!("Thermal level {} -> {} at {:.1}°C", event.previous, event.level, event.temp);
+
+        for app in self.apps.lock().iter() {
+            let rule = app.info.read().match_rule;
+            if let Ok(rule) = self.rules().get(rule) {
+                let cgroup = app.cgroup_name();
+                // Start from the rule's own limits, then narrow them
+                self.handler.apply_cpuset(&cgroup, &rule.cgroup().cpuset);
+                self.handler.set_cpu_weight(&cgroup, rule.cgroup().cpu_weight());
+                self.thermal.lock().apply(&self.handler, &cgroup, rule);
+            }
+        }
+
+        if event.level > event.previous {
+            self.evaluate_rules(Some(&Event::Hot));
+        }
+    }
+
+    /// Freeze or thaw an app according to its current rule and CPU
+    /// budget, apps holding a keep-awake or being closed are never
+    /// frozen and apps that wouldn't freeze are throttled instead.
+    /// Returns true if the app is stopped in some way.
+    fn update_freezer(&self, app: &App) -> Result<bool> {
+        let cgroup = app.cgroup_name();
+        let (rule, prev) = {
+            let info = app.info.read();
+            (info.match_rule, info.freeze_mode)
+        };
+        // Left frozen after resume until process_thaw_queue() gets to it,
+        // unless the user switched to it in the meantime
+        if rule != Rule::Foreground && self.suspend.lock().is_thawing(&cgroup) {
+            return Ok(true);
+        }
+        let rules = self.rules();
+        let rule = rules.get(rule)?;
+        let mut freezer = self.freezer.lock();
+        if rule.name == Rule::Foreground {
+            freezer.forget(&cgroup);
+        }
+
+        let mut mode = rule.freeze_mode();
+        if self.keepawake.lock().holds(&cgroup) || self.lowmem.lock().is_closing(&cgroup) {
+            mode = FreezeMode::Off;
+        } else if let Some(action) = self.budgets.lock().exhausted(&cgroup, rule.name) {
+            // Out of CPU budget, don't let the rule's own mode be any softer
+            if mode == FreezeMode::Off || (mode == FreezeMode::Throttle && action != FreezeMode::Throttle) {
+                mode = action;
+            }
+        }
+        if mode == FreezeMode::Freeze && freezer.is_unfreezable(&cgroup) {
+            mode = FreezeMode::Throttle;
+        }
+
+        if mode != prev {
+            debug!("{}: {} -> {}", cgroup, prev, mode);
+        }
+        self.handler.set_freeze_mode(&cgroup, mode, prev, &rule.throttle())?;
+        app.info.write().freeze_mode = mode;
+
+        if mode == FreezeMode::Freeze {
+            freezer.start(&cgroup);
+        } else {
+            freezer.cancel(&cgroup);
+        }
+
+        Ok(mode != FreezeMode::Off)
+    }
+
     /// The main event loop, called every 200ms
     /// or when a new event is received
     fn handle_event(&self, app_track: &AppTrack, event: HammockEvent, dbg: &mut DbgSock) -> Result<()> {
@@ -58,9 +523,7 @@ impl Hammock {
             // We need to create a new cgroup for it ASAP and hope
             // we don't get screwed by PID race conditions (ie a fork)
             HammockEvent::NewApplication(app_info) => {
-                self.apps.lock().push(
-                    App::new(app_info.app_id(), app_info.pid(), &self.handler)?
-                );
+                self.add_app(App::new(app_info.app_id(), app_info.pid(), &self.handler)?)?;
                 dbg.send_app(&app_info.app_id().to_string(), true, 1);
                 Ok(())
             }
@@ -90,34 +553,41 @@ impl Hammock {
                 // FIXME: The PID may not be the one used to launch the cgroup
                 // Should instead look it up via /proc/$pid/cgroup
                 let cgroup = self.handler.load_cgroup(&format!("{}-{}", top_level.app_id, top_level.pid))?;
-                self.apps.lock().push(
-                    App::new_with_cgroup(top_level.app_id, top_level.pid, cgroup)
-                );
+                self.add_app(App::new_with_cgroup(top_level.app_id, top_level.pid, cgroup))?;
 
                 dbg.send_app(&app_id, true, 1);
                 Ok(())
             }
             HammockEvent::TopLevelChanged(top_level) => {
                 for app in self.apps.lock().iter() {
-                    let guard = app.info.read();
-                    if guard.app_id == top_level.app_id {
-                        let rule = match top_level.state {
-                            Some(TopLevelState::Activated) => self.rules.get(Rule::Foreground),
-                            _ => self.rules.get(Rule::Background),
-                        }.expect(&format!("CONFIG ERROR: No rule for toplevel {:?}", &top_level.state));
-                        debug!("{}:{} applying rule {}", guard.app_id, app.pid, rule.name);
-
-                        // HACK: For PoC just freeze/unfreeze app unconditionally
-                        match top_level.state {
-                            Some(TopLevelState::Activated) => {
-                                app.thaw()?;
-                                dbg.send_app(&guard.app_id.to_string(), true, 1);
-                            },
-                            _ => {
-                                app.freeze()?;
-                                dbg.send_app(&guard.app_id.to_string(), true, 0);
-                            },
-                        }
+                    if app.info.read().app_id == top_level.app_id {
+                        let focused = matches!(top_level.state, Some(TopLevelState::Activated));
+                        let changed = {
+                            let mut info = app.info.write();
+                            let changed = info.focused != focused;
+                            if changed {
+                                info.focused = focused;
+                                info.last_focused = Some(Instant::now());
+                            }
+                            changed
+                        };
+                        // Focused apps go straight to the foreground and apps that
+                        // lose focus straight to the background, evaluate_rules()
+                        // moves them on from there as enter-times pass
+                        let frozen = match (focused, changed) {
+                            (true, _) => {
+                                debug!("{}:{} applying rule {}", top_level.app_id, app.pid, Rule::Foreground);
+                                let rules = self.rules();
+                                self.apply_rule(app, rules.get(Rule::Foreground)?)?
+                            }
+                            (false, true) => {
+                                debug!("{}:{} applying rule {}", top_level.app_id, app.pid, Rule::Background);
+                                let rules = self.rules();
+                                self.apply_rule(app, rules.get(Rule::Background)?)?
+                            }
+                            (false, false) => app.info.read().freeze_mode != FreezeMode::Off,
+                        };
+                        dbg.send_app(&top_level.app_id.to_string(), true, if frozen { 0 } else { 1 });
 
                         return Ok(());
                     }
@@ -126,9 +596,7 @@ impl Hammock {
                 if top_level.pid > 0 {
                     debug!("TopLevelChanged: Assuming new toplevel?");
                     let cgroup = self.handler.load_cgroup(&format!("{}-{}", top_level.app_id, top_level.pid))?;
-                    self.apps.lock().push(
-                        App::new_with_cgroup(top_level.app_id, top_level.pid, cgroup)
-                    );
+                    self.add_app(App::new_with_cgroup(top_level.app_id, top_level.pid, cgroup))?;
                 }
                 //trace!("FIXME! Can't map existing TopLevel to PID!!!");
                 Ok(())
@@ -153,35 +621,43 @@ impl Hammock {
                 }
             }
             HammockEvent::SystemSuspend(active) => {
+                if let Some(autosleep) = self.autosleep.lock().as_mut() {
+                    autosleep.on_suspend(active);
+                }
                 match active {
                     true => {
                         dbg.send_wakeup("About to suspend!");
-                        std::thread::sleep(Duration::from_millis(400)); // HACK: Give the shell some time to turn the panel off etc...
-                        self.handler.freeze_all(true)?; // Freeze all of userspace so pesky GSD doesn't touch the display when we're coming back from suspend
-                        app_track.handle_suspend(true)?;
+                        self.evaluate_rules(Some(&Event::Sleep));
+                        self.suspend.lock().prepare(&self.handler, app_track)?;
                     },
                     false => if let Ok(cause) = self.hal.wakeup().get_cause() {
                         debug!("Woke up with cause: {}", cause);
                         dbg.send_wakeup(&cause.to_string());
                         match cause {
                             WakeupType::Modem => {}, // Async wait 5+ seconds for incoming call to show up, or go back to sleep
-                            WakeupType::Button => {
-                                self.handler.freeze_all(false)?;
-                                app_track.handle_suspend(false)?;
-                            },
+                            WakeupType::Button => self.resume(app_track)?,
                             WakeupType::Motion => { // FIXME: Should wait 3-5 seconds for user interaction and then go back to sleep
-                                self.handler.freeze_all(false)?;
-                                app_track.handle_suspend(false)?;
+                                self.resume(app_track)?;
                             }
-                            _ => {
-                                self.handler.freeze_all(false)?;
-                                app_track.handle_suspend(false)?;
-                            },
+                            _ => self.resume(app_track)?,
                         }
                     }
                 }
                 Ok(())
             }
+            HammockEvent::ReloadConfig(reply) => {
+                let result = self.reload_config();
+                match &result {
+                    Ok(changes) if changes.is_empty() => info!("Reloaded config, nothing changed"),
+                    Ok(changes) => info!("Reloaded config:\n{}", changes.join("\n")),
+                    Err(e) => error!("Failed to reload config, keeping the current one: {}", e),
+                }
+                if let Some(reply) = reply {
+                    // Whoever asked may have given up waiting
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                }
+                Ok(())
+            }
         }
     }
 
@@ -234,8 +710,21 @@ impl DbgSock {
 }
 
 pub fn event_loop(hammock: Hammock, xdg_runtime_dir: &str, wl_display: &str) -> Result<()> {
+    // SIGHUP was blocked at the start of main
     let (tx, rx) = channel::<HammockEvent>();
+    reload::watch(hammock.config.read().watch(), &tx)?;
     let mut app_track = AppTrack::new(xdg_runtime_dir, wl_display, &tx)?;
+    let _server = Server::new(
+        hammock.keepawake.clone(),
+        hammock.freezer.clone(),
+        hammock.reclaimer.clone(),
+        hammock.budgets.clone(),
+        hammock.accounting.clone(),
+        hammock.profiles.clone(),
+        hammock.config.clone(),
+        tx.clone(),
+    )?;
+    crate::dbus::power_profiles::watch(hammock.profiles.clone());
     let mut debug_sock = DbgSock {
         sock: UdpSocket::bind("172.16.42.1:4480")?,
     };
@@ -249,6 +738,7 @@ pub fn event_loop(hammock: Hammock, xdg_runtime_dir: &str, wl_display: &str) ->
             trace!("Received event: {}", event);
             hammock.handle_event(&app_track, event, &mut debug_sock)?;
         }
+        hammock.tick()?;
         //debug_sock.connect_poll();
         let elapsed = start.elapsed();
         // FIXME: need to poll()
@@ -0,0 +1,220 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Keep-awake holds (AKA wakelocks). Apps and services acquire these
+// over D-Bus to stop Hammock from suspending the device or freezing
+// the holder's cgroup. Every hold has a reason and a maximum duration
+// so a misbehaving app can't keep the device awake forever, and we
+// keep per-app accounting so that those apps can be identified.
+
+use crate::cgroups::CGHandler;
+use crate::config::KeepAwakeConfig;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum HoldScope {
+    /// Keep the whole device awake (blocks autosleep)
+    Device,
+    /// Only keep the holder's cgroup from being frozen
+    App,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hold {
+    pub id: u32,
+    /// Unique bus name of the holder
+    pub owner: String,
+    pub app_id: String,
+    /// The holder's cgroup relative to the hammock root, if it has one
+    pub cgroup: Option<String>,
+    pub scope: HoldScope,
+    pub reason: String,
+    pub acquired: Instant,
+    pub expires: Instant,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HoldStats {
+    pub acquired: u32,
+    /// How many holds hit their timeout rather than being released
+    pub expired: u32,
+    pub held_for: Duration,
+    pub longest: Duration,
+}
+
+pub struct KeepAwake {
+    config: KeepAwakeConfig,
+    holds: Vec<Hold>,
+    /// Holds that were dropped since the last call to take_released()
+    released: Vec<Hold>,
+    stats: HashMap<String, HoldStats>,
+    next_id: u32,
+}
+
+impl KeepAwake {
+    pub fn new(config: KeepAwakeConfig) -> Self {
+        Self {
+            config,
+            holds: Vec::new(),
+            released: Vec::new(),
+            stats: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn acquire(
+        &mut self,
+        owner: &str,
+        cgroup: Option<String>,
+        scope: HoldScope,
+        reason: &str,
+        timeout: u32,
+    ) -> Result<u32> {
+        if reason.is_empty() {
+            bail!("A reason is required");
+        }
+
+        let max = self.config.max_timeout;
+        let timeout = if timeout == 0 || timeout > max { max } else { timeout };
+        if self.holds.iter().filter(|h| h.owner == owner).count() >= self.config.max_per_client as usize {
+            bail!("Too many holds for {}", owner);
+        }
+
+        let app_id = match cgroup {
+            Some(ref cg) => CGHandler::app_id_of(cg),
+            None => owner.to_string(),
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        let now = Instant::now();
+        info!("{} ({}) acquired {} keep-awake #{} for {}s: {}", app_id, owner, scope, id, timeout, reason);
+        self.stats.entry(app_id.clone()).or_default().acquired += 1;
+        self.holds.push(Hold {
+            id,
+            owner: owner.to_string(),
+            app_id,
+            cgroup,
+            scope,
+            reason: reason.to_string(),
+            acquired: now,
+            expires: now + Duration::from_secs(timeout as u64),
+        });
+
+        Ok(id)
+    }
+
+    /// Release a hold, only the bus name that acquired it can do this
+    pub fn release(&mut self, owner: &str, id: u32) -> Result<()> {
+        match self.holds.iter().position(|h| h.id == id && h.owner == owner) {
+            Some(i) => {
+                let hold = self.holds.remove(i);
+                debug!("{} released keep-awake #{}", hold.app_id, hold.id);
+                self.account(&hold, false);
+                self.released.push(hold);
+                Ok(())
+            }
+            None => bail!("No keep-awake #{} held by {}", id, owner),
+        }
+    }
+
+    /// Drop all holds owned by a bus name that went away
+    pub fn release_owner(&mut self, owner: &str) {
+        let (released, holds): (Vec<Hold>, Vec<Hold>) = std::mem::take(&mut self.holds)
+            .into_iter()
+            .partition(|h| h.owner == owner);
+        self.holds = holds;
+        for hold in &released {
+            debug!("{} vanished, releasing keep-awake #{}", owner, hold.id);
+            self.account(hold, false);
+        }
+        self.released.extend(released);
+    }
+
+    /// Drop holds that have timed out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let (expired, holds): (Vec<Hold>, Vec<Hold>) = std::mem::take(&mut self.holds)
+            .into_iter()
+            .partition(|h| h.expires <= now);
+        self.holds = holds;
+        for hold in &expired {
+            warn!("Keep-awake #{} from {} expired after {}s: {}",
+                hold.id, hold.app_id, hold.expires.duration_since(hold.acquired).as_secs(), hold.reason);
+            self.account(hold, true);
+        }
+        self.released.extend(expired);
+    }
+
+    pub fn take_released(&mut self) -> Vec<Hold> {
+        std::mem::take(&mut self.released)
+    }
+
+    fn account(&mut self, hold: &Hold, expired: bool) {
+        let held = Instant::now().min(hold.expires).duration_since(hold.acquired);
+        let stats = self.stats.entry(hold.app_id.clone()).or_default();
+        stats.held_for += held;
+        stats.longest = stats.longest.max(held);
+        if expired {
+            stats.expired += 1;
+        }
+    }
+
+    /// Is anything keeping the whole device awake?
+    pub fn blocks_sleep(&self) -> Option<&Hold> {
+        self.holds.iter().find(|h| h.scope == HoldScope::Device)
+    }
+
+    /// Is the given cgroup being kept from freezing? Device holds
+    /// only block sleep, they don't keep the holder running.
+    pub fn holds(&self, cgroup: &str) -> bool {
+        self.holds.iter().any(|h| h.scope == HoldScope::App && h.cgroup.as_deref() == Some(cgroup))
+    }
+
+    pub fn list(&self) -> &[Hold] {
+        &self.holds
+    }
+
+    pub fn stats(&self) -> &HashMap<String, HoldStats> {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_by_scope() {
+        let mut keep_awake = KeepAwake::new(KeepAwakeConfig::default());
+        let app = keep_awake.acquire(":1.1", Some("foo-1".into()), HoldScope::App, "playing music", 0).unwrap();
+        keep_awake.acquire(":1.2", Some("bar-2".into()), HoldScope::Device, "downloading", 0).unwrap();
+
+        assert!(keep_awake.holds("foo-1"));
+        assert!(!keep_awake.holds("bar-2"));
+        assert_eq!(keep_awake.blocks_sleep().map(|h| h.app_id.as_str()), Some("bar"));
+
+        keep_awake.release(":1.1", app).unwrap();
+        assert!(!keep_awake.holds("foo-1"));
+    }
+}
@@ -24,12 +24,26 @@
 
 mod hal;
 
+pub mod accounting;
 pub mod app_track;
 pub mod application;
+pub mod autosleep;
+pub mod budget;
+pub mod busy;
 pub mod args;
 pub mod cgroups;
 pub mod config;
+pub mod energy;
 pub mod events;
+pub mod freezer;
 pub mod hammock;
+pub mod keepawake;
+pub mod lowmem;
 pub mod match_rules;
+pub mod network;
+pub mod profiles;
+pub mod reclaim;
+pub mod reload;
+pub mod suspend;
+pub mod thermal;
 pub mod dbus;
@@ -0,0 +1,263 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Low memory killer. When the kernel OOM killer runs out of options
+// it doesn't care if it kills the foreground app or the shell, so we
+// try to get in first. PSI triggers on the whole system, on our
+// cgroup root and on each app's cgroup tell us when tasks are stalling
+// on memory, at which point we pick the app the user is least likely
+// to miss (snoozed apps first, then background, then recents, longest
+// unfocused first) and ask it to exit. An app whose own cgroup is
+// stalling goes first if it's one we'd close at all. If it's still
+// around after the grace period the whole cgroup is killed.
+
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::LowMemoryConfig;
+use crate::hal::PsiTrigger;
+use crate::keepawake::KeepAwake;
+use anyhow::Result;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Backoff for retrying an app trigger that couldn't be added
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// An app cgroup we couldn't add a trigger for
+struct FailedTrigger {
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+/// An app we asked to exit
+struct Closing {
+    cgroup: String,
+    started: Instant,
+    killed: bool,
+}
+
+pub struct LowMemoryKiller {
+    config: LowMemoryConfig,
+    triggers: Vec<PsiTrigger>,
+    /// Triggers on each app's memory.pressure, by cgroup
+    app_triggers: HashMap<String, PsiTrigger>,
+    /// App cgroups whose trigger couldn't be added, retried with a backoff
+    failed: HashMap<String, FailedTrigger>,
+    closing: Option<Closing>,
+    /// When we last finished dealing with an app
+    last_kill: Option<Instant>,
+}
+
+impl LowMemoryKiller {
+    pub fn new(config: LowMemoryConfig, handler: &CGHandler) -> Self {
+        let mut triggers = Vec::new();
+        if config.enabled {
+            let stall = Duration::from_millis(config.stall_ms as u64);
+            let window = Duration::from_millis(config.window_ms as u64);
+            let root = handler.cgroup_path("").join("memory.pressure");
+            for path in [Path::new("/proc/pressure/memory"), root.as_path()] {
+                match PsiTrigger::new(path, &config.trigger, stall, window) {
+                    Ok(trigger) => triggers.push(trigger),
+                    Err(e) => warn!("Low memory killer: {}", e),
+                }
+            }
+            if triggers.is_empty() {
+                warn!("Low memory killer disabled, is the kernel built with CONFIG_PSI?");
+            }
+        }
+
+        Self {
+            config,
+            triggers,
+            app_triggers: HashMap::new(),
+            failed: HashMap::new(),
+            closing: None,
+            last_kill: None,
+        }
+    }
+
+    /// Whether the app is being closed, it mustn't be frozen or it
+    /// won't be able to handle SIGTERM
+    pub fn is_closing(&self, cgroup: &str) -> bool {
+        self.closing.as_ref().map_or(false, |c| c.cgroup == cgroup)
+    }
+
+    /// Check the PSI triggers and deal with any app we're closing.
+    /// Returns the cgroup of an app that was just asked to exit, it
+    /// needs to be thawed so it can handle SIGTERM.
+    pub fn process(&mut self, handler: &CGHandler, apps: &[App], keepawake: &KeepAwake) -> Result<Option<String>> {
+        self.sync_app_triggers(handler, apps);
+
+        // Check every trigger so none of them stay pending
+        let mut fired = Vec::new();
+        self.triggers.retain(|trigger| match trigger.fired() {
+            Ok(true) => {
+                fired.push(trigger.path().display().to_string());
+                true
+            }
+            Ok(false) => true,
+            Err(e) => {
+                warn!("Low memory killer: {}", e);
+                false
+            }
+        });
+
+        let mut stalled = Vec::new();
+        self.app_triggers.retain(|cgroup, trigger| match trigger.fired() {
+            Ok(true) => {
+                fired.push(cgroup.clone());
+                stalled.push(cgroup.clone());
+                true
+            }
+            Ok(false) => true,
+            // Most likely the app exited
+            Err(e) => {
+                debug!("Low memory killer: {}", e);
+                false
+            }
+        });
+
+        self.process_closing(handler);
+
+        if fired.is_empty() || self.closing.is_some() {
+            return Ok(None);
+        }
+
+        let cooldown = Duration::from_secs_f32(self.config.cooldown);
+        if self.last_kill.map_or(false, |t| t.elapsed() < cooldown) {
+            return Ok(None);
+        }
+
+        let pressure = self.triggers.first().and_then(|t| t.pressure().ok()).unwrap_or_default();
+        let victim = match self.victim(apps, keepawake, &stalled) {
+            Some(app) => app,
+            None => {
+                warn!("Memory pressure on {} (some {:.1}%, full {:.1}%) but no apps we can kill",
+                    fired.join(", "), pressure.some_avg10, pressure.full_avg10);
+                return Ok(None);
+            }
+        };
+        let cgroup = victim.cgroup_name();
+        let app = victim.info.read().app_id.to_string();
+
+        info!("Memory pressure on {} (some {:.1}%, full {:.1}%), closing {}",
+            fired.join(", "), pressure.some_avg10, pressure.full_avg10, app);
+
+        // The signal is handled once the caller stops freezing the app
+        for pid in handler.procs(&cgroup)? {
+            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+        }
+        self.closing = Some(Closing {
+            cgroup: cgroup.clone(),
+            started: Instant::now(),
+            killed: false,
+        });
+
+        Ok(Some(cgroup))
+    }
+
+    fn process_closing(&mut self, handler: &CGHandler) {
+        let closing = match self.closing.as_mut() {
+            Some(closing) => closing,
+            None => return,
+        };
+
+        // An error means the cgroup is already gone
+        if !handler.populated(&closing.cgroup).unwrap_or(false) {
+            debug!("{} exited after {}ms{}", closing.cgroup, closing.started.elapsed().as_millis(),
+                if closing.killed { " (killed)" } else { "" });
+            self.closing = None;
+            self.last_kill = Some(Instant::now());
+            return;
+        }
+
+        let grace = Duration::from_secs_f32(self.config.grace_period);
+        if !closing.killed && closing.started.elapsed() >= grace {
+            warn!("{} didn't exit within {}s, killing it", closing.cgroup, self.config.grace_period);
+            if let Err(e) = handler.kill(&closing.cgroup) {
+                warn!("Failed to kill {}: {}", closing.cgroup, e);
+            }
+            closing.killed = true;
+        }
+    }
+
+    /// Add triggers for new apps and drop those of apps that are gone
+    fn sync_app_triggers(&mut self, handler: &CGHandler, apps: &[App]) {
+        // No PSI (or it's disabled), don't keep trying every tick
+        if self.triggers.is_empty() {
+            return;
+        }
+        let cgroups: Vec<String> = apps.iter().map(|app| app.cgroup_name()).collect();
+        self.app_triggers.retain(|cgroup, _| cgroups.contains(cgroup));
+        self.failed.retain(|cgroup, _| cgroups.contains(cgroup));
+
+        let now = Instant::now();
+        let stall = Duration::from_millis(self.config.stall_ms as u64);
+        let window = Duration::from_millis(self.config.window_ms as u64);
+        for cgroup in cgroups {
+            if self.app_triggers.contains_key(&cgroup)
+                || self.failed.get(&cgroup).map_or(false, |f| now < f.retry_at)
+            {
+                continue;
+            }
+            let path = handler.cgroup_path(&cgroup).join("memory.pressure");
+            match PsiTrigger::new(&path, &self.config.trigger, stall, window) {
+                Ok(trigger) => {
+                    self.failed.remove(&cgroup);
+                    self.app_triggers.insert(cgroup, trigger);
+                }
+                Err(e) => {
+                    if self.failed.contains_key(&cgroup) {
+                        debug!("Low memory killer: {}", e);
+                    } else {
+                        warn!("Low memory killer: {}, retrying with a backoff", e);
+                    }
+                    let failed = self.failed.entry(cgroup).or_insert(FailedTrigger { retry_at: now, backoff: MIN_RETRY });
+                    failed.retry_at = now + failed.backoff;
+                    failed.backoff = (failed.backoff * 2).min(MAX_RETRY);
+                }
+            }
+        }
+    }
+
+    /// The app the user would miss least, apps that are stalling on
+    /// memory themselves go first
+    fn victim<'a>(&self, apps: &'a [App], keepawake: &KeepAwake, stalled: &[String]) -> Option<&'a App> {
+        apps.iter()
+            .filter_map(|app| {
+                let cgroup = app.cgroup_name();
+                let info = app.info.read();
+                let order = self.config.kill_order.iter().position(|r| *r == info.match_rule)?;
+                if self.config.allowlist.iter().any(|id| info.app_id.to_string() == *id)
+                    || info.tags.iter().any(|t| self.config.protect_tags.contains(t))
+                    || keepawake.holds(&cgroup)
+                {
+                    return None;
+                }
+                // Never focused sorts first
+                Some((!stalled.contains(&cgroup), order, info.last_focused, app))
+            })
+            .min_by_key(|(calm, order, last_focused, _)| (*calm, *order, *last_focused))
+            .map(|(_, _, _, app)| app)
+    }
+}
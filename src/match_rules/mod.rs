@@ -18,9 +18,11 @@
 */
 
 use anyhow::{anyhow, Result};
-use crate::config::{Conditional, Rule, RuleEnterTime, CgroupConfig};
+use crate::application::App;
+use crate::config::{Conditional, Event, Rule, RuleEnterTime, CgroupConfig, FreezeMode, ThrottleConfig};
 use cgroups_rs::{Cgroup, CgroupPid};
 use std::string::ToString;
+use std::time::Instant;
 use std::{fmt, ops};
 
 pub struct MatchConditions {
@@ -30,6 +32,12 @@ pub struct MatchConditions {
 }
 
 impl MatchConditions {
+    /// Whether the only-from and never-from conditions let the app in
+    pub fn allows(&self, app: &App, event: Option<&Event>) -> bool {
+        self.only_from.as_ref().map_or(true, |c| c.evaluate(app, event))
+            && !self.never_from.as_ref().map_or(false, |c| c.evaluate(app, event))
+    }
+
     pub fn new(
         only_from: Option<Conditional>,
         never_from: Option<Conditional>,
@@ -47,16 +55,38 @@ pub struct MatchRule {
     pub name: Rule,
     conditions: MatchConditions,
     cgroup: CgroupConfig,
+    oom_score_adj: i16,
 }
 
 impl MatchRule {
-    pub fn new(name: Rule, conditions: MatchConditions, cgroup: CgroupConfig) -> Self {
+    pub fn new(name: Rule, conditions: MatchConditions, cgroup: CgroupConfig, oom_score_adj: i16) -> Self {
         Self {
             name,
             conditions,
             cgroup,
+            oom_score_adj,
+        }
+    }
+
+    pub fn cgroup(&self) -> &CgroupConfig {
+        &self.cgroup
+    }
+
+    pub fn freeze_mode(&self) -> FreezeMode {
+        match (self.cgroup.freeze_mode, self.name) {
+            (Some(mode), _) => mode,
+            (None, Rule::Foreground) => FreezeMode::Off,
+            (None, _) => FreezeMode::Freeze,
         }
     }
+
+    pub fn throttle(&self) -> ThrottleConfig {
+        self.cgroup.throttle.clone().unwrap_or_default()
+    }
+
+    pub fn oom_score_adj(&self) -> i16 {
+        self.oom_score_adj
+    }
 }
 
 // Annoying stuff to make it easy to display stuff
@@ -90,4 +120,23 @@ impl MatchRules {
             .find(|r| r.name == rule)
             .ok_or_else(|| anyhow!("No rule named {}", rule))
     }
+
+    /// The rule an app that isn't focused should move to, if any.
+    /// A rule is a candidate once its only-from/never-from conditions
+    /// allow the app and it has been in its current state for the
+    /// rule's enter-time, the quickest one to enter wins. Focus is
+    /// handled separately since the foreground rule applies right away.
+    pub fn next_rule(&self, app: &App, event: Option<&Event>, since: Instant) -> Option<&MatchRule> {
+        let current = app.info.read().match_rule;
+        let waited = since.elapsed().as_secs_f32();
+
+        self.iter()
+            .filter(|rule| rule.name != current && rule.name != Rule::Foreground)
+            .filter(|rule| rule.conditions.allows(app, event))
+            .map(|rule| (rule, rule.conditions.enter_time.for_app(app, event)))
+            .filter(|(_, enter_time)| waited >= *enter_time)
+            // min_by picks the first of equal elements, so config order breaks ties
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(rule, _)| rule)
+    }
 }
@@ -0,0 +1,486 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Per-rule network policy. nftables can match packets by the cgroup
+// of the socket they belong to ("socket cgroupv2"), so we keep an
+// "inet hammock" table with a rule per app that:
+//
+// * drops everything for apps in a rule that blocks the network
+// * for capped apps, tags outgoing packets with the priority of a
+//   tc HTB class on the interfaces the default routes go over, and
+//   polices incoming packets (we can't shape what we've already
+//   received, dropping makes TCP back off).
+//
+// The whole table is replaced in one go whenever the apps, their
+// rules or the metered state change, nft applies it atomically.
+// Policies marked metered-only are dropped when no default route
+// (IPv4 or IPv6) goes over a metered interface.
+//
+// Cgroups are matched by id rather than path, nft looks paths up
+// under /sys/fs/cgroup which isn't where cgroup2 is mounted on
+// hybrid setups. nft and tc run on a worker thread so a slow or stuck
+// one doesn't hold up the event loop, if applying fails it's retried
+// with a backoff.
+
+use crate::application::App;
+use crate::config::{NetworkConfig, NetworkPolicyConfig, Rule};
+use crate::match_rules::MatchRules;
+use anyhow::Result;
+use glob::Pattern;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Packets that aren't ours go here
+const DEFAULT_CLASS: &str = "1:1";
+/// Kill nft or tc if they take longer than this
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// /proc/net/ipv6_route flags
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+#[derive(PartialEq, Clone)]
+struct Applied {
+    cgroup: String,
+    rule: Rule,
+    config: NetworkConfig,
+}
+
+/// A command for the worker thread
+struct Cmd {
+    program: &'static str,
+    args: Vec<String>,
+    stdin: Option<String>,
+    /// Carry on if it fails, e.g. removing something that's gone
+    may_fail: bool,
+}
+
+impl Cmd {
+    fn new(program: &'static str, args: &[&str]) -> Self {
+        Self {
+            program,
+            args: args.iter().map(|a| a.to_string()).collect(),
+            stdin: None,
+            may_fail: false,
+        }
+    }
+
+    fn may_fail(mut self) -> Self {
+        self.may_fail = true;
+        self
+    }
+}
+
+/// What we've asked the worker to apply, recorded once it succeeds
+struct Pending {
+    wanted: Vec<Applied>,
+    shaped: Vec<String>,
+}
+
+pub struct NetworkPolicy {
+    config: NetworkPolicyConfig,
+    metered: Vec<Pattern>,
+    next_check: Instant,
+    /// Where app cgroups live and where cgroup2 is mounted
+    cgroup_root: PathBuf,
+    cgroup_mount: Option<PathBuf>,
+    /// The interfaces the IPv4 and IPv6 default routes go over
+    interfaces: Vec<String>,
+    applied: Vec<Applied>,
+    /// The interfaces we set up HTB on
+    shaped: Vec<String>,
+    worker: Option<(Sender<Vec<Cmd>>, Receiver<Result<()>>)>,
+    pending: Option<Pending>,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+impl NetworkPolicy {
+    pub fn new(config: NetworkPolicyConfig, cgroup_root: &Path) -> Self {
+        let metered = config.metered_interfaces.iter().filter_map(|p| Pattern::new(p).ok()).collect();
+        let cgroup_mount = cgroup2_mount().unwrap_or_else(|e| {
+            warn!("Network policy: couldn't find the cgroup2 mount: {}", e);
+            None
+        });
+
+        let mut policy = Self {
+            config,
+            metered,
+            next_check: Instant::now(),
+            cgroup_root: cgroup_root.to_path_buf(),
+            cgroup_mount,
+            interfaces: Vec::new(),
+            applied: Vec::new(),
+            shaped: Vec::new(),
+            worker: None,
+            pending: None,
+            retry_at: Instant::now(),
+            backoff: MIN_BACKOFF,
+        };
+
+        if policy.config.enabled {
+            policy.worker = Some(spawn_worker());
+            // Left over if we didn't exit cleanly
+            let cleanup = Cmd::new("nft", &["delete", "table", "inet", "hammock"]).may_fail();
+            policy.send(vec![cleanup], Vec::new(), Vec::new());
+        }
+
+        policy
+    }
+
+    fn is_metered(&self) -> bool {
+        self.interfaces.iter().any(|iface| self.metered.iter().any(|p| p.matches(iface)))
+    }
+
+    /// Bring the nftables and tc state in line with the apps' rules
+    pub fn process(&mut self, apps: &[App], rules: &MatchRules) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        if !self.check_worker(now) {
+            return;
+        }
+
+        if now >= self.next_check {
+            self.next_check = now + Duration::from_secs(self.config.check_interval as u64);
+            let interfaces = default_route_interfaces().unwrap_or_else(|e| {
+                debug!("Network policy: couldn't read the routing table: {}", e);
+                Vec::new()
+            });
+            if interfaces != self.interfaces {
+                info!("Default routes are now over {}", match interfaces.is_empty() {
+                    true => "nothing".into(),
+                    false => interfaces.join(", "),
+                });
+                self.interfaces = interfaces;
+                // Reapply so shaping moves over to the new interfaces
+                self.applied.clear();
+            }
+        }
+
+        let metered = self.is_metered();
+        let wanted: Vec<Applied> = apps
+            .iter()
+            .filter_map(|app| {
+                let rule = app.info.read().match_rule;
+                let config = rules.get(rule).ok()?.cgroup().network.clone()?;
+                if config.metered_only && !metered {
+                    return None;
+                }
+                Some(Applied {
+                    cgroup: app.cgroup_name(),
+                    rule,
+                    config,
+                })
+            })
+            .collect();
+
+        if wanted == self.applied || now < self.retry_at {
+            return;
+        }
+
+        match self.plan(&wanted, rules) {
+            Ok((commands, shaped)) => self.send(commands, wanted, shaped),
+            Err(e) => {
+                warn!("Failed to apply network policy: {}", e);
+                self.back_off(now);
+            }
+        }
+    }
+
+    /// Pick up the result of the last batch, returns false if it's
+    /// still running
+    fn check_worker(&mut self, now: Instant) -> bool {
+        let result = match &self.worker {
+            Some((_, results)) => match results.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return self.pending.is_none(),
+                Err(TryRecvError::Disconnected) => Err(anyhow!("The worker thread went away")),
+            },
+            None => return false,
+        };
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return true,
+        };
+
+        // Even if it failed some of the shaping may have been set up,
+        // make sure it's torn down next time if it's not wanted
+        self.shaped = pending.shaped;
+        match result {
+            Ok(_) => {
+                debug!("Network policy: {} apps restricted", pending.wanted.len());
+                self.applied = pending.wanted;
+                self.backoff = MIN_BACKOFF;
+            }
+            Err(e) => {
+                warn!("Failed to apply network policy, retrying in {}s: {}", self.backoff.as_secs(), e);
+                self.back_off(now);
+            }
+        }
+        true
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.retry_at = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    fn send(&mut self, commands: Vec<Cmd>, wanted: Vec<Applied>, shaped: Vec<String>) {
+        if let Some((jobs, _)) = &self.worker {
+            if jobs.send(commands).is_ok() {
+                self.pending = Some(Pending { wanted, shaped });
+            }
+        }
+    }
+
+    /// The commands that take us from what's applied to wanted, and
+    /// the interfaces that will be shaped afterwards
+    fn plan(&self, wanted: &[Applied], rules: &MatchRules) -> Result<(Vec<Cmd>, Vec<String>)> {
+        let capped = wanted.iter().any(|a| a.config.bandwidth.is_some() && !a.config.block);
+        let shaped = match capped {
+            true => self.interfaces.clone(),
+            false => Vec::new(),
+        };
+
+        let mut commands = Vec::new();
+        for iface in self.shaped.iter().filter(|iface| !shaped.contains(iface)) {
+            info!("Removing traffic shaping from {}", iface);
+            // The interface may have gone away
+            commands.push(Cmd::new("tc", &["qdisc", "del", "dev", iface, "root"]).may_fail());
+        }
+        for iface in shaped.iter().filter(|iface| !self.shaped.contains(iface)) {
+            info!("Setting up traffic shaping on {}", iface);
+            commands.extend(shaping(iface, rules));
+        }
+
+        // Recreate the table from scratch, declaring it first means
+        // the delete doesn't fail if it doesn't exist yet
+        let mut script = String::from("table inet hammock\ndelete table inet hammock\n");
+        if !wanted.is_empty() {
+            let mut output = String::new();
+            let mut input = String::new();
+            for app in wanted {
+                let matches = match self.cgroup_match(&app.cgroup) {
+                    Ok(matches) => matches,
+                    // Most likely the app just exited
+                    Err(e) => {
+                        debug!("Network policy: skipping {}: {}", app.cgroup, e);
+                        continue;
+                    }
+                };
+                if app.config.block {
+                    writeln!(output, "    {} drop", matches)?;
+                    writeln!(input, "    {} drop", matches)?;
+                } else if let Some(kbps) = app.config.bandwidth {
+                    if !shaped.is_empty() {
+                        writeln!(output, "    {} meta priority set {}", matches, class_id(app.rule))?;
+                    }
+                    writeln!(input, "    {} limit rate over {} bytes/second drop", matches, kbps as u64 * 125)?;
+                }
+            }
+            writeln!(script, "table inet hammock {{")?;
+            writeln!(script, "  chain output {{\n    type filter hook output priority 0; policy accept;\n{}  }}", output)?;
+            writeln!(script, "  chain input {{\n    type filter hook input priority 0; policy accept;\n{}  }}", input)?;
+            writeln!(script, "}}")?;
+        }
+
+        trace!("nft script:\n{}", script);
+        let mut nft = Cmd::new("nft", &["-f", "-"]);
+        nft.stdin = Some(script);
+        commands.push(nft);
+
+        Ok((commands, shaped))
+    }
+
+    /// Matches sockets in the app's cgroup. The level is how deep the
+    /// cgroup is in the cgroup2 hierarchy and the id is its inode.
+    fn cgroup_match(&self, cgroup: &str) -> Result<String> {
+        let mount = match &self.cgroup_mount {
+            Some(mount) => mount,
+            None => bail!("cgroup2 isn't mounted"),
+        };
+        let path = self.cgroup_root.join(cgroup);
+        let level = path.strip_prefix(mount)?.components().count();
+        let id = std::fs::metadata(&path)?.ino();
+        Ok(format!("socket cgroupv2 level {} {}", level, id))
+    }
+}
+
+/// An HTB class per rule with a bandwidth cap, everything else
+/// goes through the default class untouched
+fn shaping(iface: &str, rules: &MatchRules) -> Vec<Cmd> {
+    let mut commands = vec![
+        Cmd::new("tc", &["qdisc", "replace", "dev", iface, "root", "handle", "1:", "htb", "default", "1"]),
+        Cmd::new("tc", &["class", "replace", "dev", iface, "parent", "1:", "classid", DEFAULT_CLASS, "htb", "rate", "10gbit"]),
+        Cmd::new("tc", &["qdisc", "replace", "dev", iface, "parent", DEFAULT_CLASS, "fq_codel"]),
+    ];
+    for rule in rules.iter() {
+        if let Some(kbps) = rule.cgroup().network.as_ref().and_then(|n| n.bandwidth) {
+            let rate = format!("{}kbit", kbps);
+            let class = class_id(rule.name);
+            commands.push(Cmd::new("tc", &["class", "replace", "dev", iface, "parent", "1:", "classid", &class,
+                "htb", "rate", &rate, "ceil", &rate]));
+            commands.push(Cmd::new("tc", &["qdisc", "replace", "dev", iface, "parent", &class, "fq_codel"]));
+        }
+    }
+    commands
+}
+
+/// The HTB class for apps in a rule, tc class ids are hex
+fn class_id(rule: Rule) -> String {
+    format!("1:{:x}", 0x10 + rule.priority())
+}
+
+/// Runs batches of commands in order, stopping at the first one that
+/// fails, and sends back how it went
+fn spawn_worker() -> (Sender<Vec<Cmd>>, Receiver<Result<()>>) {
+    let (jobs_tx, jobs) = channel::<Vec<Cmd>>();
+    let (results, results_rx) = channel();
+    thread::spawn(move || {
+        for commands in jobs {
+            let result = commands.iter().try_for_each(|cmd| match run(cmd) {
+                Err(e) if cmd.may_fail => {
+                    debug!("{}", e);
+                    Ok(())
+                }
+                result => result,
+            });
+            if results.send(result).is_err() {
+                break;
+            }
+        }
+    });
+    (jobs_tx, results_rx)
+}
+
+fn run(cmd: &Cmd) -> Result<()> {
+    let name = format!("{} {}", cmd.program, cmd.args.join(" "));
+    let mut child = Command::new(cmd.program)
+        .args(&cmd.args)
+        .stdin(if cmd.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("{}: {}", name, e))?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), &cmd.stdin) {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{} timed out after {}s", name, COMMAND_TIMEOUT.as_secs());
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        let mut stderr = String::new();
+        if let Some(mut err) = child.stderr.take() {
+            let _ = err.read_to_string(&mut stderr);
+        }
+        bail!("{} failed ({}): {}", name, status, stderr.trim());
+    }
+    Ok(())
+}
+
+/// Where the cgroup2 hierarchy is mounted, /sys/fs/cgroup or
+/// /sys/fs/cgroup/unified on hybrid setups
+fn cgroup2_mount() -> Result<Option<PathBuf>> {
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo")?;
+    // ID parent major:minor root mountpoint options ... - fstype source options
+    Ok(mounts.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    }))
+}
+
+/// The interfaces the IPv4 and IPv6 default routes go over
+fn default_route_interfaces() -> Result<Vec<String>> {
+    let mut interfaces = Vec::new();
+    // IPv6 may well be disabled
+    let routes = [ipv4_default_route(), ipv6_default_route().or_else(|_| Ok(None))];
+    for route in routes {
+        if let Some(iface) = route? {
+            if !interfaces.contains(&iface) {
+                interfaces.push(iface);
+            }
+        }
+    }
+    Ok(interfaces)
+}
+
+fn ipv4_default_route() -> Result<Option<String>> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    let mut best: Option<(u32, &str)> = None;
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+            continue;
+        }
+        let metric = fields[6].parse::<u32>().unwrap_or(u32::MAX);
+        if best.map_or(true, |(m, _)| metric < m) {
+            best = Some((metric, fields[0]));
+        }
+    }
+
+    Ok(best.map(|(_, iface)| iface.to_string()))
+}
+
+fn ipv6_default_route() -> Result<Option<String>> {
+    let routes = std::fs::read_to_string("/proc/net/ipv6_route")?;
+    // dest dest_len src src_len next_hop metric refcnt use flags iface,
+    // all hex and without a header
+    let mut best: Option<(u32, &str)> = None;
+    for line in routes.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[1] != "00" || fields[0].chars().any(|c| c != '0') {
+            continue;
+        }
+        // The kernel adds unreachable defaults on lo
+        let flags = u32::from_str_radix(fields[8], 16).unwrap_or(0);
+        if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 || fields[9] == "lo" {
+            continue;
+        }
+        let metric = u32::from_str_radix(fields[5], 16).unwrap_or(u32::MAX);
+        if best.map_or(true, |(m, _)| metric < m) {
+            best = Some((metric, fields[9]));
+        }
+    }
+
+    Ok(best.map(|(_, iface)| iface.to_string()))
+}
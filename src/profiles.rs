@@ -0,0 +1,161 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Power profiles. A profile is a named overlay on the match rules,
+// e.g. "power-saver" moving apps out of recents sooner and capping
+// the background harder. Which profile is active depends on, in
+// order of priority:
+//
+// * one picked with hammockctl
+// * the low battery profile while the battery is below the
+//   low-battery event's threshold and discharging
+// * whatever power-profiles-daemon's ActiveProfile maps to
+//
+// The daemon's profile is watched from its own thread, everything
+// else happens on the event loop which rebuilds the rules when the
+// wanted profile changes.
+
+use crate::config::PowerProfilesConfig;
+use crate::hal::Hal;
+use std::time::{Duration, Instant};
+use strum_macros::Display;
+
+const BATTERY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Why a profile is active
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ProfileSource {
+    Manual,
+    LowBattery,
+    Daemon,
+    None,
+}
+
+pub struct PowerProfiles {
+    config: PowerProfilesConfig,
+    low_battery_threshold: Option<u32>,
+    /// Picked over D-Bus, overrides everything
+    manual: Option<String>,
+    /// power-profiles-daemon's ActiveProfile
+    daemon: Option<String>,
+    low_battery: bool,
+    next_battery_check: Instant,
+    /// The profile the rules were last built for
+    active: Option<String>,
+}
+
+impl PowerProfiles {
+    pub fn new(config: PowerProfilesConfig, low_battery_threshold: Option<u32>) -> Self {
+        Self {
+            config,
+            low_battery_threshold,
+            manual: None,
+            daemon: None,
+            low_battery: false,
+            next_battery_check: Instant::now(),
+            active: None,
+        }
+    }
+
+    /// Swap in a reloaded config. A manually picked profile that no
+    /// longer exists is dropped, the caller rebuilds the rules.
+    pub fn set_config(&mut self, config: PowerProfilesConfig, low_battery_threshold: Option<u32>) {
+        if self.manual.as_ref().map_or(false, |name| !config.profiles.contains_key(name)) {
+            warn!("Power profile {:?} went away, choosing one automatically", self.manual);
+            self.manual = None;
+        }
+        self.config = config;
+        self.low_battery_threshold = low_battery_threshold;
+    }
+
+    pub fn profiles(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.config.profiles.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Pick a profile by hand, None goes back to choosing automatically
+    pub fn set_manual(&mut self, profile: Option<String>) -> anyhow::Result<()> {
+        if let Some(name) = &profile {
+            if !self.config.profiles.contains_key(name) {
+                bail!("No power profile named '{}'", name);
+            }
+        }
+        self.manual = profile;
+        Ok(())
+    }
+
+    pub fn set_daemon_profile(&mut self, profile: &str) {
+        debug!("power-profiles-daemon profile is now {}", profile);
+        self.daemon = Some(profile.to_string());
+    }
+
+    /// The profile we should be using and why
+    pub fn wanted(&self) -> (Option<String>, ProfileSource) {
+        if let Some(name) = &self.manual {
+            return (Some(name.clone()), ProfileSource::Manual);
+        }
+        if self.low_battery {
+            if let Some(name) = &self.config.low_battery {
+                return (Some(name.clone()), ProfileSource::LowBattery);
+            }
+        }
+        match self.daemon.as_ref().and_then(|d| self.config.daemon.get(d)) {
+            Some(name) => (Some(name.clone()), ProfileSource::Daemon),
+            None => (None, ProfileSource::None),
+        }
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    pub fn set_active(&mut self, profile: Option<String>) {
+        self.active = profile;
+    }
+
+    /// Check the battery, returns true if it just became low
+    pub fn check_battery(&mut self, hal: &Hal) -> bool {
+        let threshold = match self.low_battery_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let now = Instant::now();
+        if now < self.next_battery_check {
+            return false;
+        }
+        self.next_battery_check = now + BATTERY_CHECK_INTERVAL;
+
+        let low = match hal.power_supply().battery() {
+            Ok(Some(battery)) => battery.discharging() && battery.capacity <= threshold,
+            Ok(None) => false,
+            Err(e) => {
+                debug!("Power profiles: couldn't read battery state: {}", e);
+                self.low_battery
+            }
+        };
+        let became_low = low && !self.low_battery;
+        if low != self.low_battery {
+            info!("Battery is {}", if low { "low" } else { "no longer low" });
+        }
+        self.low_battery = low;
+        became_low
+    }
+}
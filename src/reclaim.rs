@@ -0,0 +1,152 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Proactive reclaim. A snoozed app is frozen and won't be doing
+// anything until the user comes back to it, so there's no point in
+// it holding on to RAM that the foreground app could use. Once an
+// app has been snoozed for a while we ask the kernel to reclaim
+// from its cgroup via memory.reclaim, one chunk per interval so
+// that each write (which reclaims synchronously) doesn't hold up
+// the event loop for long. We stop when the kernel can't find
+// anything more to reclaim or we hit the per-app limit.
+
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::{ReclaimConfig, Rule};
+use crate::hal::Hal;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Reclaim progress for an app in its current snooze
+struct Progress {
+    reclaimed: u64,
+    done: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReclaimStats {
+    /// Bytes reclaimed over the lifetime of the daemon
+    pub reclaimed: u64,
+    /// How many times we reclaimed until the kernel ran out
+    pub runs: u32,
+}
+
+pub struct Reclaimer {
+    config: ReclaimConfig,
+    next_run: Instant,
+    /// Keyed by cgroup, dropped when the app leaves snooze
+    progress: HashMap<String, Progress>,
+    /// Keyed by app id
+    stats: HashMap<String, ReclaimStats>,
+}
+
+impl Reclaimer {
+    pub fn new(config: ReclaimConfig) -> Self {
+        Self {
+            config,
+            next_run: Instant::now(),
+            progress: HashMap::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &HashMap<String, ReclaimStats> {
+        &self.stats
+    }
+
+    /// Whether swapping is worth the power right now
+    fn battery_ok(&self, hal: &Hal) -> bool {
+        match hal.power_supply().battery() {
+            Ok(Some(battery)) => !battery.discharging() || battery.capacity >= self.config.min_battery,
+            Ok(None) => true,
+            Err(e) => {
+                debug!("Reclaim: couldn't read battery state: {}", e);
+                true
+            }
+        }
+    }
+
+    pub fn process(&mut self, handler: &CGHandler, hal: &Hal, apps: &[App]) {
+        let now = Instant::now();
+        if !self.config.enabled || now < self.next_run {
+            return;
+        }
+        self.next_run = now + Duration::from_secs_f32(self.config.interval);
+
+        let delay = Duration::from_secs(self.config.delay as u64);
+        let snoozed: Vec<(String, String)> = apps
+            .iter()
+            .filter_map(|app| {
+                let cgroup = app.cgroup_name();
+                let info = app.info.read();
+                match info.match_rule == Rule::Snooze && now.duration_since(info.rule_since) >= delay {
+                    true => Some((cgroup, info.app_id.to_string())),
+                    false => None,
+                }
+            })
+            .collect();
+
+        // Start again next time they're snoozed
+        self.progress.retain(|cgroup, _| snoozed.iter().any(|(cg, _)| cg == cgroup));
+        if snoozed.iter().all(|(cg, _)| self.progress.get(cg).map_or(false, |p| p.done)) {
+            return;
+        }
+
+        if !self.battery_ok(hal) {
+            trace!("Reclaim: battery below {}%, skipping", self.config.min_battery);
+            return;
+        }
+
+        let chunk = (self.config.chunk_mb as u64) << 20;
+        let max = (self.config.max_mb as u64) << 20;
+        for (cgroup, app_id) in snoozed {
+            let progress = self.progress.entry(cgroup.clone()).or_insert(Progress { reclaimed: 0, done: false });
+            if progress.done {
+                continue;
+            }
+
+            let bytes = match max {
+                0 => chunk,
+                max => chunk.min(max.saturating_sub(progress.reclaimed)),
+            };
+            let before = handler.memory_current(&cgroup).unwrap_or(0);
+            let more = match handler.reclaim(&cgroup, bytes) {
+                Ok(more) => more,
+                Err(e) => {
+                    warn!("Reclaim: {}", e);
+                    false
+                }
+            };
+            // memory.current can grow in between, don't count that
+            let reclaimed = before.saturating_sub(handler.memory_current(&cgroup).unwrap_or(before));
+
+            progress.reclaimed += reclaimed;
+            let stats = self.stats.entry(app_id).or_default();
+            stats.reclaimed += reclaimed;
+
+            if !more || (max > 0 && progress.reclaimed >= max) {
+                progress.done = true;
+                stats.runs += 1;
+                debug!("Reclaimed {}MB from {}", progress.reclaimed >> 20, cgroup);
+            }
+            // One chunk per run
+            break;
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Config reload triggers. SIGHUP and changes to the config files both
+// send a ReloadConfig event to the event loop, which does the actual
+// reload (so does the D-Bus Reload method). Only changes to the files
+// the config was merged from, the session user's overlay and new
+// overlays in config.d count, see layers::Watch. The set of files is
+// fixed at startup.
+
+use crate::config::layers::Watch;
+use crate::events::HammockEvent;
+use anyhow::Result;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{SigSet, Signal};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+/// Give editors a moment to finish writing before we read the file
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Block SIGHUP so it can be waited for, threads started afterwards
+/// inherit the mask. Has to be called before any thread is started
+/// (hammockd does it first thing in main), a thread that still has it
+/// unblocked would get the default action and kill us.
+pub fn block_sighup() -> Result<()> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGHUP);
+    mask.thread_block()?;
+    Ok(())
+}
+
+/// Start the threads that watch for SIGHUP and config file changes
+pub fn watch(files: Watch, tx: &Sender<HammockEvent>) -> Result<()> {
+    let sighup_tx = tx.clone();
+    thread::spawn(move || {
+        if let Err(e) = wait_sighup(sighup_tx) {
+            error!("Stopped waiting for SIGHUP: {}", e);
+        }
+    });
+
+    let inotify_tx = tx.clone();
+    thread::spawn(move || {
+        if let Err(e) = watch_files(&files, inotify_tx) {
+            warn!("Not watching the config for changes: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn wait_sighup(tx: Sender<HammockEvent>) -> Result<()> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGHUP);
+    loop {
+        if mask.wait()? == Signal::SIGHUP {
+            info!("Got SIGHUP, reloading config");
+            tx.send(HammockEvent::ReloadConfig(None))
+                .map_err(|_| anyhow!("The event loop went away"))?;
+        }
+    }
+}
+
+fn watch_files(files: &Watch, tx: Sender<HammockEvent>) -> Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let mut dirs = Vec::new();
+    for dir in &files.dirs {
+        let wd = inotify.add_watch(dir, AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO)?;
+        dirs.push((wd, dir));
+    }
+
+    loop {
+        let events = inotify.read_events()?;
+        let changed = events.iter().find_map(|event| {
+            let (_, dir) = dirs.iter().find(|(wd, _)| *wd == event.wd)?;
+            Some(dir.join(event.name.as_deref()?)).filter(|path| files.matches(path))
+        });
+        if let Some(path) = changed {
+            thread::sleep(SETTLE_TIME);
+            info!("{} changed, reloading config", path.display());
+            tx.send(HammockEvent::ReloadConfig(None))
+                .map_err(|_| anyhow!("The event loop went away"))?;
+        }
+    }
+}
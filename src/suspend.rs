@@ -0,0 +1,232 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// What we do between logind telling us it's about to suspend and
+// letting it go ahead. We hold a "delay" inhibitor so logind waits
+// for us, but only up to InhibitDelayMaxUSec, so every step here has
+// to fit in that budget.
+//
+// 1. Wait for the compositor to actually power off the outputs, we
+//    don't want to freeze the shell half way through blanking.
+// 2. Freeze userspace and wait for the freezer to settle.
+// 3. Release the delay inhibitor.
+//
+// On resume we don't want everything fighting for the CPU right when
+// the user is waiting for the lock screen. The shell and system stuff
+// is thawed first, then the last foreground app, then the rest in
+// order of priority a little while apart. Apps are put back in the
+// state their rule says rather than all being thawed.
+
+use crate::app_track::AppTrack;
+use crate::application::App;
+use crate::cgroups::CGHandler;
+use crate::config::SuspendConfig;
+use anyhow::Result;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Only used if we can't watch the freezer state
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Release the inhibitor a little before logind would give up on us
+const DELAY_MARGIN: Duration = Duration::from_millis(100);
+
+pub struct Suspend {
+    config: SuspendConfig,
+    /// App cgroups still to be thawed after resume, in order
+    thaw_queue: VecDeque<String>,
+    next_thaw: Instant,
+}
+
+impl Suspend {
+    pub fn new(config: SuspendConfig) -> Self {
+        Self {
+            config,
+            thaw_queue: VecDeque::new(),
+            next_thaw: Instant::now(),
+        }
+    }
+
+    /// Run the pre-suspend sequence. The delay inhibitor is always
+    /// released at the end, even if one of the steps failed.
+    pub fn prepare(&mut self, handler: &CGHandler, app_track: &AppTrack) -> Result<()> {
+        if !self.thaw_queue.is_empty() {
+            debug!("Suspending with {} apps still waiting to be thawed", self.thaw_queue.len());
+            self.thaw_queue.clear();
+        }
+
+        let start = Instant::now();
+        let deadline = start + app_track.inhibit_delay_max().saturating_sub(DELAY_MARGIN);
+
+        self.wait_outputs_off(app_track, deadline);
+        let res = self.freeze(handler, deadline);
+
+        let inhib_start = Instant::now();
+        app_track.handle_suspend(true)?;
+        debug!("Pre-suspend: released inhibitor in {}ms", inhib_start.elapsed().as_millis());
+        info!("Pre-suspend sequence took {}ms", start.elapsed().as_millis());
+
+        res
+    }
+
+    fn wait_outputs_off(&self, app_track: &AppTrack, deadline: Instant) {
+        if app_track.outputs_powered().is_none() {
+            debug!("Pre-suspend: output power state unknown, not waiting");
+            return;
+        }
+
+        // Forget about changes from before now
+        while app_track.wait_outputs_changed(Duration::ZERO) {}
+
+        let start = Instant::now();
+        let limit = deadline.min(start + Duration::from_secs_f32(self.config.output_off_timeout));
+        let outputs_off = || Ok(app_track.outputs_powered() != Some(true));
+        let wait = |timeout| {
+            app_track.wait_outputs_changed(timeout);
+            Ok(())
+        };
+        match wait_for(limit, outputs_off, wait) {
+            Ok(Some(t)) => info!("Pre-suspend: outputs off after {}ms", t.as_millis()),
+            _ => warn!("Pre-suspend: outputs still on after {}ms, carrying on", start.elapsed().as_millis()),
+        }
+    }
+
+    fn freeze(&self, handler: &CGHandler, deadline: Instant) -> Result<()> {
+        let start = Instant::now();
+        // Freeze all of userspace so pesky GSD doesn't touch the display when we're coming back from suspend
+        let report = handler.freeze_except()?;
+        info!("Pre-suspend: {}", report);
+
+        let limit = deadline.min(start + Duration::from_secs_f32(self.config.freeze_timeout));
+        let inotify = match watch_files(&handler.frozen_events()) {
+            Ok(inotify) => Some(inotify),
+            Err(e) => {
+                warn!("Pre-suspend: can't watch the freezer state, polling it: {}", e);
+                None
+            }
+        };
+        let wait = |timeout: Duration| match &inotify {
+            Some(inotify) => wait_inotify(inotify, timeout),
+            None => {
+                thread::sleep(timeout.min(POLL_INTERVAL));
+                Ok(())
+            }
+        };
+        match wait_for(limit, || handler.all_frozen(), wait)? {
+            Some(t) => info!("Pre-suspend: userspace frozen after {}ms", t.as_millis()),
+            None => warn!("Pre-suspend: userspace still not frozen after {}ms", start.elapsed().as_millis()),
+        }
+
+        Ok(())
+    }
+
+    /// Thaw the system, keeping apps frozen so they can be
+    /// brought back one at a time with next_thaw().
+    pub fn resume(&mut self, handler: &CGHandler, apps: &[App]) -> Result<()> {
+        let start = Instant::now();
+        // Thawing the root would thaw every app with it
+        for app in apps {
+            if let Err(e) = app.freeze() {
+                warn!("Resume: couldn't keep {} frozen: {}", app.cgroup_name(), e);
+            }
+        }
+        let cgroups: Vec<String> = apps.iter().map(|app| app.cgroup_name()).collect();
+        handler.thaw_except(&cgroups)?;
+        info!("Resume: system thawed in {}ms", start.elapsed().as_millis());
+
+        // Most important first, the last app the user had
+        // focused ends up at the front
+        let mut order: Vec<(u32, Reverse<Option<Instant>>, String)> = apps
+            .iter()
+            .map(|app| {
+                let info = app.info.read();
+                (info.match_rule.priority(), Reverse(info.last_focused), app.cgroup_name())
+            })
+            .collect();
+        order.sort();
+
+        self.thaw_queue = order.into_iter().map(|(_, _, cgroup)| cgroup).collect();
+        self.next_thaw = Instant::now();
+
+        Ok(())
+    }
+
+    /// Is the cgroup still frozen waiting for its turn to be thawed?
+    pub fn is_thawing(&self, cgroup: &str) -> bool {
+        self.thaw_queue.iter().any(|c| c == cgroup)
+    }
+
+    /// The next app cgroup due to have its freezer state restored
+    pub fn next_thaw(&mut self) -> Option<String> {
+        let now = Instant::now();
+        if self.thaw_queue.is_empty() || now < self.next_thaw {
+            return None;
+        }
+
+        // Step from the previous deadline so that staggers shorter
+        // than the event loop interval still add up correctly
+        let stagger = Duration::from_secs_f32(self.config.thaw_stagger);
+        self.next_thaw = self.next_thaw.max(now.checked_sub(stagger).unwrap_or(now)) + stagger;
+        self.thaw_queue.pop_front()
+    }
+}
+
+/// Check cond until it returns true or we hit the time limit, wait
+/// blocks until it might have changed (or the timeout passes). Returns
+/// how long it took or None if we timed out.
+fn wait_for<F, W>(limit: Instant, mut cond: F, mut wait: W) -> Result<Option<Duration>>
+where
+    F: FnMut() -> Result<bool>,
+    W: FnMut(Duration) -> Result<()>,
+{
+    let start = Instant::now();
+    loop {
+        if cond()? {
+            return Ok(Some(start.elapsed()));
+        }
+        let now = Instant::now();
+        if now >= limit {
+            return Ok(None);
+        }
+        wait(limit - now)?;
+    }
+}
+
+fn watch_files(paths: &[std::path::PathBuf]) -> Result<Inotify> {
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    for path in paths {
+        inotify.add_watch(path.as_path(), AddWatchFlags::IN_MODIFY)?;
+    }
+    Ok(inotify)
+}
+
+/// Block until one of the watched files changes or the timeout passes
+fn wait_inotify(inotify: &Inotify, timeout: Duration) -> Result<()> {
+    let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+    // Round up so we don't spin for the last fraction of a millisecond
+    let ms = ((timeout.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32;
+    poll(&mut fds, ms)?;
+    // We only care that something changed, not what
+    let _ = inotify.read_events();
+    Ok(())
+}
@@ -0,0 +1,182 @@
+/*
+* Hammock system daemon
+* Copyright (C) 2022 Caleb Connolly <caleb@connolly.tech>
+*
+* This program is free software; you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation; either version 2 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License along
+* with this program; if not, write to the Free Software Foundation, Inc.,
+* 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+*/
+
+// Thermal policy. Once the phone gets hot the kernel starts capping
+// CPU frequencies, and background apps running on the big cores make
+// that worse for the foreground app. We watch the hottest of the
+// configured thermal zones and step through the configured levels as
+// it heats up, each level narrowing the cpuset and CPU weight of the
+// less important rules a bit more. Levels are only left once the
+// temperature drops hysteresis degrees below them so we don't flap
+// around a threshold.
+//
+// Levels can be given as a temperature or as one of the zones' trip
+// points, so a config doesn't need to know the SoC's limits.
+
+use crate::cgroups::CGHandler;
+use crate::config::{CpuSet, ThermalConfig};
+use crate::hal::ThermalZone;
+use crate::match_rules::MatchRule;
+use glob::Pattern;
+use std::time::{Duration, Instant};
+
+struct Level {
+    temp: f32,
+    cpuset: Option<CpuSet>,
+    weight_percent: Option<u32>,
+}
+
+/// The thermal level changed, 0 means we're below every level
+pub struct ThermalEvent {
+    pub level: usize,
+    pub previous: usize,
+    pub temp: f32,
+}
+
+pub struct ThermalPolicy {
+    config: ThermalConfig,
+    zones: Vec<ThermalZone>,
+    /// Sorted by temperature
+    levels: Vec<Level>,
+    level: usize,
+    next_check: Instant,
+}
+
+impl ThermalPolicy {
+    pub fn new(config: ThermalConfig) -> Self {
+        let zones = match config.enabled {
+            true => Self::find_zones(&config),
+            false => Vec::new(),
+        };
+        let mut levels: Vec<Level> = config
+            .levels
+            .iter()
+            .filter_map(|level| {
+                let temp = match (level.temp, &level.trip) {
+                    (Some(temp), _) => temp,
+                    (None, Some(kind)) => {
+                        let trip = zones
+                            .iter()
+                            .flat_map(|zone| zone.trips())
+                            .filter(|trip| trip.kind == *kind)
+                            .map(|trip| trip.temp)
+                            .reduce(f32::min);
+                        match trip {
+                            Some(trip) => trip + level.offset,
+                            None => {
+                                warn!("Thermal: no {} trip point in the watched zones, ignoring level", kind);
+                                return None;
+                            }
+                        }
+                    }
+                    (None, None) => return None,
+                };
+                Some(Level {
+                    temp,
+                    cpuset: level.cpuset.clone(),
+                    weight_percent: level.weight_percent,
+                })
+            })
+            .collect();
+        levels.sort_by(|a, b| a.temp.total_cmp(&b.temp));
+
+        if !zones.is_empty() {
+            let temps: Vec<String> = levels.iter().map(|l| format!("{}°C", l.temp)).collect();
+            info!("Thermal: watching {} zones, levels at {}", zones.len(), temps.join(", "));
+        }
+
+        Self {
+            config,
+            zones,
+            levels,
+            level: 0,
+            next_check: Instant::now(),
+        }
+    }
+
+    fn find_zones(config: &ThermalConfig) -> Vec<ThermalZone> {
+        let patterns: Vec<Pattern> = config.zones.iter().filter_map(|p| Pattern::new(p).ok()).collect();
+        match ThermalZone::all() {
+            Ok(zones) => {
+                let zones: Vec<ThermalZone> = zones
+                    .into_iter()
+                    .filter(|zone| patterns.iter().any(|p| p.matches(&zone.kind)))
+                    .collect();
+                if zones.is_empty() {
+                    warn!("Thermal: no thermal zones match {:?}", config.zones);
+                }
+                zones
+            }
+            Err(e) => {
+                warn!("Thermal: couldn't list thermal zones: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Read the temperature and move between levels
+    pub fn process(&mut self) -> Option<ThermalEvent> {
+        let now = Instant::now();
+        if self.zones.is_empty() || self.levels.is_empty() || now < self.next_check {
+            return None;
+        }
+        self.next_check = now + Duration::from_secs_f32(self.config.interval);
+
+        // Disabled zones fail to read
+        let temp = self.zones.iter().filter_map(|zone| zone.temp().ok()).reduce(f32::max)?;
+        let previous = self.level;
+        while self.level < self.levels.len() && temp >= self.levels[self.level].temp {
+            self.level += 1;
+        }
+        while self.level > 0 && temp < self.levels[self.level - 1].temp - self.config.hysteresis {
+            self.level -= 1;
+        }
+
+        match self.level != previous {
+            true => Some(ThermalEvent {
+                level: self.level,
+                previous,
+                temp,
+            }),
+            false => None,
+        }
+    }
+
+    /// Narrow an app's cpuset and CPU weight for the current level,
+    /// on top of what its rule already applied
+    pub fn apply(&self, handler: &CGHandler, cgroup: &str, rule: &MatchRule) {
+        if self.level == 0 || !self.config.rules.contains(&rule.name) {
+            return;
+        }
+
+        // Levels build on the ones below them
+        let active = &self.levels[..self.level];
+        if let Some(cpuset) = active.iter().rev().find_map(|l| l.cpuset.as_ref()) {
+            if let (Some(rule_cpus), Some(level_cpus)) =
+                (handler.resolve_cpus(&rule.cgroup().cpuset), handler.resolve_cpus(cpuset))
+            {
+                let cpus: Vec<u32> = rule_cpus.into_iter().filter(|cpu| level_cpus.contains(cpu)).collect();
+                handler.set_cpus(cgroup, if cpus.is_empty() { &level_cpus } else { &cpus });
+            }
+        }
+        if let Some(percent) = active.iter().rev().find_map(|l| l.weight_percent) {
+            handler.set_cpu_weight(cgroup, rule.cgroup().cpu_weight() * percent as u64 / 100);
+        }
+    }
+}